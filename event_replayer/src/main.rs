@@ -0,0 +1,360 @@
+use candid::Decode;
+use clap::{Parser, Subcommand, ValueEnum};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use water_neuron::Unit;
+use water_neuron::numeric::{ICP, WTN, nICP};
+use water_neuron::state::audit::apply_state_transition;
+use water_neuron::state::event::{Event, EventType, GetEventsResult};
+use water_neuron::state::{State, TransferStatus, WithdrawalId};
+use water_neuron::storage::read_event_log;
+
+/// Rebuilds the WaterNeuron state offline from a copy of its event log.
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Replays an event log and prints the resulting state invariants.
+    Inspect {
+        #[arg(long, value_enum, default_value_t = InputFormat::Candid)]
+        format: InputFormat,
+        events: PathBuf,
+    },
+    /// Replays two event logs and reports how the resulting states differ.
+    Diff {
+        #[arg(long, value_enum, default_value_t = InputFormat::Candid)]
+        format: InputFormat,
+        left: PathBuf,
+        right: PathBuf,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum InputFormat {
+    /// Candid encoded `get_events` replies, either a single binary reply or one hex
+    /// encoded reply per line as printed by `dfx canister call --output raw`.
+    Candid,
+    /// A raw copy of the stable memory of the canister.
+    StableMemory,
+}
+
+fn decode_get_events_reply(bytes: &[u8]) -> Result<Vec<Event>, String> {
+    Decode!(bytes, GetEventsResult)
+        .map(|result| result.events)
+        .map_err(|e| format!("failed to decode a get_events reply: {e}"))
+}
+
+fn read_events(path: &Path, format: InputFormat) -> Result<Vec<Event>, String> {
+    let bytes =
+        std::fs::read(path).map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+    match format {
+        InputFormat::Candid if bytes.starts_with(b"DIDL") => decode_get_events_reply(&bytes),
+        InputFormat::Candid => {
+            let text = String::from_utf8(bytes)
+                .map_err(|_| format!("{} is neither binary Candid nor hex", path.display()))?;
+            let mut events = vec![];
+            for (line_number, line) in text.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+                let reply = hex::decode(line)
+                    .map_err(|e| format!("invalid hex on line {}: {e}", line_number + 1))?;
+                events.extend(decode_get_events_reply(&reply)?);
+            }
+            Ok(events)
+        }
+        InputFormat::StableMemory => read_event_log(Rc::new(RefCell::new(bytes))),
+    }
+}
+
+/// Replays the events the same way as the canister, an event failing to apply is reported
+/// as an error along with its index instead of aborting.
+fn replay(path: &Path, format: InputFormat) -> Result<State, String> {
+    let events = read_events(path, format)?;
+    eprintln!("replaying {} events from {}", events.len(), path.display());
+    let mut events = events.into_iter();
+    let mut state = match events.next() {
+        Some(Event {
+            payload: EventType::Init(init_arg),
+            ..
+        }) => State::from_init_args(init_arg),
+        Some(_) => {
+            return Err(format!(
+                "{} does not start with the Init event, the log should be dumped from index 0",
+                path.display()
+            ));
+        }
+        None => return Err(format!("{} contains no events", path.display())),
+    };
+    for (index, event) in (1..).zip(events) {
+        catch_unwind(AssertUnwindSafe(|| {
+            apply_state_transition(&mut state, &event.payload, event.timestamp)
+        }))
+        .map_err(|_| {
+            format!(
+                "failed to apply event {index} of {}, the log might be missing events",
+                path.display()
+            )
+        })?;
+    }
+    Ok(state)
+}
+
+fn display_amount(unit: &Unit, e8s: u64) -> String {
+    match unit {
+        Unit::ICP => ICP::from_e8s(e8s).to_string(),
+        Unit::NICP => nICP::from_e8s(e8s).to_string(),
+        Unit::WTN => WTN::from_e8s(e8s).to_string(),
+    }
+}
+
+fn total_icp_due<'a>(state: &State, withdrawal_ids: impl IntoIterator<Item = &'a u64>) -> ICP {
+    ICP::from_e8s(
+        withdrawal_ids
+            .into_iter()
+            .filter_map(|id| state.withdrawal_id_to_request.get(id))
+            .map(|request| request.icp_due.0)
+            .sum(),
+    )
+}
+
+fn print_invariants(state: &State) {
+    println!("# Supply");
+    println!("total_circulating_nicp: {}", state.total_circulating_nicp);
+    println!("tracked_6m_stake: {}", state.tracked_6m_stake);
+    println!("liquidity_buffer: {}", state.liquidity_buffer);
+    println!(
+        "nicp_backing_icp: {}",
+        ICP::from_e8s(state.tracked_6m_stake.0 + state.liquidity_buffer.0)
+    );
+    println!(
+        "exchange_rate_e8s: {}",
+        state.get_icp_to_ncip_exchange_rate_e8s()
+    );
+    println!("total_icp_deposited: {}", state.total_icp_deposited);
+    println!(
+        "protocol_mode: {}{}",
+        state.effective_protocol_mode(),
+        state
+            .effective_protocol_mode_reason()
+            .map(|reason| format!(" ({reason})"))
+            .unwrap_or_default()
+    );
+
+    println!("\n# Transfers");
+    let transfers = [
+        (
+            "pending",
+            state.pending_transfers.values().collect::<Vec<_>>(),
+        ),
+        (
+            "dead-lettered",
+            state
+                .dead_letter_transfers
+                .values()
+                .map(|dead_letter| &dead_letter.transfer)
+                .collect(),
+        ),
+        (
+            "rejected",
+            state
+                .rejected_transfers
+                .values()
+                .map(|rejected| &rejected.transfer)
+                .collect(),
+        ),
+    ];
+    for unit in [Unit::ICP, Unit::NICP, Unit::WTN] {
+        let totals: Vec<String> = transfers
+            .iter()
+            .map(|(status, transfers)| {
+                let (count, total_e8s) = transfers
+                    .iter()
+                    .filter(|transfer| transfer.unit == unit)
+                    .fold((0, 0), |(count, total), transfer| {
+                        (count + 1, total + transfer.amount)
+                    });
+                format!("{count} {status} ({})", display_amount(&unit, total_e8s))
+            })
+            .collect();
+        println!("{unit}: {}", totals.join(", "));
+    }
+    for ((unit, subaccount), amount) in &state.unswept_rejected_funds {
+        println!(
+            "unswept {unit} in {}: {}",
+            subaccount
+                .map(hex::encode)
+                .unwrap_or_else(|| "default".to_string()),
+            display_amount(unit, *amount)
+        );
+    }
+
+    println!("\n# Withdrawals");
+    println!(
+        "waiting_to_split: {} ({})",
+        state.withdrawal_to_split.len(),
+        total_icp_due(state, &state.withdrawal_to_split)
+    );
+    println!(
+        "waiting_to_start_dissolving: {} ({})",
+        state.withdrawal_to_start_dissolving.len(),
+        total_icp_due(state, &state.withdrawal_to_start_dissolving)
+    );
+    println!(
+        "waiting_dissolvement: {} ({})",
+        state.withdrawal_to_disburse.len(),
+        total_icp_due(state, &state.withdrawal_to_disburse)
+    );
+    println!(
+        "waiting_payout: {}",
+        state.withdrawal_payout_transfers.len()
+    );
+    println!("payout_failed: {}", state.withdrawal_payout_rejected.len());
+    println!("finalized: {}", state.withdrawal_finalized.len());
+    println!("cancelled: {}", state.withdrawal_cancelled.len());
+    println!("total_requests: {}", state.withdrawal_id_to_request.len());
+
+    println!("\n# Airdrop");
+    println!("participants: {}", state.airdrop.len());
+    println!(
+        "wtn_allocated: {}",
+        WTN::from_e8s(state.airdrop.values().map(|amount| amount.0).sum())
+    );
+}
+
+/// Returns the invariants the replayed state does not hold.
+fn invariant_violations(state: &State) -> Vec<String> {
+    let mut violations = vec![];
+
+    let backing = state.total_icp_backing();
+    if state.total_circulating_nicp.0 > backing.0 {
+        violations.push(format!(
+            "the nICP supply {} exceeds the ICP backing {backing}",
+            state.total_circulating_nicp
+        ));
+    }
+
+    let unsettled_mints: u64 = state
+        .unsettled_transfers()
+        .filter(|transfer| transfer.unit == Unit::NICP)
+        .map(|transfer| transfer.amount)
+        .sum();
+    if unsettled_mints > state.total_circulating_nicp.0 {
+        violations.push(format!(
+            "the unsettled nICP mints {} exceed the nICP supply {}",
+            nICP::from_e8s(unsettled_mints),
+            state.total_circulating_nicp
+        ));
+    }
+
+    let neuron_6m_account = state.get_6m_neuron_account();
+    let unsettled_to_6m: u64 = state
+        .unsettled_transfers()
+        .filter(|transfer| transfer.unit == Unit::ICP && transfer.receiver == neuron_6m_account)
+        .map(|transfer| transfer.amount)
+        .sum();
+    let unsplit_icp_due = total_icp_due(state, &state.withdrawal_to_split);
+    if unsettled_to_6m > state.tracked_6m_stake.0 + unsplit_icp_due.0 {
+        violations.push(format!(
+            "the unsettled ICP to the 6 months neuron {} exceed the tracked stake {} plus the unsplit withdrawals {unsplit_icp_due}",
+            ICP::from_e8s(unsettled_to_6m),
+            state.tracked_6m_stake
+        ));
+    }
+
+    for (transfer_id, withdrawal_id) in &state.withdrawal_payout_transfers {
+        let Some(icp_due) = state
+            .withdrawal_id_to_request
+            .get(withdrawal_id)
+            .map(|request| request.icp_due)
+        else {
+            violations.push(format!(
+                "the payout {transfer_id} is for the unknown withdrawal {withdrawal_id}"
+            ));
+            continue;
+        };
+        match state.get_transfer_status(*transfer_id) {
+            TransferStatus::Pending(transfer) if transfer.amount <= icp_due.0 => {}
+            TransferStatus::DeadLettered(dead_letter)
+                if dead_letter.transfer.amount <= icp_due.0 => {}
+            status => violations.push(format!(
+                "the payout {transfer_id} of withdrawal {withdrawal_id} due {icp_due} is {status:?}"
+            )),
+        }
+    }
+
+    // Every withdrawal is in a single stage of the pipeline.
+    let mut stages: BTreeMap<WithdrawalId, usize> = BTreeMap::new();
+    for withdrawal_id in state
+        .withdrawal_to_split
+        .iter()
+        .chain(&state.withdrawal_to_start_dissolving)
+        .chain(&state.withdrawal_to_disburse)
+        .chain(state.withdrawal_id_to_payout_transfer.keys())
+        .chain(state.withdrawal_payout_rejected.keys())
+        .chain(state.withdrawal_finalized.keys())
+        .chain(&state.withdrawal_cancelled)
+    {
+        *stages.entry(*withdrawal_id).or_default() += 1;
+    }
+    for withdrawal_id in state.withdrawal_id_to_request.keys() {
+        match stages.get(withdrawal_id).copied().unwrap_or_default() {
+            1 => {}
+            count => violations.push(format!(
+                "withdrawal {withdrawal_id} is in {count} stages of the pipeline"
+            )),
+        }
+    }
+
+    violations
+}
+
+fn inspect(state: &State) -> Result<(), String> {
+    print_invariants(state);
+    println!("\n# Checks");
+    let violations = invariant_violations(state);
+    if violations.is_empty() {
+        println!("all invariants hold");
+        return Ok(());
+    }
+    for violation in &violations {
+        println!("FAILED: {violation}");
+    }
+    Err(format!("{} invariants do not hold", violations.len()))
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Command::Inspect { format, events } => {
+            replay(&events, format).and_then(|state| inspect(&state))
+        }
+        Command::Diff {
+            format,
+            left,
+            right,
+        } => replay(&left, format).and_then(|left_state| {
+            let right_state = replay(&right, format)?;
+            match left_state.is_equivalent_to(&right_state) {
+                Ok(()) => {
+                    println!("the states are equivalent");
+                    Ok(())
+                }
+                Err(diff) => Err(format!("the states differ:\n{diff}")),
+            }
+        }),
+    };
+    if let Err(error) = result {
+        eprintln!("{error}");
+        std::process::exit(1);
+    }
+}
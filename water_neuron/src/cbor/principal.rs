@@ -28,6 +28,14 @@ pub mod option {
         Ok(Option::<CborPrincipal>::decode(d, ctx)?.map(|n| n.0))
     }
 
+    pub fn nil() -> Option<Option<Principal>> {
+        Some(None)
+    }
+
+    pub fn is_nil(v: &Option<Principal>) -> bool {
+        v.is_none()
+    }
+
     pub fn encode<Ctx, W: Write>(
         v: &Option<Principal>,
         e: &mut Encoder<W>,
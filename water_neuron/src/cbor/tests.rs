@@ -34,6 +34,12 @@ struct OptPrincipalContainer {
     pub value: Option<Principal>,
 }
 
+#[derive(Debug, PartialEq, Eq, Encode, Decode)]
+struct OptAccountContainer {
+    #[cbor(n(0), with = "crate::cbor::account::option")]
+    pub value: Option<Account>,
+}
+
 fn arb_principal() -> impl Strategy<Value = Principal> {
     pvec(any::<u8>(), 0..=29).prop_map(|bytes| Principal::from_slice(&bytes))
 }
@@ -76,4 +82,12 @@ proptest! {
             value: account
         })?;
     }
+
+    #[test]
+    fn opt_account_encoding_roundtrip(
+        account in proptest::option::of(arb_account())) {
+        check_roundtrip(&OptAccountContainer {
+            value: account
+        })?;
+    }
 }
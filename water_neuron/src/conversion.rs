@@ -1,35 +1,253 @@
-use crate::guards::GuardPrincipal;
+use crate::guards::{GuardPrincipal, LiquidityBufferReservation, consume_rate_limit};
 use crate::logs::{DEBUG, INFO};
-use crate::management::{merge_neuron_into_six_months, stop_dissolvement};
+use crate::management::{
+    get_icp_block, merge_neuron_into_six_months, split_neuron_by_id, stop_dissolvement, transfer,
+};
 use crate::nns_types::{NeuronId, time_left_seconds};
 use crate::numeric::{ICP, nICP};
 use crate::state::audit::process_event;
 use crate::state::event::EventType;
-use crate::state::{mutate_state, read_state};
-use crate::tasks::{TaskType, schedule_now};
+use crate::state::{WithdrawalId, mutate_state, read_state};
+use crate::tasks::{TaskType, schedule_after, schedule_now};
 use crate::{
-    CancelWithdrawalError, ConversionArg, ConversionError, DepositSuccess, ICP_LEDGER_ID,
-    ONE_DAY_SECONDS, WithdrawalSuccess, get_full_neuron, timestamp_nanos,
+    CancelWithdrawalError, ConversionArg, ConversionError, DEFAULT_LEDGER_FEE,
+    DEPOSIT_DEDUP_WINDOW_NANOS, DepositQuote, DepositSuccess, E8S, ICP_LEDGER_ID,
+    InstantWithdrawalSuccess, NotifyIcpDepositArg, ONE_DAY_SECONDS, ONE_MINUTE, ProtocolMode,
+    WithdrawalQuote, WithdrawalSuccess, fetch_neuron_stake, get_full_neuron, timestamp_nanos,
 };
-use candid::Nat;
+use candid::{Nat, Principal};
 use ic_canister_log::log;
 use ic_nns_governance_api::{
     manage_neuron_response::Command as CommandResponse, manage_neuron_response::MergeResponse,
 };
+use icp_ledger::{AccountIdentifier, CandidBlock, CandidOperation};
 use icrc_ledger_client_cdk::{CdkRuntime, ICRC1Client};
 use icrc_ledger_types::icrc1::account::Account;
 use icrc_ledger_types::icrc2::transfer_from::TransferFromArgs;
 
+// Default conversion limits, they can be changed through governance afterwards.
 pub const MINIMUM_DEPOSIT_AMOUNT: ICP = ICP::ONE;
 pub const MINIMUM_WITHDRAWAL_AMOUNT: ICP = ICP::from_unscaled(10);
 
 pub async fn cancel_withdrawal(
     neuron_id: NeuronId,
 ) -> Result<MergeResponse, CancelWithdrawalError> {
+    check_cancellation_allowed()?;
     let caller = ic_cdk::api::msg_caller();
     let _guard_principal = GuardPrincipal::new(caller)
         .map_err(|guard_error| CancelWithdrawalError::GuardError { guard_error })?;
+    consume_rate_limit(caller)
+        .map_err(|guard_error| CancelWithdrawalError::GuardError { guard_error })?;
+
+    let withdrawal = check_withdrawal_cancellable(caller, neuron_id).await?;
+    let icp_due = withdrawal.icp_due;
+
+    // The other withdrawals of a batch neuron keep dissolving, the cancelled one is split out.
+    if withdrawal.batched {
+        let minimum_amount_e8s = E8S + DEFAULT_LEDGER_FEE;
+        if icp_due.0 < minimum_amount_e8s {
+            return Err(CancelWithdrawalError::AmountTooLow { minimum_amount_e8s });
+        }
+        log!(
+            DEBUG,
+            "[cancel_withdrawal] Cancelling withdrawal {} out of batch neuron with id {}, ICP due: {icp_due}",
+            withdrawal.withdrawal_id,
+            neuron_id.id
+        );
+        return split_and_merge_neuron(neuron_id, icp_due, Some(withdrawal.withdrawal_id)).await;
+    }
+
+    log!(
+        DEBUG,
+        "[cancel_withdrawal] Cancelling neuron with id {}, ICP due: {icp_due}",
+        neuron_id.id
+    );
+
+    let response = stop_and_merge_neuron(neuron_id).await?;
+    mutate_state(|s| {
+        let minted = s.quote_neuron_merge(neuron_id);
+        process_event(
+            s,
+            EventType::MergeNeuron {
+                neuron_id,
+                nicp_minted: minted.map(|(nicp_minted, _)| nicp_minted),
+                nicp_fee: minted.map(|(_, nicp_fee)| nicp_fee),
+            },
+        );
+    });
+    schedule_now(TaskType::ProcessPendingTransfers);
+    schedule_now(TaskType::RefreshShortTerm);
+    Ok(response)
+}
+
+/// Cancels `icp_amount_e8s` out of the withdrawal associated to `neuron_id`.
+/// The amount is split out of the dissolving neuron and merged back into the
+/// 6 months neuron, the rest of the withdrawal keeps dissolving.
+pub async fn cancel_withdrawal_partial(
+    neuron_id: NeuronId,
+    icp_amount_e8s: u64,
+) -> Result<MergeResponse, CancelWithdrawalError> {
+    check_cancellation_allowed()?;
+    let caller = ic_cdk::api::msg_caller();
+    let _guard_principal = GuardPrincipal::new(caller)
+        .map_err(|guard_error| CancelWithdrawalError::GuardError { guard_error })?;
+    consume_rate_limit(caller)
+        .map_err(|guard_error| CancelWithdrawalError::GuardError { guard_error })?;
+
+    let withdrawal = check_withdrawal_cancellable(caller, neuron_id).await?;
+    let icp_due = withdrawal.icp_due;
+
+    // Both the split neuron and the remaining neuron need a stake of at least 1 ICP,
+    // the split itself costs a ledger fee.
+    let minimum_amount_e8s = E8S + DEFAULT_LEDGER_FEE;
+    let maximum_amount_e8s = icp_due.0.saturating_sub(E8S + DEFAULT_LEDGER_FEE);
+    if icp_amount_e8s < minimum_amount_e8s {
+        return Err(CancelWithdrawalError::AmountTooLow { minimum_amount_e8s });
+    }
+    if icp_amount_e8s > maximum_amount_e8s {
+        return Err(CancelWithdrawalError::AmountTooHigh { maximum_amount_e8s });
+    }
+    let icp_amount = ICP::from_e8s(icp_amount_e8s);
+
+    log!(
+        DEBUG,
+        "[cancel_withdrawal_partial] Cancelling {icp_amount} ICP of neuron with id {}, ICP due: {icp_due}",
+        neuron_id.id
+    );
+
+    split_and_merge_neuron(
+        neuron_id,
+        icp_amount,
+        withdrawal.batched.then_some(withdrawal.withdrawal_id),
+    )
+    .await
+}
+
+/// Splits `icp_amount` out of the withdrawal neuron `neuron_id` and merges it into the
+/// 6 months neuron. `withdrawal_id` is the withdrawal cancelled when the neuron is a batch.
+async fn split_and_merge_neuron(
+    neuron_id: NeuronId,
+    icp_amount: ICP,
+    withdrawal_id: Option<WithdrawalId>,
+) -> Result<MergeResponse, CancelWithdrawalError> {
+    let split_neuron_id = match split_neuron_by_id(neuron_id, icp_amount.0)
+        .await
+        .map_err(|message| CancelWithdrawalError::SplitNeuronError { message })?
+        .command
+        .expect("Command should always be set.")
+    {
+        CommandResponse::Split(response) => match response.created_neuron_id {
+            Some(created_neuron_id) => NeuronId {
+                id: created_neuron_id.id,
+            },
+            None => {
+                return Err(CancelWithdrawalError::SplitNeuronError {
+                    message: "Split neuron response without neuron id".to_string(),
+                });
+            }
+        },
+        CommandResponse::Error(e) => return Err(CancelWithdrawalError::GovernanceError(e)),
+        other => {
+            return Err(CancelWithdrawalError::BadCommand {
+                message: format!("Expected split command got {other:?}"),
+            });
+        }
+    };
+
+    // The split neuron is tracked before merging it, a failed merge is retried by a task.
+    mutate_state(|s| {
+        process_event(
+            s,
+            EventType::PartialSplitNeuron {
+                neuron_id,
+                split_neuron_id,
+                icp_amount,
+                withdrawal_id,
+            },
+        );
+    });
+
+    match merge_split_neuron(split_neuron_id).await {
+        Ok(response) => Ok(response),
+        Err(e) => {
+            log!(
+                INFO,
+                "[split_and_merge_neuron] Failed to merge neuron {} split from neuron {}, retrying later: {e:?}",
+                split_neuron_id.id,
+                neuron_id.id
+            );
+            schedule_after(ONE_MINUTE, TaskType::ProcessPendingMerges);
+            Err(e)
+        }
+    }
+}
 
+/// Merges the neurons split out of withdrawal neurons that are still pending,
+/// returns the number of neurons that failed to merge.
+pub async fn process_pending_partial_merges() -> usize {
+    let split_neuron_ids: Vec<NeuronId> =
+        read_state(|s| s.pending_partial_merges.keys().copied().collect());
+    let mut error_count = 0;
+    for split_neuron_id in split_neuron_ids {
+        // A merge whose reply got lost leaves the split neuron empty.
+        if fetch_neuron_stake(split_neuron_id.id).await == Ok(ICP::ZERO) {
+            record_partial_merge(split_neuron_id);
+            continue;
+        }
+        if let Err(e) = merge_split_neuron(split_neuron_id).await {
+            log!(
+                INFO,
+                "[process_pending_partial_merges] Failed to merge split neuron {}: {e:?}",
+                split_neuron_id.id
+            );
+            error_count += 1;
+        }
+    }
+    error_count
+}
+
+/// Merges a neuron recorded by [EventType::PartialSplitNeuron] into the 6 months neuron.
+async fn merge_split_neuron(
+    split_neuron_id: NeuronId,
+) -> Result<MergeResponse, CancelWithdrawalError> {
+    let response = stop_and_merge_neuron(split_neuron_id).await?;
+    record_partial_merge(split_neuron_id);
+    Ok(response)
+}
+
+fn record_partial_merge(split_neuron_id: NeuronId) {
+    mutate_state(|s| {
+        if let Some(pending_merge) = s.pending_partial_merges.get(&split_neuron_id).cloned() {
+            let minted = s.quote_partial_neuron_merge(split_neuron_id);
+            process_event(
+                s,
+                EventType::PartialMergeNeuron {
+                    neuron_id: pending_merge.neuron_id,
+                    split_neuron_id,
+                    icp_amount: pending_merge.icp_amount,
+                    nicp_minted: minted.map(|(nicp_minted, _)| nicp_minted),
+                    nicp_fee: minted.map(|(_, nicp_fee)| nicp_fee),
+                },
+            );
+        }
+    });
+    schedule_now(TaskType::ProcessPendingTransfers);
+    schedule_now(TaskType::RefreshShortTerm);
+}
+
+/// The withdrawal of the caller dissolving in the neuron to cancel.
+struct CancellableWithdrawal {
+    withdrawal_id: WithdrawalId,
+    icp_due: ICP,
+    /// Set if the withdrawal shares a batch neuron with other withdrawals.
+    batched: bool,
+}
+
+/// Checks that `caller` can cancel the withdrawal associated to `neuron_id`.
+async fn check_withdrawal_cancellable(
+    caller: Principal,
+    neuron_id: NeuronId,
+) -> Result<CancellableWithdrawal, CancelWithdrawalError> {
     match get_full_neuron(neuron_id.id).await {
         Ok(result) => match result {
             Ok(neuron) => match time_left_seconds(&neuron, timestamp_nanos() / crate::SEC_NANOS) {
@@ -45,28 +263,46 @@ pub async fn cancel_withdrawal(
         Err(error) => return Err(CancelWithdrawalError::GetFullNeuronError { message: error }),
     }
 
-    let icp_due = match read_state(|s| {
-        s.neuron_id_to_withdrawal_id
+    let caller_account: Account = caller.into();
+    match read_state(|s| match s.sole_withdrawal_of_neuron(neuron_id) {
+        Some(withdrawal_id) => s
+            .withdrawal_id_to_request
+            .get(&withdrawal_id)
+            .map(|request| (request.clone(), false)),
+        None => s
+            .batch_neuron_to_withdrawal_ids
             .get(&neuron_id)
-            .and_then(|withdrawal_id| s.withdrawal_id_to_request.get(withdrawal_id).cloned())
+            .and_then(|ids| {
+                let mut requests = ids
+                    .iter()
+                    .filter_map(|id| s.withdrawal_id_to_request.get(id));
+                // Any request of the batch tells a caller not owning one of them apart.
+                let first_request = requests.clone().next();
+                requests
+                    .find(|request| request.receiver == caller_account)
+                    .or(first_request)
+                    .map(|request| (request.clone(), true))
+            }),
     }) {
-        Some(withdrawal_request) => {
-            if withdrawal_request.receiver != caller.into() {
+        Some((withdrawal_request, batched)) => {
+            if withdrawal_request.receiver != caller_account {
                 return Err(CancelWithdrawalError::BadCaller {
                     message: "Caller is not the owner.".to_string(),
                 });
             }
-            withdrawal_request.icp_due
+            Ok(CancellableWithdrawal {
+                withdrawal_id: withdrawal_request.withdrawal_id,
+                icp_due: withdrawal_request.icp_due,
+                batched,
+            })
         }
-        None => return Err(CancelWithdrawalError::RequestNotFound),
-    };
-
-    log!(
-        DEBUG,
-        "[cancel_withdrawal] Cancelling neuron with id {}, ICP due: {icp_due}",
-        neuron_id.id
-    );
+        None => Err(CancelWithdrawalError::RequestNotFound),
+    }
+}
 
+async fn stop_and_merge_neuron(
+    neuron_id: NeuronId,
+) -> Result<MergeResponse, CancelWithdrawalError> {
     let stop_dissolvement_result = stop_dissolvement(neuron_id)
         .await
         .map_err(|error_msg| CancelWithdrawalError::StopDissolvementError { message: error_msg });
@@ -102,12 +338,6 @@ pub async fn cancel_withdrawal(
                     message: format!("Expected cached_neuron_stake_e8s to be 0 got {response:?}"),
                 });
             }
-
-            mutate_state(|s| {
-                process_event(s, EventType::MergeNeuron { neuron_id });
-            });
-            schedule_now(TaskType::ProcessPendingTransfers);
-            schedule_now(TaskType::RefreshShortTerm);
             Ok(response)
         }
         CommandResponse::Error(e) => Err(CancelWithdrawalError::GovernanceError(e)),
@@ -117,110 +347,324 @@ pub async fn cancel_withdrawal(
     }
 }
 
+fn check_deposit_amount(amount: ICP) -> Result<(), ConversionError> {
+    let minimum_deposit_amount = read_state(|s| s.minimum_deposit_amount);
+    if amount < minimum_deposit_amount {
+        return Err(ConversionError::AmountTooLow {
+            minimum_amount_e8s: minimum_deposit_amount.0,
+        });
+    }
+    Ok(())
+}
+
+fn check_deposits_allowed() -> Result<(), ConversionError> {
+    read_state(|s| {
+        if s.effective_protocol_mode().deposits_allowed() {
+            Ok(())
+        } else {
+            Err(ConversionError::ProtocolPaused {
+                reason: s.protocol_pause_reason(),
+            })
+        }
+    })
+}
+
+fn check_withdrawals_allowed() -> Result<(), ConversionError> {
+    read_state(|s| {
+        if s.effective_protocol_mode().withdrawals_allowed() {
+            Ok(())
+        } else {
+            Err(ConversionError::ProtocolPaused {
+                reason: s.protocol_pause_reason(),
+            })
+        }
+    })
+}
+
+/// Cancelling a withdrawal stakes ICP back and mints nICP, it needs both directions open.
+fn check_cancellation_allowed() -> Result<(), CancelWithdrawalError> {
+    read_state(|s| {
+        if s.effective_protocol_mode() == ProtocolMode::Normal {
+            Ok(())
+        } else {
+            Err(CancelWithdrawalError::ProtocolPaused {
+                reason: s.protocol_pause_reason(),
+            })
+        }
+    })
+}
+
+fn check_min_amount_out(
+    min_amount_out_e8s: Option<u64>,
+    actual: u64,
+) -> Result<(), ConversionError> {
+    match min_amount_out_e8s {
+        Some(expected) if actual < expected => {
+            Err(ConversionError::SlippageExceeded { expected, actual })
+        }
+        _ => Ok(()),
+    }
+}
+
+fn check_withdrawal_amount(icp_due: ICP) -> Result<(), ConversionError> {
+    let minimum_withdrawal_amount = read_state(|s| s.minimum_withdrawal_amount);
+    if icp_due < minimum_withdrawal_amount {
+        return Err(ConversionError::AmountTooLow {
+            minimum_amount_e8s: minimum_withdrawal_amount.0,
+        });
+    }
+    Ok(())
+}
+
+pub fn quote_icp_to_nicp(amount_e8s: u64) -> Result<DepositQuote, ConversionError> {
+    let amount = ICP::from_e8s(amount_e8s);
+    check_deposit_amount(amount)?;
+    Ok(read_state(|s| {
+        s.quote_icp_to_nicp(amount, timestamp_nanos())
+    }))
+}
+
+pub fn quote_nicp_to_icp(amount_e8s: u64) -> Result<WithdrawalQuote, ConversionError> {
+    let quote = read_state(|s| s.quote_nicp_to_icp(nICP::from_e8s(amount_e8s), timestamp_nanos()));
+    check_withdrawal_amount(quote.icp_amount)?;
+    Ok(quote)
+}
+
 pub async fn nicp_to_icp(arg: ConversionArg) -> Result<WithdrawalSuccess, ConversionError> {
+    check_withdrawals_allowed()?;
     let caller = ic_cdk::api::msg_caller();
     let _guard_principal = GuardPrincipal::new(caller)
         .map_err(|guard_error| ConversionError::GuardError { guard_error })?;
+    consume_rate_limit(caller)
+        .map_err(|guard_error| ConversionError::GuardError { guard_error })?;
 
     let (nicp_amount, maybe_subaccount) = (nICP::from_e8s(arg.amount_e8s), arg.maybe_subaccount);
     let icp_due = read_state(|s| s.convert_nicp_to_icp(nicp_amount));
 
-    if icp_due < MINIMUM_WITHDRAWAL_AMOUNT {
-        return Err(ConversionError::AmountTooLow {
-            minimum_amount_e8s: MINIMUM_WITHDRAWAL_AMOUNT.0,
-        });
-    }
+    check_withdrawal_amount(icp_due)?;
+    check_min_amount_out(arg.min_amount_out_e8s, icp_due.0)?;
 
-    let client = ICRC1Client {
-        runtime: CdkRuntime,
-        ledger_canister_id: read_state(|s| s.nicp_ledger_id),
+    let receiver = Account {
+        owner: caller,
+        subaccount: maybe_subaccount,
     };
 
+    let block_index = burn_nicp(receiver, nicp_amount).await?;
+    Ok(record_nicp_withdrawal(
+        receiver,
+        arg.beneficiary,
+        nicp_amount,
+        block_index,
+    ))
+}
+
+pub async fn nicp_to_icp_instant(
+    arg: ConversionArg,
+) -> Result<InstantWithdrawalSuccess, ConversionError> {
+    check_withdrawals_allowed()?;
+    let caller = ic_cdk::api::msg_caller();
+    let _guard_principal = GuardPrincipal::new(caller)
+        .map_err(|guard_error| ConversionError::GuardError { guard_error })?;
+    consume_rate_limit(caller)
+        .map_err(|guard_error| ConversionError::GuardError { guard_error })?;
+
+    let (nicp_amount, maybe_subaccount) = (nICP::from_e8s(arg.amount_e8s), arg.maybe_subaccount);
+    let icp_due = read_state(|s| s.convert_nicp_to_icp(nicp_amount));
+
+    check_withdrawal_amount(icp_due)?;
+    let maybe_fee = read_state(|s| s.compute_instant_withdrawal_fee(icp_due));
+    let icp_out = match maybe_fee {
+        Some(fee) => icp_due
+            .checked_sub(fee)
+            .expect("bug: the instant withdrawal fee exceeds the amount due"),
+        None => icp_due,
+    };
+    check_min_amount_out(arg.min_amount_out_e8s, icp_out.0)?;
+
     let receiver = Account {
         owner: caller,
         subaccount: maybe_subaccount,
     };
 
+    // The quoted fee is kept once the nICP are burned, the buffer it was quoted
+    // against is reserved so that concurrent withdrawals cannot drain it meanwhile.
+    let reservation = maybe_fee.map(|_| LiquidityBufferReservation::new(caller, icp_due));
+    let block_index = burn_nicp(receiver, nicp_amount).await?;
+    drop(reservation);
+
+    // The exchange rate may have moved while burning, if the buffer cannot cover the
+    // withdrawal or the caller's minimum anymore we fall back to the regular flow.
+    let (icp_due, available_buffer) = read_state(|s| {
+        (
+            s.convert_nicp_to_icp(nicp_amount),
+            s.available_liquidity_buffer(),
+        )
+    });
+    let instant = maybe_fee.and_then(|fee| {
+        icp_due
+            .checked_sub(fee)
+            .filter(|icp_out| {
+                *icp_out <= available_buffer
+                    && check_min_amount_out(arg.min_amount_out_e8s, icp_out.0).is_ok()
+            })
+            .map(|icp_out| (fee, icp_out))
+    });
+    match instant {
+        Some((fee, icp_out)) => {
+            log!(
+                INFO,
+                "[nicp_to_icp_instant] Converted {nicp_amount} nICP for {icp_due} ICP with a {fee} ICP fee by {receiver}",
+            );
+            let transfer_id = mutate_state(|s| {
+                let transfer_id = s.transfer_id;
+                process_event(
+                    s,
+                    EventType::InstantWithdrawal {
+                        receiver,
+                        nicp_burned: nicp_amount,
+                        nicp_burn_index: block_index.clone().0.try_into().unwrap(),
+                        fee,
+                        beneficiary: arg.beneficiary,
+                    },
+                );
+                transfer_id
+            });
+            schedule_now(TaskType::ProcessPendingTransfers);
+            Ok(InstantWithdrawalSuccess::Instant {
+                block_index,
+                transfer_id,
+                icp_amount: icp_out,
+                fee,
+            })
+        }
+        None => {
+            log!(
+                INFO,
+                "[nicp_to_icp_instant] The liquidity buffer cannot cover {icp_due} ICP, falling back to a regular withdrawal",
+            );
+            Ok(InstantWithdrawalSuccess::Queued(record_nicp_withdrawal(
+                receiver,
+                arg.beneficiary,
+                nicp_amount,
+                block_index,
+            )))
+        }
+    }
+}
+
+async fn burn_nicp(from: Account, nicp_amount: nICP) -> Result<Nat, ConversionError> {
+    let client = ICRC1Client {
+        runtime: CdkRuntime,
+        ledger_canister_id: read_state(|s| s.nicp_ledger_id),
+    };
+
     match client
         .transfer_from(TransferFromArgs {
             spender_subaccount: None,
-            from: receiver,
+            from,
             to: Account {
                 owner: ic_cdk::api::canister_self(),
                 subaccount: None,
             },
-            amount: Nat::from(arg.amount_e8s),
+            amount: Nat::from(nicp_amount.0),
             fee: None,
             memo: None,
             created_at_time: None,
         })
         .await
     {
-        Ok(result) => match result {
-            Ok(block_index) => {
-                let icp_due = read_state(|s| s.convert_nicp_to_icp(nicp_amount));
-                log!(
-                    INFO,
-                    "[nicp_to_icp] Converted {nicp_amount} nICP for {icp_due} ICP by {receiver}",
-                );
-                schedule_now(TaskType::ProcessLogic);
-                let withdrawal_id = mutate_state(|s| {
-                    let withdrawal_id = s.withdrawal_id;
-                    process_event(
-                        s,
-                        EventType::NIcpWithdrawal {
-                            receiver,
-                            nicp_burned: nicp_amount,
-                            nicp_burn_index: block_index.clone().0.try_into().unwrap(),
-                        },
-                    );
-                    withdrawal_id
-                });
-                Ok(WithdrawalSuccess {
-                    withdrawal_id,
-                    block_index,
-                    icp_amount: Some(icp_due),
-                })
-            }
-            Err(transfer_from_error) => {
-                Err(ConversionError::TransferFromError(transfer_from_error))
-            }
-        },
+        Ok(result) => result.map_err(ConversionError::TransferFromError),
         Err((code, message)) => Err(ConversionError::GenericError { code, message }),
     }
 }
 
+fn record_nicp_withdrawal(
+    receiver: Account,
+    beneficiary: Option<Account>,
+    nicp_amount: nICP,
+    block_index: Nat,
+) -> WithdrawalSuccess {
+    let icp_due = read_state(|s| s.convert_nicp_to_icp(nicp_amount));
+    log!(
+        INFO,
+        "[nicp_to_icp] Converted {nicp_amount} nICP for {icp_due} ICP by {receiver}",
+    );
+    schedule_now(TaskType::ProcessLogic);
+    let withdrawal_id = mutate_state(|s| {
+        let withdrawal_id = s.withdrawal_id;
+        process_event(
+            s,
+            EventType::NIcpWithdrawal {
+                receiver,
+                nicp_burned: nicp_amount,
+                nicp_burn_index: block_index.clone().0.try_into().unwrap(),
+                beneficiary,
+            },
+        );
+        withdrawal_id
+    });
+    WithdrawalSuccess {
+        withdrawal_id,
+        block_index,
+        icp_amount: Some(icp_due),
+    }
+}
+
 pub async fn icp_to_nicp(arg: ConversionArg) -> Result<DepositSuccess, ConversionError> {
+    check_deposits_allowed()?;
     let caller = ic_cdk::api::msg_caller();
     let _guard_principal = GuardPrincipal::new(caller)
         .map_err(|guard_error| ConversionError::GuardError { guard_error })?;
+    consume_rate_limit(caller)
+        .map_err(|guard_error| ConversionError::GuardError { guard_error })?;
 
     let (amount, maybe_subaccount) = (ICP::from_e8s(arg.amount_e8s), arg.maybe_subaccount);
-    if amount < MINIMUM_DEPOSIT_AMOUNT {
-        return Err(ConversionError::AmountTooLow {
-            minimum_amount_e8s: MINIMUM_DEPOSIT_AMOUNT.0,
-        });
-    }
+    check_deposit_amount(amount)?;
 
     let client = ICRC1Client {
         runtime: CdkRuntime,
         ledger_canister_id: ICP_LEDGER_ID,
     };
 
-    let receiver = Account {
+    let from = Account {
         owner: caller,
         subaccount: maybe_subaccount,
     };
+    let receiver = arg.receiver.unwrap_or(from);
+
+    if let Some(created_at_time) = arg.created_at_time {
+        if let Some(deposit) =
+            read_state(|s| s.get_deduplicated_deposit(from, created_at_time, amount))
+        {
+            log!(
+                DEBUG,
+                "[icp_to_nicp] Deduplicated deposit of {amount} ICP by {from} created at {created_at_time}",
+            );
+            return Ok(deposit);
+        }
+    }
+
+    let nicp_due = read_state(|s| s.convert_icp_to_nicp(amount));
+    check_min_amount_out(arg.min_amount_out_e8s, nicp_due.0)?;
+
+    let to_liquidity_buffer = read_state(|s| s.should_deposit_to_liquidity_buffer(amount));
+    let to = read_state(|s| {
+        if to_liquidity_buffer {
+            s.get_liquidity_buffer_account()
+        } else {
+            s.get_6m_neuron_account()
+        }
+    });
 
     match client
         .transfer_from(TransferFromArgs {
             spender_subaccount: None,
-            from: receiver,
-            to: read_state(|s| s.get_6m_neuron_account()),
+            from,
+            to,
             amount: Nat::from(arg.amount_e8s),
             fee: None,
-            memo: None,
-            created_at_time: None,
+            memo: arg.memo,
+            created_at_time: arg.created_at_time,
         })
         .await
     {
@@ -229,7 +673,7 @@ pub async fn icp_to_nicp(arg: ConversionArg) -> Result<DepositSuccess, Conversio
                 let nicp_due = read_state(|s| s.convert_icp_to_nicp(amount));
                 log!(
                     INFO,
-                    "[icp_to_nicp] Converted {amount} ICP for {nicp_due} nICP by {receiver}",
+                    "[icp_to_nicp] Converted {amount} ICP from {from} for {nicp_due} nICP to {receiver}",
                 );
                 schedule_now(TaskType::ProcessPendingTransfers);
                 schedule_now(TaskType::RefreshShortTerm);
@@ -241,6 +685,10 @@ pub async fn icp_to_nicp(arg: ConversionArg) -> Result<DepositSuccess, Conversio
                             receiver,
                             amount,
                             block_index: block_index.clone().0.try_into().unwrap(),
+                            created_at_time: arg.created_at_time,
+                            to_liquidity_buffer: to_liquidity_buffer.then_some(true),
+                            from: (from != receiver).then_some(from),
+                            deposit_block_index: None,
                         },
                     );
                 });
@@ -257,3 +705,134 @@ pub async fn icp_to_nicp(arg: ConversionArg) -> Result<DepositSuccess, Conversio
         Err((code, message)) => Err(ConversionError::GenericError { code, message }),
     }
 }
+
+/// Returns the amount of `block` if it is a transfer to `deposit_account` recent enough to
+/// still be deduplicated, see `State::record_notified_deposit`.
+fn deposit_block_amount(
+    block: Option<CandidBlock>,
+    deposit_account: Account,
+    block_index: u64,
+) -> Result<ICP, ConversionError> {
+    let invalid = |message: String| ConversionError::GenericError { code: 0, message };
+    let block = block.ok_or_else(|| invalid(format!("block {block_index} not found")))?;
+    let amount = match block.transaction.operation {
+        Some(CandidOperation::Transfer { to, amount, .. })
+            if to == AccountIdentifier::from(deposit_account).to_address() =>
+        {
+            ICP::from_e8s(amount.get_e8s())
+        }
+        _ => {
+            return Err(invalid(format!(
+                "block {block_index} is not a transfer to the deposit account {deposit_account}"
+            )));
+        }
+    };
+    if block
+        .timestamp
+        .as_nanos_since_unix_epoch()
+        .saturating_add(DEPOSIT_DEDUP_WINDOW_NANOS)
+        < timestamp_nanos()
+    {
+        return Err(invalid(format!(
+            "block {block_index} is too old to be notified"
+        )));
+    }
+    Ok(amount)
+}
+
+/// Converts the ICP previously transferred to the caller's deposit account, see
+/// `State::get_deposit_account`. The amount of the transfer at `arg.block_index` is swept to
+/// the 6 months neuron, the deposit is recorded against the block of that transfer.
+pub async fn notify_icp_deposit(
+    arg: NotifyIcpDepositArg,
+) -> Result<DepositSuccess, ConversionError> {
+    check_deposits_allowed()?;
+    let caller = ic_cdk::api::msg_caller();
+    let _guard_principal = GuardPrincipal::new(caller)
+        .map_err(|guard_error| ConversionError::GuardError { guard_error })?;
+    consume_rate_limit(caller)
+        .map_err(|guard_error| ConversionError::GuardError { guard_error })?;
+
+    let from = read_state(|s| s.get_deposit_account(caller));
+    if let Some(deposit) = read_state(|s| s.get_notified_deposit(from, arg.block_index)) {
+        log!(
+            DEBUG,
+            "[notify_icp_deposit] Deposit to {from} at block {} already notified",
+            arg.block_index
+        );
+        return Ok(deposit);
+    }
+
+    // The guard keeps the same block from being notified by another call meanwhile.
+    let block = get_icp_block(arg.block_index)
+        .await
+        .map_err(|message| ConversionError::GenericError { code: 0, message })?;
+    let deposited = deposit_block_amount(block, from, arg.block_index)?;
+    let amount = ICP::from_e8s(deposited.0.saturating_sub(DEFAULT_LEDGER_FEE));
+    let minimum_deposit_amount = read_state(|s| s.minimum_deposit_amount);
+    if amount < minimum_deposit_amount {
+        return Err(ConversionError::AmountTooLow {
+            minimum_amount_e8s: minimum_deposit_amount.0 + DEFAULT_LEDGER_FEE,
+        });
+    }
+
+    let nicp_due = read_state(|s| s.convert_icp_to_nicp(amount));
+    check_min_amount_out(arg.min_amount_out_e8s, nicp_due.0)?;
+
+    let receiver = arg.receiver.unwrap_or(Account {
+        owner: caller,
+        subaccount: None,
+    });
+
+    let to_liquidity_buffer = read_state(|s| s.should_deposit_to_liquidity_buffer(amount));
+    let to = read_state(|s| {
+        if to_liquidity_buffer {
+            s.get_liquidity_buffer_account()
+        } else {
+            s.get_6m_neuron_account()
+        }
+    });
+
+    match transfer(
+        to,
+        Nat::from(amount.0),
+        Some(Nat::from(DEFAULT_LEDGER_FEE)),
+        from.subaccount,
+        ICP_LEDGER_ID,
+        None,
+        None,
+    )
+    .await
+    {
+        Ok(block_index) => {
+            let nicp_due = read_state(|s| s.convert_icp_to_nicp(amount));
+            log!(
+                INFO,
+                "[notify_icp_deposit] Converted {amount} ICP from {from} for {nicp_due} nICP to {receiver}",
+            );
+            schedule_now(TaskType::ProcessPendingTransfers);
+            schedule_now(TaskType::RefreshShortTerm);
+            let transfer_id = read_state(|s| s.transfer_id);
+            mutate_state(|s| {
+                process_event(
+                    s,
+                    EventType::IcpDeposit {
+                        receiver,
+                        amount,
+                        block_index,
+                        created_at_time: None,
+                        to_liquidity_buffer: to_liquidity_buffer.then_some(true),
+                        from: Some(from),
+                        deposit_block_index: Some(arg.block_index),
+                    },
+                );
+            });
+            Ok(DepositSuccess {
+                block_index: Nat::from(block_index),
+                transfer_id,
+                nicp_amount: Some(nicp_due),
+            })
+        }
+        Err(e) => Err(ConversionError::TransferError(e)),
+    }
+}
@@ -166,6 +166,7 @@ pub fn build_dashboard() -> Vec<u8> {
                     </table>
                 </div>
                 {}
+                {}
             </div>
         </body>
     </html>
@@ -177,6 +178,7 @@ pub fn build_dashboard() -> Vec<u8> {
         construct_maturity_neuron_table(),
         construct_to_disburse_table(),
         get_pending_transfer_table(),
+        get_dead_letter_transfer_table(),
     )
     .into_bytes()
 }
@@ -274,6 +276,10 @@ fn construct_metadata_table() -> String {
                         <th>Exchange Rate</th>
                         <td>{}</td>
                     </tr>
+                    <tr>
+                        <th>Protocol Mode</th>
+                        <td>{}</td>
+                    </tr>
                 </tbody>
             </table>",
             link_to_dashboard(ICP_LEDGER_ID),
@@ -300,7 +306,11 @@ fn construct_metadata_table() -> String {
             s.main_neuron_8y_stake,
             s.total_circulating_nicp,
             s.account_to_deposits.keys().len(),
-            s.get_icp_to_ncip_exchange_rate_e8s()
+            s.get_icp_to_ncip_exchange_rate_e8s(),
+            match s.effective_protocol_mode_reason() {
+                Some(reason) => format!("{} ({reason})", s.effective_protocol_mode()),
+                None => s.effective_protocol_mode().to_string(),
+            }
         )
     })
 }
@@ -397,6 +407,8 @@ fn get_pending_transfer_table() -> String {
                                     <th>Receiver</th>
                                     <th>Amount</th>
                                     <th>Unit</th>
+                                    <th>Attempts</th>
+                                    <th>Last Error</th>
                                 </tr>
                             </thead>
                             <tbody>
@@ -417,6 +429,7 @@ fn construct_pending_transfer_table() -> String {
     with_utf8_buffer(|buf| {
         read_state(|s| {
             for transfer in s.pending_transfers.values() {
+                let retry = s.transfer_retries.get(&transfer.transfer_id);
                 write!(
                     buf,
                     "
@@ -425,18 +438,24 @@ fn construct_pending_transfer_table() -> String {
                     <td>{}</td>
                     <td>{}</td>
                     <td>{}</td>
+                    <td>{}</td>
+                    <td>{}</td>
                 </tr>
                 ",
                     transfer.transfer_id,
                     transfer.receiver,
                     DisplayAmount(transfer.amount),
                     transfer.unit,
+                    retry.map(|retry| retry.attempts).unwrap_or_default(),
+                    retry
+                        .map(|retry| retry.last_error.as_str())
+                        .unwrap_or_default(),
                 )
                 .unwrap();
             }
             write!(
                 buf,
-                "<tr><td colspan='3' style='text-align: right;'><b>Pending Transfers Count</b></td><td>{}</td></tr>",
+                "<tr><td colspan='5' style='text-align: right;'><b>Pending Transfers Count</b></td><td>{}</td></tr>",
                 s.pending_transfers.len()
             )
             .unwrap();
@@ -444,6 +463,72 @@ fn construct_pending_transfer_table() -> String {
     })
 }
 
+fn get_dead_letter_transfer_table() -> String {
+    with_utf8_buffer(|buf| {
+        read_state(|s| {
+            if !s.dead_letter_transfers.is_empty() {
+                write!(
+                    buf,
+                    "
+                    <h3>Dead-Letter Transfers</h3>
+                    <div class=\"table-container\">
+                        <table>
+                            <thead>
+                                <tr>
+                                    <th>Transfer Id</th>
+                                    <th>Receiver</th>
+                                    <th>Amount</th>
+                                    <th>Unit</th>
+                                    <th>Attempts</th>
+                                    <th>Error</th>
+                                    <th>Maybe Executed</th>
+                                </tr>
+                            </thead>
+                            <tbody>
+                                {}
+                            </tbody>
+                        </table>
+                    </div>
+                ",
+                    construct_dead_letter_transfer_table()
+                )
+                .unwrap();
+            }
+        });
+    })
+}
+
+fn construct_dead_letter_transfer_table() -> String {
+    with_utf8_buffer(|buf| {
+        read_state(|s| {
+            for dead_letter in s.dead_letter_transfers.values() {
+                write!(
+                    buf,
+                    "
+                <tr>
+                    <td>{}</td>
+                    <td>{}</td>
+                    <td>{}</td>
+                    <td>{}</td>
+                    <td>{}</td>
+                    <td>{}</td>
+                    <td>{}</td>
+                </tr>
+                ",
+                    dead_letter.transfer.transfer_id,
+                    dead_letter.transfer.receiver,
+                    DisplayAmount(dead_letter.transfer.amount),
+                    dead_letter.transfer.unit,
+                    dead_letter.attempts,
+                    dead_letter.error,
+                    dead_letter.maybe_executed,
+                )
+                .unwrap();
+            }
+        });
+    })
+}
+
 fn display_tasks() -> String {
     with_utf8_buffer(|buf| {
         let tasks = crate::tasks::get_task_queue();
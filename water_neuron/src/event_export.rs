@@ -0,0 +1,394 @@
+use crate::dashboard::DisplayAmount;
+use crate::state::event::{Event, EventType};
+use icrc_ledger_types::icrc1::account::Account;
+use serde_json::{Map, Value, json};
+use std::fmt::Write;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Json,
+    Csv,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(ExportFormat::Json),
+            "csv" => Ok(ExportFormat::Csv),
+            _ => Err(format!("unknown format {s}, expected json or csv")),
+        }
+    }
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "application/json; charset=utf-8",
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+        }
+    }
+}
+
+fn amount(e8s: u64) -> String {
+    DisplayAmount(e8s).to_string()
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+fn optional_account(account: &Option<Account>) -> String {
+    optional(account.as_ref())
+}
+
+/// Returns the name of the event type and its fields in a human-readable form.
+pub fn event_fields(payload: &EventType) -> (String, Vec<(&'static str, String)>) {
+    let fields = match payload {
+        EventType::Init(arg) => vec![
+            ("nicp_ledger_id", arg.nicp_ledger_id.to_string()),
+            ("wtn_governance_id", arg.wtn_governance_id.to_string()),
+            ("wtn_ledger_id", arg.wtn_ledger_id.to_string()),
+        ],
+        EventType::Upgrade(arg) => vec![("arg", format!("{arg:?}"))],
+        EventType::DistributeICPtoSNS {
+            amount: icp_amount,
+            receiver,
+        } => vec![
+            ("amount", amount(icp_amount.0)),
+            ("receiver", receiver.to_string()),
+        ],
+        EventType::TransferExecuted {
+            transfer_id,
+            block_index,
+        } => vec![
+            ("transfer_id", transfer_id.to_string()),
+            ("block_index", optional(*block_index)),
+        ],
+        EventType::IcpDeposit {
+            receiver,
+            amount: icp_amount,
+            block_index,
+            created_at_time,
+            to_liquidity_buffer,
+            from,
+            deposit_block_index,
+        } => vec![
+            ("receiver", receiver.to_string()),
+            ("amount", amount(icp_amount.0)),
+            ("block_index", block_index.to_string()),
+            ("created_at_time", optional(*created_at_time)),
+            ("to_liquidity_buffer", optional(*to_liquidity_buffer)),
+            ("from", optional_account(from)),
+            ("deposit_block_index", optional(*deposit_block_index)),
+        ],
+        EventType::NIcpWithdrawal {
+            receiver,
+            nicp_burned,
+            nicp_burn_index,
+            beneficiary,
+        } => vec![
+            ("receiver", receiver.to_string()),
+            ("nicp_burned", amount(nicp_burned.0)),
+            ("nicp_burn_index", nicp_burn_index.to_string()),
+            ("beneficiary", optional_account(beneficiary)),
+        ],
+        EventType::DispatchICPRewards {
+            nicp_amount,
+            sns_gov_amount,
+            from_neuron_type,
+        } => vec![
+            ("nicp_amount", amount(nicp_amount.0)),
+            ("sns_gov_amount", amount(sns_gov_amount.0)),
+            ("from_neuron_type", from_neuron_type.to_string()),
+        ],
+        EventType::SplitNeuron {
+            withdrawal_id,
+            neuron_id,
+        } => vec![
+            ("withdrawal_id", withdrawal_id.to_string()),
+            ("neuron_id", neuron_id.id.to_string()),
+        ],
+        EventType::StartedToDissolve { withdrawal_id } => {
+            vec![("withdrawal_id", withdrawal_id.to_string())]
+        }
+        EventType::DisbursedUserNeuron {
+            withdrawal_id,
+            transfer_block_height,
+        } => vec![
+            ("withdrawal_id", withdrawal_id.to_string()),
+            ("transfer_block_height", transfer_block_height.to_string()),
+        ],
+        EventType::MaturityNeuron {
+            neuron_id,
+            from_neuron_type,
+        } => vec![
+            ("neuron_id", neuron_id.id.to_string()),
+            ("from_neuron_type", from_neuron_type.to_string()),
+        ],
+        EventType::DisbursedMaturityNeuron {
+            neuron_id,
+            transfer_block_height,
+        }
+        | EventType::DisbursedBatchNeuron {
+            neuron_id,
+            transfer_block_height,
+        } => vec![
+            ("neuron_id", neuron_id.id.to_string()),
+            ("transfer_block_height", transfer_block_height.to_string()),
+        ],
+        EventType::NeuronSixMonths(neuron_id)
+        | EventType::NeuronEightYears(neuron_id)
+        | EventType::StartedToDissolveBatch { neuron_id } => {
+            vec![("neuron_id", neuron_id.id.to_string())]
+        }
+        EventType::ClaimedAirdrop {
+            caller,
+            block_index,
+        } => vec![
+            ("caller", caller.to_string()),
+            ("block_index", block_index.to_string()),
+        ],
+        EventType::MirroredProposal {
+            nns_proposal_id,
+            sns_proposal_id,
+        } => vec![
+            ("nns_proposal_id", nns_proposal_id.id.to_string()),
+            ("sns_proposal_id", sns_proposal_id.id.to_string()),
+        ],
+        EventType::DistributeICPtoSNSv2 => vec![],
+        EventType::InstantWithdrawal {
+            receiver,
+            nicp_burned,
+            nicp_burn_index,
+            fee,
+            beneficiary,
+        } => vec![
+            ("receiver", receiver.to_string()),
+            ("nicp_burned", amount(nicp_burned.0)),
+            ("nicp_burn_index", nicp_burn_index.to_string()),
+            ("fee", amount(fee.0)),
+            ("beneficiary", optional_account(beneficiary)),
+        ],
+        EventType::SplitNeuronBatch {
+            withdrawal_ids,
+            neuron_id,
+        } => vec![
+            (
+                "withdrawal_ids",
+                withdrawal_ids
+                    .iter()
+                    .map(|id| id.to_string())
+                    .collect::<Vec<String>>()
+                    .join(" "),
+            ),
+            ("neuron_id", neuron_id.id.to_string()),
+        ],
+        EventType::MergeNeuron {
+            neuron_id,
+            nicp_minted,
+            nicp_fee,
+        } => vec![
+            ("neuron_id", neuron_id.id.to_string()),
+            ("nicp_minted", optional(nicp_minted.map(|v| amount(v.0)))),
+            ("nicp_fee", optional(nicp_fee.map(|v| amount(v.0)))),
+        ],
+        EventType::PartialSplitNeuron {
+            neuron_id,
+            split_neuron_id,
+            icp_amount,
+            withdrawal_id,
+        } => vec![
+            ("neuron_id", neuron_id.id.to_string()),
+            ("split_neuron_id", split_neuron_id.id.to_string()),
+            ("icp_amount", amount(icp_amount.0)),
+            ("withdrawal_id", optional(*withdrawal_id)),
+        ],
+        EventType::PartialMergeNeuron {
+            neuron_id,
+            split_neuron_id,
+            icp_amount,
+            nicp_minted,
+            nicp_fee,
+        } => vec![
+            ("neuron_id", neuron_id.id.to_string()),
+            ("split_neuron_id", split_neuron_id.id.to_string()),
+            ("icp_amount", amount(icp_amount.0)),
+            ("nicp_minted", optional(nicp_minted.map(|v| amount(v.0)))),
+            ("nicp_fee", optional(nicp_fee.map(|v| amount(v.0)))),
+        ],
+        EventType::ConversionLimitsUpdated(limits) => vec![
+            (
+                "minimum_deposit_amount",
+                optional(limits.minimum_deposit_amount_e8s.map(amount)),
+            ),
+            (
+                "minimum_withdrawal_amount",
+                optional(limits.minimum_withdrawal_amount_e8s.map(amount)),
+            ),
+        ],
+        EventType::ProtocolPaused { reason } => vec![("reason", reason.clone())],
+        EventType::ProtocolResumed => vec![],
+        EventType::ProtocolModeChanged { mode, reason } => vec![
+            ("mode", mode.to_string()),
+            ("reason", optional(reason.as_ref())),
+        ],
+        EventType::TransferFailed {
+            transfer_id,
+            error,
+            maybe_executed,
+        }
+        | EventType::TransferDeadLettered {
+            transfer_id,
+            error,
+            maybe_executed,
+        } => vec![
+            ("transfer_id", transfer_id.to_string()),
+            ("error", error.clone()),
+            ("maybe_executed", maybe_executed.to_string()),
+        ],
+        EventType::TransferRequeued {
+            transfer_id,
+            receiver,
+            verified_not_executed,
+        } => vec![
+            ("transfer_id", transfer_id.to_string()),
+            ("receiver", optional_account(receiver)),
+            ("verified_not_executed", verified_not_executed.to_string()),
+        ],
+        EventType::TransferRejected {
+            transfer_id,
+            reason,
+        } => vec![
+            ("transfer_id", transfer_id.to_string()),
+            ("reason", reason.clone()),
+        ],
+        EventType::TransferRestamped { transfer_id } => {
+            vec![("transfer_id", transfer_id.to_string())]
+        }
+        EventType::WithdrawalPayoutRequeued {
+            withdrawal_id,
+            receiver,
+        } => vec![
+            ("withdrawal_id", withdrawal_id.to_string()),
+            ("receiver", receiver.to_string()),
+        ],
+    };
+    (format!("{:?}", payload.kind()), fields)
+}
+
+fn escape_csv(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Renders the events starting at index `start`, one row per event.
+///
+/// The CSV has a column per field of the events exported, in the order they first
+/// appear, the events of different kinds share the columns of the fields they have
+/// in common.
+pub fn export_events(start: u64, events: &[Event], format: ExportFormat) -> String {
+    let rows = events.iter().zip(start..).map(|(event, index)| {
+        let (kind, fields) = event_fields(&event.payload);
+        (index, event.timestamp, kind, fields)
+    });
+    match format {
+        ExportFormat::Json => {
+            let rows: Vec<Value> = rows
+                .map(|(index, timestamp, kind, fields)| {
+                    let details: Map<String, Value> = fields
+                        .into_iter()
+                        .map(|(name, value)| (name.to_string(), Value::String(value)))
+                        .collect();
+                    json!({
+                        "index": index,
+                        "timestamp": timestamp,
+                        "kind": kind,
+                        "details": details,
+                    })
+                })
+                .collect();
+            serde_json::to_string(&rows).unwrap_or_default()
+        }
+        ExportFormat::Csv => {
+            let rows: Vec<_> = rows.collect();
+            let mut columns: Vec<&'static str> = vec![];
+            for (name, _) in rows.iter().flat_map(|(_, _, _, fields)| fields) {
+                if !columns.contains(name) {
+                    columns.push(*name);
+                }
+            }
+
+            let mut buf = String::from("index,timestamp,kind");
+            for column in &columns {
+                write!(buf, ",{}", escape_csv(column)).unwrap();
+            }
+            buf.push('\n');
+            for (index, timestamp, kind, fields) in rows {
+                write!(buf, "{index},{timestamp},{kind}").unwrap();
+                for column in &columns {
+                    let value = fields
+                        .iter()
+                        .find(|(name, _)| name == column)
+                        .map(|(_, value)| escape_csv(value))
+                        .unwrap_or_default();
+                    write!(buf, ",{value}").unwrap();
+                }
+                buf.push('\n');
+            }
+            buf
+        }
+    }
+}
+
+#[test]
+fn should_export_events() {
+    use crate::numeric::ICP;
+    use candid::Principal;
+
+    let receiver: Account = Principal::anonymous().into();
+    let events = vec![
+        Event {
+            timestamp: 1,
+            payload: EventType::IcpDeposit {
+                receiver,
+                amount: ICP::from_e8s(150_000_000),
+                block_index: 3,
+                created_at_time: None,
+                to_liquidity_buffer: None,
+                from: None,
+                deposit_block_index: None,
+            },
+        },
+        Event {
+            timestamp: 2,
+            payload: EventType::TransferExecuted {
+                transfer_id: 0,
+                block_index: Some(4),
+            },
+        },
+    ];
+
+    assert_eq!(
+        export_events(7, &events, ExportFormat::Csv),
+        format!(
+            "index,timestamp,kind,receiver,amount,block_index,created_at_time,to_liquidity_buffer,from,deposit_block_index,transfer_id\n\
+             7,1,IcpDeposit,{receiver},1.5,3,,,,,\n\
+             8,2,TransferExecuted,,,4,,,,,0\n"
+        )
+    );
+
+    let json: Value = serde_json::from_str(&export_events(7, &events, ExportFormat::Json)).unwrap();
+    assert_eq!(json[0]["index"], 7);
+    assert_eq!(json[0]["kind"], "IcpDeposit");
+    assert_eq!(json[0]["details"]["amount"], "1.5");
+    assert_eq!(json[1]["details"]["block_index"], "4");
+
+    assert_eq!(ExportFormat::from_str("CSV"), Ok(ExportFormat::Csv));
+    assert!(ExportFormat::from_str("xml").is_err());
+}
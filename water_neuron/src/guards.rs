@@ -1,11 +1,23 @@
+use crate::numeric::ICP;
 use crate::state::mutate_state;
 use crate::tasks::TaskType;
+use crate::{SEC_NANOS, timestamp_nanos};
 use candid::{CandidType, Deserialize, Principal};
+use minicbor_derive::{Decode, Encode};
 use serde::Serialize;
 use std::marker::PhantomData;
 
 const MAX_CONCURRENT: usize = 100;
 
+pub const DEFAULT_PRINCIPAL_RATE_LIMIT: RateLimit = RateLimit {
+    capacity: 10,
+    refill_interval_secs: 30,
+};
+pub const DEFAULT_GLOBAL_RATE_LIMIT: RateLimit = RateLimit {
+    capacity: 200,
+    refill_interval_secs: 1,
+};
+
 /// Guards a block from executing twice when called by the same user and from being
 /// executed [MAX_CONCURRENT] or more times in parallel.
 #[must_use]
@@ -18,6 +30,7 @@ pub struct GuardPrincipal {
 pub enum GuardError {
     AlreadyProcessing,
     TooManyConcurrentRequests,
+    RateLimited { retry_after_secs: u64 },
 }
 
 impl GuardPrincipal {
@@ -47,6 +60,157 @@ impl Drop for GuardPrincipal {
     }
 }
 
+/// Reserves part of the liquidity buffer for an instant withdrawal of [principal] while its
+/// nICP are burned, the other withdrawals are quoted against the remaining buffer.
+/// Must be held with a [GuardPrincipal] for the same principal.
+#[must_use]
+pub struct LiquidityBufferReservation {
+    principal: Principal,
+    _marker: PhantomData<LiquidityBufferReservation>,
+}
+
+impl LiquidityBufferReservation {
+    pub fn new(principal: Principal, amount: ICP) -> Self {
+        mutate_state(|s| {
+            s.liquidity_buffer_reservations.insert(principal, amount);
+        });
+        Self {
+            principal,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl Drop for LiquidityBufferReservation {
+    fn drop(&mut self) {
+        mutate_state(|s| s.liquidity_buffer_reservations.remove(&self.principal));
+    }
+}
+
+/// A bucket holding up to `capacity` calls, refilled by one call
+/// every `refill_interval_secs`. A refill interval of 0 disables the limit.
+#[derive(Deserialize, Serialize, CandidType, Encode, Decode, PartialEq, Eq, Clone, Copy, Debug)]
+pub struct RateLimit {
+    #[n(0)]
+    pub capacity: u64,
+    #[n(1)]
+    pub refill_interval_secs: u64,
+}
+
+impl RateLimit {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.capacity == 0 {
+            return Err("rate limit capacity has to be greater than 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct TokenBucket {
+    tokens: u64,
+    last_refill_secs: u64,
+}
+
+impl TokenBucket {
+    pub fn full(limit: RateLimit, now_secs: u64) -> Self {
+        Self {
+            tokens: limit.capacity,
+            last_refill_secs: now_secs,
+        }
+    }
+
+    fn refill(&mut self, limit: RateLimit, now_secs: u64) {
+        if limit.refill_interval_secs == 0 {
+            *self = Self::full(limit, now_secs);
+            return;
+        }
+        let refilled = now_secs.saturating_sub(self.last_refill_secs) / limit.refill_interval_secs;
+        self.tokens = self.tokens.saturating_add(refilled).min(limit.capacity);
+        if self.tokens == limit.capacity {
+            self.last_refill_secs = now_secs;
+        } else {
+            self.last_refill_secs += refilled * limit.refill_interval_secs;
+        }
+    }
+
+    /// Returns the number of seconds to wait before the next token is available, if any.
+    fn retry_after_secs(&mut self, limit: RateLimit, now_secs: u64) -> Option<u64> {
+        self.refill(limit, now_secs);
+        if self.tokens > 0 {
+            return None;
+        }
+        Some(
+            (self.last_refill_secs + limit.refill_interval_secs)
+                .saturating_sub(now_secs)
+                .max(1),
+        )
+    }
+
+    pub fn is_full(&self, limit: RateLimit, now_secs: u64) -> bool {
+        let mut bucket = *self;
+        bucket.refill(limit, now_secs);
+        bucket.tokens == limit.capacity
+    }
+
+    /// Takes a token out of the bucket or returns the number of seconds to wait.
+    pub fn try_consume(&mut self, limit: RateLimit, now_secs: u64) -> Result<(), u64> {
+        match self.retry_after_secs(limit, now_secs) {
+            Some(retry_after_secs) => Err(retry_after_secs),
+            None => {
+                self.tokens -= 1;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Consumes a call from both the bucket of `principal` and the global bucket.
+/// Nothing is consumed if either of them is empty.
+pub fn consume_rate_limit(principal: Principal) -> Result<(), GuardError> {
+    let now_secs = timestamp_nanos() / SEC_NANOS;
+    mutate_state(|s| {
+        let (principal_limit, global_limit) = (s.principal_rate_limit, s.global_rate_limit);
+        let mut principal_bucket = s
+            .principal_rate_limit_buckets
+            .get(&principal)
+            .copied()
+            .unwrap_or_else(|| TokenBucket::full(principal_limit, now_secs));
+        let mut global_bucket = s
+            .global_rate_limit_bucket
+            .unwrap_or_else(|| TokenBucket::full(global_limit, now_secs));
+
+        let retry_after_secs = principal_bucket
+            .retry_after_secs(principal_limit, now_secs)
+            .max(global_bucket.retry_after_secs(global_limit, now_secs));
+        if let Some(retry_after_secs) = retry_after_secs {
+            return Err(GuardError::RateLimited { retry_after_secs });
+        }
+
+        principal_bucket
+            .try_consume(principal_limit, now_secs)
+            .expect("bug: the principal bucket should not be empty");
+        global_bucket
+            .try_consume(global_limit, now_secs)
+            .expect("bug: the global bucket should not be empty");
+        s.principal_rate_limit_buckets
+            .insert(principal, principal_bucket);
+        s.global_rate_limit_bucket = Some(global_bucket);
+        Ok(())
+    })
+}
+
+/// Drops the full buckets, they carry no information. Called from a timer to keep
+/// the map small without walking it on every call.
+pub fn prune_rate_limit_buckets() {
+    let now_secs = timestamp_nanos() / SEC_NANOS;
+    mutate_state(|s| {
+        let principal_limit = s.principal_rate_limit;
+        s.principal_rate_limit_buckets
+            .retain(|_, bucket| !bucket.is_full(principal_limit, now_secs));
+    });
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum TaskGuardError {
     AlreadyProcessing,
@@ -95,3 +259,55 @@ fn guard_should_exclude() {
         Err(TaskGuardError::AlreadyProcessing)
     );
 }
+
+#[test]
+fn token_bucket_should_refill() {
+    let limit = RateLimit {
+        capacity: 2,
+        refill_interval_secs: 10,
+    };
+    let mut bucket = TokenBucket::full(limit, 0);
+
+    assert_eq!(bucket.try_consume(limit, 0), Ok(()));
+    assert_eq!(bucket.try_consume(limit, 1), Ok(()));
+    assert_eq!(bucket.try_consume(limit, 2), Err(8));
+    assert_eq!(bucket.try_consume(limit, 10), Ok(()));
+    assert_eq!(bucket.try_consume(limit, 15), Err(5));
+    assert!(!bucket.is_full(limit, 25));
+    assert!(bucket.is_full(limit, 30));
+    assert_eq!(bucket.try_consume(limit, 100), Ok(()));
+    assert_eq!(bucket.try_consume(limit, 100), Ok(()));
+    assert_eq!(bucket.try_consume(limit, 100), Err(10));
+
+    let unlimited = RateLimit {
+        capacity: 1,
+        refill_interval_secs: 0,
+    };
+    let mut bucket = TokenBucket::full(unlimited, 0);
+    assert_eq!(bucket.try_consume(unlimited, 0), Ok(()));
+    assert_eq!(bucket.try_consume(unlimited, 0), Ok(()));
+}
+
+#[test]
+fn should_prune_full_buckets() {
+    let mut state = crate::state::test::default_state();
+    let now_secs = timestamp_nanos() / SEC_NANOS;
+    let limit = state.principal_rate_limit;
+    let mut empty = TokenBucket::full(limit, now_secs);
+    empty.tokens = 0;
+    state
+        .principal_rate_limit_buckets
+        .insert(Principal::anonymous(), TokenBucket::full(limit, now_secs));
+    state
+        .principal_rate_limit_buckets
+        .insert(Principal::management_canister(), empty);
+    crate::state::replace_state(state);
+
+    prune_rate_limit_buckets();
+    crate::state::read_state(|s| {
+        assert_eq!(
+            s.principal_rate_limit_buckets.keys().collect::<Vec<_>>(),
+            vec![&Principal::management_canister()]
+        );
+    });
+}
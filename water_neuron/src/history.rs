@@ -0,0 +1,666 @@
+use crate::numeric::{ICP, nICP};
+use crate::state::event::{
+    EventKind, EventType, GetEventsFilteredArg, GetEventsFilteredResult, IndexedEvent,
+};
+use crate::state::{
+    Cancellation, State, TransferStatus, WithdrawalId, WithdrawalRequest, WithdrawalStatus,
+};
+use crate::{NeuronId, Unit, storage};
+use candid::CandidType;
+use icrc_ledger_types::icrc1::account::Account;
+use serde::Deserialize;
+use std::ops::ControlFlow;
+
+const MAX_EVENTS_PER_PAGE: usize = 2_000;
+
+/// The events moving a withdrawal from one status to the next.
+const WITHDRAWAL_TRANSITION_KINDS: [EventKind; 8] = [
+    EventKind::SplitNeuron,
+    EventKind::SplitNeuronBatch,
+    EventKind::StartedToDissolve,
+    EventKind::StartedToDissolveBatch,
+    EventKind::DisbursedUserNeuron,
+    EventKind::DisbursedBatchNeuron,
+    EventKind::MergeNeuron,
+    EventKind::PartialMergeNeuron,
+];
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum AccountActivity {
+    /// ICP deposited for `receiver`, either by or for the account.
+    Deposit {
+        receiver: Account,
+        icp_amount: ICP,
+        icp_block_index: u64,
+        nicp_minted: Option<nICP>,
+        /// Set once the nICP are minted.
+        nicp_block_index: Option<u64>,
+    },
+    /// nICP burned to withdraw ICP through a dissolving neuron.
+    Withdrawal {
+        withdrawal_id: Option<WithdrawalId>,
+        nicp_burned: nICP,
+        nicp_burn_index: u64,
+        icp_due: Option<ICP>,
+        beneficiary: Option<Account>,
+        /// The current status of the withdrawal.
+        status: WithdrawalStatus,
+    },
+    WithdrawalStatusChanged {
+        withdrawal_id: WithdrawalId,
+        status: WithdrawalStatus,
+    },
+    InstantWithdrawal {
+        nicp_burned: nICP,
+        nicp_burn_index: u64,
+        fee: ICP,
+        beneficiary: Option<Account>,
+    },
+    /// `icp_amount` of the withdrawal staked back into the 6 months neuron, minting nICP.
+    WithdrawalCancelled {
+        withdrawal_id: WithdrawalId,
+        /// Set if only part of the withdrawal was cancelled.
+        partial: bool,
+        icp_amount: ICP,
+        /// The ledger fees paid by the neuron operations.
+        icp_fee: ICP,
+        /// The 0.5% cancellation fee, unknown for the cancellations recorded before
+        /// the events carried it.
+        nicp_fee: Option<nICP>,
+        nicp_minted: Option<nICP>,
+        nicp_block_index: Option<u64>,
+    },
+    /// ICP rewards sent to the principal of the account.
+    IcpRewards {
+        amount: ICP,
+    },
+    AirdropClaimed {
+        block_index: u64,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AccountActivityEntry {
+    /// The index of the event recording the activity.
+    pub event_index: u64,
+    pub timestamp: u64,
+    pub activity: AccountActivity,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AccountHistory {
+    pub entries: Vec<AccountActivityEntry>,
+    /// Set if there are more entries, pass it as the `cursor` of the next call.
+    pub next_cursor: Option<u64>,
+}
+
+fn for_each_event(
+    mut arg: GetEventsFilteredArg,
+    mut f: impl FnMut(IndexedEvent) -> ControlFlow<()>,
+) {
+    loop {
+        let GetEventsFilteredResult {
+            events,
+            next_cursor,
+            ..
+        } = storage::get_events_filtered(&arg, MAX_EVENTS_PER_PAGE);
+        for event in events {
+            if f(event).is_break() {
+                return;
+            }
+        }
+        match next_cursor {
+            Some(cursor) => arg.cursor = Some(cursor),
+            None => return,
+        }
+    }
+}
+
+/// Returns the nICP amount and ledger block index of a transfer, if executed.
+fn transfer_outcome(state: &State, transfer_id: u64) -> (Option<nICP>, Option<u64>) {
+    match state.get_transfer_status(transfer_id) {
+        TransferStatus::Pending(transfer) => (Some(nICP::from_e8s(transfer.amount)), None),
+        TransferStatus::Executed(executed) => (
+            Some(nICP::from_e8s(executed.transfer.amount)),
+            executed.block_index,
+        ),
+        TransferStatus::DeadLettered(dead_letter) => {
+            (Some(nICP::from_e8s(dead_letter.transfer.amount)), None)
+        }
+        TransferStatus::Rejected { .. } | TransferStatus::Unknown => (None, None),
+    }
+}
+
+/// Returns the transfers minting nICP to `account` with the given memo, in order.
+fn nicp_transfers_with_memo(state: &State, account: &Account, memo: Option<u64>) -> Vec<u64> {
+    state
+        .account_to_deposits
+        .get(account)
+        .into_iter()
+        .flatten()
+        .copied()
+        .filter(
+            |transfer_id| match state.get_transfer_status(*transfer_id) {
+                TransferStatus::Pending(transfer) => {
+                    transfer.unit == Unit::NICP && transfer.memo == memo
+                }
+                TransferStatus::Executed(executed) => {
+                    executed.transfer.unit == Unit::NICP && executed.transfer.memo == memo
+                }
+                TransferStatus::DeadLettered(dead_letter) => {
+                    dead_letter.transfer.unit == Unit::NICP && dead_letter.transfer.memo == memo
+                }
+                TransferStatus::Rejected { .. } => state
+                    .rejected_transfers
+                    .get(transfer_id)
+                    .is_some_and(|rejected| {
+                        rejected.transfer.unit == Unit::NICP && rejected.transfer.memo == memo
+                    }),
+                TransferStatus::Unknown => false,
+            },
+        )
+        .collect()
+}
+
+fn account_activity(state: &State, payload: &EventType) -> Vec<AccountActivity> {
+    match payload {
+        EventType::IcpDeposit {
+            receiver,
+            amount,
+            block_index,
+            ..
+        } => {
+            let (nicp_minted, nicp_block_index) =
+                nicp_transfers_with_memo(state, receiver, Some(*block_index))
+                    .first()
+                    .map(|transfer_id| transfer_outcome(state, *transfer_id))
+                    .unwrap_or_default();
+            vec![AccountActivity::Deposit {
+                receiver: *receiver,
+                icp_amount: *amount,
+                icp_block_index: *block_index,
+                nicp_minted,
+                nicp_block_index,
+            }]
+        }
+        EventType::NIcpWithdrawal {
+            receiver,
+            nicp_burned,
+            nicp_burn_index,
+            beneficiary,
+        } => {
+            let request = state
+                .account_to_withdrawals
+                .get(receiver)
+                .into_iter()
+                .flatten()
+                .filter_map(|id| state.withdrawal_id_to_request.get(id))
+                .find(|request| request.nicp_burn_index == *nicp_burn_index);
+            vec![AccountActivity::Withdrawal {
+                withdrawal_id: request.map(|request| request.withdrawal_id),
+                nicp_burned: *nicp_burned,
+                nicp_burn_index: *nicp_burn_index,
+                icp_due: request.map(|request| request.icp_due),
+                beneficiary: *beneficiary,
+                status: request
+                    .map(|request| state.get_withdrawal_status(request.withdrawal_id))
+                    .unwrap_or(WithdrawalStatus::NotFound),
+            }]
+        }
+        EventType::InstantWithdrawal {
+            nicp_burned,
+            nicp_burn_index,
+            fee,
+            beneficiary,
+            ..
+        } => vec![AccountActivity::InstantWithdrawal {
+            nicp_burned: *nicp_burned,
+            nicp_burn_index: *nicp_burn_index,
+            fee: *fee,
+            beneficiary: *beneficiary,
+        }],
+        EventType::DistributeICPtoSNS { amount, .. } => {
+            vec![AccountActivity::IcpRewards { amount: *amount }]
+        }
+        EventType::ClaimedAirdrop { block_index, .. } => vec![AccountActivity::AirdropClaimed {
+            block_index: *block_index,
+        }],
+        _ => vec![],
+    }
+}
+
+/// Tracks the withdrawals requested by an account while walking through the
+/// events moving them forward.
+struct WithdrawalTransitions<'a> {
+    state: &'a State,
+    requests: Vec<&'a WithdrawalRequest>,
+}
+
+impl<'a> WithdrawalTransitions<'a> {
+    fn new(state: &'a State, account: &Account) -> Self {
+        Self {
+            state,
+            requests: state
+                .account_to_withdrawals
+                .get(account)
+                .into_iter()
+                .flatten()
+                .filter_map(|id| state.withdrawal_id_to_request.get(id))
+                .collect(),
+        }
+    }
+
+    fn requests_with_neuron(&self, neuron_id: NeuronId) -> Vec<&'a WithdrawalRequest> {
+        self.requests
+            .iter()
+            .copied()
+            .filter(|request| request.neuron_id == Some(neuron_id))
+            .collect()
+    }
+
+    fn owns(&self, withdrawal_id: WithdrawalId) -> bool {
+        self.requests
+            .iter()
+            .any(|request| request.withdrawal_id == withdrawal_id)
+    }
+
+    fn status_changed(withdrawal_id: WithdrawalId, status: WithdrawalStatus) -> AccountActivity {
+        AccountActivity::WithdrawalStatusChanged {
+            withdrawal_id,
+            status,
+        }
+    }
+
+    /// Returns the request of the account cancelled by merging `merged_neuron_id`.
+    fn cancelled_request(
+        &self,
+        merged_neuron_id: NeuronId,
+    ) -> Option<(&'a WithdrawalRequest, &'a Cancellation)> {
+        let cancellation = self.state.cancellations.get(&merged_neuron_id)?;
+        self.requests
+            .iter()
+            .copied()
+            .find(|request| request.withdrawal_id == cancellation.withdrawal_id)
+            .map(|request| (request, cancellation))
+    }
+
+    /// The nICP transfer of `cancellation` gives the amount minted to the cancellations
+    /// which did not record it.
+    fn cancelled(
+        &self,
+        cancellation: &Cancellation,
+        icp_amount: ICP,
+        nicp_minted: Option<nICP>,
+        nicp_fee: Option<nICP>,
+    ) -> AccountActivity {
+        let (transferred, nicp_block_index) =
+            transfer_outcome(self.state, cancellation.transfer_id);
+        AccountActivity::WithdrawalCancelled {
+            withdrawal_id: cancellation.withdrawal_id,
+            partial: cancellation.partial,
+            icp_amount,
+            icp_fee: cancellation.icp_fee,
+            nicp_fee,
+            nicp_minted: nicp_minted.or(transferred),
+            nicp_block_index,
+        }
+    }
+
+    fn activity(&self, payload: &EventType) -> Vec<AccountActivity> {
+        match payload {
+            EventType::SplitNeuron {
+                withdrawal_id,
+                neuron_id,
+            } if self.owns(*withdrawal_id) => vec![Self::status_changed(
+                *withdrawal_id,
+                WithdrawalStatus::WaitingToStartDissolving {
+                    neuron_id: *neuron_id,
+                },
+            )],
+            EventType::SplitNeuronBatch {
+                withdrawal_ids,
+                neuron_id,
+            } => withdrawal_ids
+                .iter()
+                .filter(|id| self.owns(**id))
+                .map(|id| {
+                    Self::status_changed(
+                        *id,
+                        WithdrawalStatus::WaitingToStartDissolving {
+                            neuron_id: *neuron_id,
+                        },
+                    )
+                })
+                .collect(),
+            EventType::StartedToDissolve { withdrawal_id } => self
+                .requests
+                .iter()
+                .filter(|request| request.withdrawal_id == *withdrawal_id)
+                .filter_map(|request| request.neuron_id)
+                .map(|neuron_id| {
+                    Self::status_changed(
+                        *withdrawal_id,
+                        WithdrawalStatus::WaitingDissolvement { neuron_id },
+                    )
+                })
+                .collect(),
+            EventType::StartedToDissolveBatch { neuron_id } => self
+                .requests_with_neuron(*neuron_id)
+                .into_iter()
+                .map(|request| {
+                    Self::status_changed(
+                        request.withdrawal_id,
+                        WithdrawalStatus::WaitingDissolvement {
+                            neuron_id: *neuron_id,
+                        },
+                    )
+                })
+                .collect(),
+            EventType::DisbursedUserNeuron {
+                withdrawal_id,
+                transfer_block_height,
+            } if self.owns(*withdrawal_id) => vec![Self::status_changed(
+                *withdrawal_id,
+                WithdrawalStatus::ConversionDone {
+                    transfer_block_height: *transfer_block_height,
+                },
+            )],
+            // The payouts of a batch are separate transfers, report their outcome if known.
+            // The withdrawals cancelled out of the batch are not paid out.
+            EventType::DisbursedBatchNeuron { neuron_id, .. } => self
+                .requests_with_neuron(*neuron_id)
+                .into_iter()
+                .filter(|request| {
+                    !self
+                        .state
+                        .withdrawal_cancelled
+                        .contains(&request.withdrawal_id)
+                })
+                .map(|request| {
+                    let status = match self.state.withdrawal_finalized.get(&request.withdrawal_id) {
+                        Some(block_index) => WithdrawalStatus::ConversionDone {
+                            transfer_block_height: *block_index,
+                        },
+                        None => self.state.get_withdrawal_status(request.withdrawal_id),
+                    };
+                    Self::status_changed(request.withdrawal_id, status)
+                })
+                .collect(),
+            EventType::MergeNeuron {
+                neuron_id,
+                nicp_minted,
+                nicp_fee,
+            } => self
+                .cancelled_request(*neuron_id)
+                .into_iter()
+                .map(|(request, cancellation)| {
+                    self.cancelled(cancellation, request.icp_due, *nicp_minted, *nicp_fee)
+                })
+                .collect(),
+            EventType::PartialMergeNeuron {
+                neuron_id: _,
+                split_neuron_id,
+                icp_amount,
+                nicp_minted,
+                nicp_fee,
+            } => self
+                .cancelled_request(*split_neuron_id)
+                .into_iter()
+                .map(|(_, cancellation)| {
+                    self.cancelled(cancellation, *icp_amount, *nicp_minted, *nicp_fee)
+                })
+                .collect(),
+            _ => vec![],
+        }
+    }
+}
+
+/// Pushes the entries of the events matching `arg` until they take more than `limit`
+/// entries, or the event with index `to_index` is reached.
+///
+/// Returns the index of the first event not walked through, if stopped early.
+fn collect_entries(
+    arg: GetEventsFilteredArg,
+    limit: usize,
+    to_index: Option<u64>,
+    entries: &mut Vec<AccountActivityEntry>,
+    mut activity: impl FnMut(&EventType) -> Vec<AccountActivity>,
+) -> Option<u64> {
+    let mut count = 0;
+    let mut stopped_at = None;
+    for_each_event(arg, |IndexedEvent { index, event }| {
+        if to_index.is_some_and(|to_index| index >= to_index) {
+            stopped_at = Some(index);
+            return ControlFlow::Break(());
+        }
+        for activity in activity(&event.payload) {
+            count += 1;
+            entries.push(AccountActivityEntry {
+                event_index: index,
+                timestamp: event.timestamp,
+                activity,
+            });
+        }
+        // One entry past the limit tells where the page ends.
+        if count > limit {
+            stopped_at = Some(index + 1);
+            return ControlFlow::Break(());
+        }
+        ControlFlow::Continue(())
+    });
+    stopped_at
+}
+
+/// Returns the activity of `account` recorded from the event with index `cursor`,
+/// at most `limit` entries unless the last event yields several of them.
+///
+/// The entries are rebuilt from the event log through its secondary indexes, the
+/// amounts minted and the current statuses come from the state. Both the events of
+/// the account and the withdrawal transitions are only walked through up to the end
+/// of the page.
+pub fn get_account_history(
+    state: &State,
+    account: Account,
+    cursor: Option<u64>,
+    limit: usize,
+) -> AccountHistory {
+    let mut entries: Vec<AccountActivityEntry> = vec![];
+    let account_stopped_at = collect_entries(
+        GetEventsFilteredArg {
+            account: Some(account),
+            cursor,
+            ..Default::default()
+        },
+        limit,
+        None,
+        &mut entries,
+        |payload| account_activity(state, payload),
+    );
+
+    let transitions = WithdrawalTransitions::new(state, &account);
+    let mut transitions_stopped_at = None;
+    if let Some(first_request) = transitions
+        .requests
+        .iter()
+        .map(|request| request.timestamp)
+        .min()
+    {
+        // The page cannot end past the events of the account walked through.
+        transitions_stopped_at = collect_entries(
+            GetEventsFilteredArg {
+                kinds: WITHDRAWAL_TRANSITION_KINDS.to_vec(),
+                from_timestamp: Some(first_request),
+                cursor,
+                ..Default::default()
+            },
+            limit,
+            account_stopped_at,
+            &mut entries,
+            |payload| transitions.activity(payload),
+        );
+    }
+    entries.sort_by_key(|entry| entry.event_index);
+
+    let mut result: Vec<AccountActivityEntry> = vec![];
+    let mut next_cursor = None;
+    for entry in entries {
+        if result.len() >= limit
+            && result
+                .last()
+                .is_none_or(|last| last.event_index != entry.event_index)
+        {
+            next_cursor = Some(entry.event_index);
+            break;
+        }
+        result.push(entry);
+    }
+    // Resume where a scan stopped if no entry was left out before it.
+    for stopped_at in [account_stopped_at, transitions_stopped_at]
+        .into_iter()
+        .flatten()
+    {
+        next_cursor = Some(next_cursor.map_or(stopped_at, |cursor: u64| cursor.min(stopped_at)));
+    }
+    AccountHistory {
+        entries: result,
+        next_cursor,
+    }
+}
+
+#[test]
+fn should_rebuild_account_history() {
+    use crate::DEFAULT_LEDGER_FEE;
+    use crate::state::audit::apply_state_transition;
+    use crate::state::test::default_state;
+    use candid::Principal;
+
+    let alice: Account = Principal::from_slice(&[1]).into();
+    let bob: Account = Principal::from_slice(&[2]).into();
+    let mut state = default_state();
+    let mut timestamp = 0;
+    let mut apply = |state: &mut State, payload: EventType| {
+        timestamp += 1;
+        apply_state_transition(state, &payload, timestamp);
+        storage::record_event(payload, timestamp);
+    };
+
+    for (receiver, block_index) in [(alice, 5), (bob, 6)] {
+        apply(
+            &mut state,
+            EventType::IcpDeposit {
+                receiver,
+                amount: ICP::from_unscaled(10),
+                block_index,
+                created_at_time: None,
+                to_liquidity_buffer: None,
+                from: None,
+                deposit_block_index: None,
+            },
+        );
+    }
+    apply(
+        &mut state,
+        EventType::TransferExecuted {
+            transfer_id: 0,
+            block_index: Some(9),
+        },
+    );
+    apply(
+        &mut state,
+        EventType::NIcpWithdrawal {
+            receiver: alice,
+            nicp_burned: nICP::from_unscaled(5),
+            nicp_burn_index: 7,
+            beneficiary: None,
+        },
+    );
+    apply(
+        &mut state,
+        EventType::SplitNeuron {
+            withdrawal_id: 0,
+            neuron_id: NeuronId { id: 1 },
+        },
+    );
+    apply(
+        &mut state,
+        EventType::PartialSplitNeuron {
+            neuron_id: NeuronId { id: 1 },
+            split_neuron_id: NeuronId { id: 2 },
+            icp_amount: ICP::ONE,
+            withdrawal_id: None,
+        },
+    );
+    let minted = state.quote_partial_neuron_merge(NeuronId { id: 2 });
+    apply(
+        &mut state,
+        EventType::PartialMergeNeuron {
+            neuron_id: NeuronId { id: 1 },
+            split_neuron_id: NeuronId { id: 2 },
+            icp_amount: ICP::ONE,
+            nicp_minted: minted.map(|(nicp_minted, _)| nicp_minted),
+            nicp_fee: minted.map(|(_, nicp_fee)| nicp_fee),
+        },
+    );
+
+    let history = get_account_history(&state, alice, None, 10);
+    assert_eq!(history.next_cursor, None);
+    assert_eq!(
+        history
+            .entries
+            .iter()
+            .map(|entry| entry.event_index)
+            .collect::<Vec<u64>>(),
+        vec![0, 3, 4, 6]
+    );
+    assert_eq!(
+        history.entries[0].activity,
+        AccountActivity::Deposit {
+            receiver: alice,
+            icp_amount: ICP::from_unscaled(10),
+            icp_block_index: 5,
+            nicp_minted: Some(nICP::from_unscaled(10)),
+            nicp_block_index: Some(9),
+        }
+    );
+    assert_eq!(
+        history.entries[2].activity,
+        AccountActivity::WithdrawalStatusChanged {
+            withdrawal_id: 0,
+            status: WithdrawalStatus::WaitingToStartDissolving {
+                neuron_id: NeuronId { id: 1 }
+            },
+        }
+    );
+    assert_eq!(
+        history.entries[3].activity,
+        AccountActivity::WithdrawalCancelled {
+            withdrawal_id: 0,
+            partial: true,
+            icp_amount: ICP::ONE,
+            icp_fee: ICP::from_e8s(3 * DEFAULT_LEDGER_FEE),
+            nicp_fee: Some(nICP::from_e8s(499_850)),
+            nicp_minted: Some(nICP::from_e8s(99_470_150)),
+            nicp_block_index: None,
+        }
+    );
+
+    let page = get_account_history(&state, alice, None, 2);
+    assert_eq!(page.entries.len(), 2);
+    assert_eq!(page.next_cursor, Some(4));
+    let page = get_account_history(&state, alice, page.next_cursor, 2);
+    assert_eq!(page.entries.len(), 2);
+    assert_eq!(page.next_cursor, None);
+
+    let mut cursor = None;
+    let mut entries = vec![];
+    loop {
+        let page = get_account_history(&state, alice, cursor, 1);
+        assert_eq!(page.entries.len(), 1);
+        entries.extend(page.entries);
+        match page.next_cursor {
+            Some(next_cursor) => cursor = Some(next_cursor),
+            None => break,
+        }
+    }
+    assert_eq!(entries, history.entries);
+}
@@ -1,7 +1,9 @@
-use crate::ConversionArg;
 use crate::dashboard::DisplayAmount;
 use crate::nns_types::NeuronId;
+use crate::state::read_state;
+use crate::{ConversionArg, NotifyIcpDepositArg, ProtocolMode};
 use candid::{CandidType, Decode, Deserialize};
+use icrc_ledger_types::icrc1::account::Account;
 use strum::IntoEnumIterator;
 use strum_macros::{Display, EnumIter, EnumString};
 
@@ -12,14 +14,34 @@ pub const MAX_CONSENT_MESSAGE_ARG_SIZE_BYTES: u16 = 500;
 pub enum Icrc21Function {
     #[strum(serialize = "icp_to_nicp")]
     Stake,
+    #[strum(serialize = "notify_icp_deposit")]
+    NotifyDeposit,
     #[strum(serialize = "nicp_to_icp")]
     Unstake,
+    #[strum(serialize = "nicp_to_icp_instant")]
+    InstantUnstake,
     #[strum(serialize = "cancel_withdrawal")]
     CancelWithdrawal,
+    #[strum(serialize = "cancel_withdrawal_partial")]
+    CancelWithdrawalPartial,
     #[strum(serialize = "claim_airdrop")]
     ClaimAirdrop,
 }
 
+impl Icrc21Function {
+    /// Whether the call is accepted in the given protocol mode.
+    pub fn is_allowed_in(&self, mode: ProtocolMode) -> bool {
+        match self {
+            Icrc21Function::Stake | Icrc21Function::NotifyDeposit => mode.deposits_allowed(),
+            Icrc21Function::Unstake | Icrc21Function::InstantUnstake => mode.withdrawals_allowed(),
+            Icrc21Function::CancelWithdrawal | Icrc21Function::CancelWithdrawalPartial => {
+                mode == ProtocolMode::Normal
+            }
+            Icrc21Function::ClaimAirdrop => mode != ProtocolMode::FullyPaused,
+        }
+    }
+}
+
 #[derive(CandidType, Deserialize)]
 pub struct StandardRecord {
     pub url: String,
@@ -87,6 +109,44 @@ pub struct ErrorInfo {
     pub description: String,
 }
 
+fn with_receiver(message: String, receiver: Option<Account>) -> String {
+    match receiver {
+        Some(receiver) => format!(
+            "{message}\nThe ICP are paid from your account and the nICP are sent to the receiver account: {receiver}."
+        ),
+        None => message,
+    }
+}
+
+fn with_min_amount_out(message: String, min_amount_out_e8s: Option<u64>, unit: &str) -> String {
+    match min_amount_out_e8s {
+        Some(min_amount_out_e8s) => format!(
+            "{message}\nThe call fails without moving any funds if you would receive less than {} {unit}.",
+            DisplayAmount(min_amount_out_e8s)
+        ),
+        None => message,
+    }
+}
+
+fn with_beneficiary(message: String, beneficiary: Option<Account>) -> String {
+    match beneficiary {
+        Some(beneficiary) => {
+            format!("{message}\nThe ICP will be sent to the beneficiary account: {beneficiary}.")
+        }
+        None => message,
+    }
+}
+
+fn with_protocol_mode(message: String, method: &str) -> String {
+    let protocol_mode = read_state(|s| s.effective_protocol_mode());
+    match method.parse::<Icrc21Function>() {
+        Ok(function) if !function.is_allowed_in(protocol_mode) => format!(
+            "{message}\nThe protocol is currently in {protocol_mode} mode, this call will be rejected."
+        ),
+        _ => message,
+    }
+}
+
 pub fn icrc10_supported_standards() -> Vec<StandardRecord> {
     vec![
         StandardRecord {
@@ -127,20 +187,28 @@ pub fn icrc21_canister_call_consent_message(
             let arg = Decode!(&request.arg, ConversionArg).map_err(|e| Icrc21Error::UnsupportedCanisterCall(ErrorInfo {
                 description: format!("Failed to decode ConversionArg: {}", e)
             }))?;
-            match arg.maybe_subaccount {
+            let message = match arg.maybe_subaccount {
                 Some(subaccount) => format!("Convert {} ICP to nICP at the current exchange rate. 
                     Specified subaccount: {}.", 
                     DisplayAmount(arg.amount_e8s),
                     hex::encode(subaccount)
                 ),
                 None => format!("Convert {} ICP to nICP at the current exchange rate.", DisplayAmount(arg.amount_e8s))
-            }
+            };
+            with_min_amount_out(with_receiver(message, arg.receiver), arg.min_amount_out_e8s, "nICP")
+        },
+        Icrc21Function::NotifyDeposit =>  {
+            let arg = Decode!(&request.arg, NotifyIcpDepositArg).map_err(|e| Icrc21Error::UnsupportedCanisterCall(ErrorInfo {
+                description: format!("Failed to decode NotifyIcpDepositArg: {}", e)
+            }))?;
+            let message = format!("Convert the ICP transferred to your deposit account at block {} to nICP at the current exchange rate.", arg.block_index);
+            with_min_amount_out(with_receiver(message, arg.receiver), arg.min_amount_out_e8s, "nICP")
         },
         Icrc21Function::Unstake =>  {
             let arg = Decode!(&request.arg, ConversionArg).map_err(|e| Icrc21Error::UnsupportedCanisterCall(ErrorInfo {
                 description: format!("Failed to decode ConversionArg: {}", e),
             }))?;
-            match arg.maybe_subaccount {
+            let message = match arg.maybe_subaccount {
                 Some(subaccount) => format!(
                     "Convert {} nICP to ICP at the current exchange rate after a 2 weeks dissolve delay.
                     Specified subaccount: {}.",
@@ -151,7 +219,26 @@ pub fn icrc21_canister_call_consent_message(
                     "Convert {} nICP to ICP at the current exchange rate after a 2 weeks dissolve delay.",
                     DisplayAmount(arg.amount_e8s)
                 )
-            }
+            };
+            with_min_amount_out(with_beneficiary(message, arg.beneficiary), arg.min_amount_out_e8s, "ICP")
+        },
+        Icrc21Function::InstantUnstake =>  {
+            let arg = Decode!(&request.arg, ConversionArg).map_err(|e| Icrc21Error::UnsupportedCanisterCall(ErrorInfo {
+                description: format!("Failed to decode ConversionArg: {}", e),
+            }))?;
+            let message = match arg.maybe_subaccount {
+                Some(subaccount) => format!(
+                    "Convert {} nICP to ICP at the current exchange rate, paid immediately minus a fee depending on the liquidity buffer utilisation. If the liquidity buffer is insufficient, the withdrawal follows the 2 weeks dissolve delay.
+                    Specified subaccount: {}.",
+                    DisplayAmount(arg.amount_e8s),
+                    hex::encode(subaccount)
+                ),
+                None => format!(
+                    "Convert {} nICP to ICP at the current exchange rate, paid immediately minus a fee depending on the liquidity buffer utilisation. If the liquidity buffer is insufficient, the withdrawal follows the 2 weeks dissolve delay.",
+                    DisplayAmount(arg.amount_e8s)
+                )
+            };
+            with_min_amount_out(with_beneficiary(message, arg.beneficiary), arg.min_amount_out_e8s, "ICP")
         },
         Icrc21Function::CancelWithdrawal =>  {
             let arg = Decode!(&request.arg, NeuronId).map_err(|e| Icrc21Error::UnsupportedCanisterCall(ErrorInfo {
@@ -159,6 +246,12 @@ pub fn icrc21_canister_call_consent_message(
             }))?;
             format!("Cancel the withdrawal associated to the neuron id: {}.", arg.id)
         },
+        Icrc21Function::CancelWithdrawalPartial =>  {
+            let (neuron_id, icp_amount_e8s) = Decode!(&request.arg, NeuronId, u64).map_err(|e| Icrc21Error::UnsupportedCanisterCall(ErrorInfo {
+                description: format!("Failed to decode NeuronId and amount: {}", e),
+            }))?;
+            format!("Cancel {} ICP of the withdrawal associated to the neuron id: {}.", DisplayAmount(icp_amount_e8s), neuron_id.id)
+        },
         Icrc21Function::ClaimAirdrop =>  {
             "Claim WTN tokens associated to your airdrop allocation.".to_string()
         }
@@ -166,6 +259,9 @@ pub fn icrc21_canister_call_consent_message(
 
     Ok(ConsentInfo {
         metadata,
-        consent_message: ConsentMessage::GenericDisplayMessage(message),
+        consent_message: ConsentMessage::GenericDisplayMessage(with_protocol_mode(
+            message,
+            &request.method,
+        )),
     })
 }
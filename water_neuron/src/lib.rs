@@ -1,5 +1,5 @@
 use crate::dashboard::DisplayAmount;
-use crate::guards::{GuardError, TaskGuard};
+use crate::guards::{GuardError, RateLimit, TaskGuard};
 use crate::logs::{DEBUG, INFO};
 use crate::management::{
     balance_of, disburse, follow_neuron, get_full_neuron, increase_dissolve_delay, list_neurons,
@@ -7,19 +7,25 @@ use crate::management::{
     start_dissolving, transfer,
 };
 use crate::nns_types::{NeuronId, ProposalId, is_dissolved};
-use crate::numeric::{ICP, nICP};
+use crate::numeric::{ICP, WTN, nICP};
 use crate::proposal::{early_voting_on_nns_proposals, process_voting_cycle};
 use crate::sns_governance::{
     CanisterRuntime, IcCanisterRuntime, WTN_MAX_DISSOLVE_DELAY_SECONDS, process_icp_distribution,
 };
-use crate::state::audit::process_event;
+use crate::state::audit::{
+    ReplayProgress, finish_event_replay, get_replay_next_event, initial_replay_state, is_replaying,
+    process_event, replay_events_until, set_replay_next_event, start_event_replay,
+};
 use crate::state::event::EventType;
 use crate::state::{
     EIGHT_YEARS_NEURON_NONCE, ICP_LEDGER_ID, NNS_GOVERNANCE_ID, NeuronOrigin,
-    SIX_MONTHS_NEURON_NONCE, SNS_GOVERNANCE_SUBACCOUNT, TransferId, mutate_state, read_state,
+    SIX_MONTHS_NEURON_NONCE, SNS_GOVERNANCE_SUBACCOUNT, State, TransferId, WithdrawalId,
+    mutate_state, read_state, replace_state,
 };
 use crate::storage::{
-    are_rewards_distributed, get_rewards_ready_to_be_distributed, stable_sub_rewards,
+    are_rewards_distributed, get_exchange_rate_at, get_rewards_ready_to_be_distributed,
+    index_events_until, indexed_event_count, record_exchange_rate, record_state_checkpoint,
+    stable_sub_rewards, total_event_count,
 };
 use crate::tasks::{TaskType, schedule_after, schedule_now};
 use candid::{CandidType, Deserialize, Nat, Principal};
@@ -30,7 +36,7 @@ use ic_nns_governance_api::{
 };
 use icrc_ledger_client_cdk::{CdkRuntime, ICRC1Client};
 use icrc_ledger_types::icrc1::account::Account;
-use icrc_ledger_types::icrc1::transfer::TransferError;
+use icrc_ledger_types::icrc1::transfer::{Memo, TransferError};
 use icrc_ledger_types::icrc2::transfer_from::TransferFromError;
 use minicbor::{Decode, Encode};
 use serde::Serialize;
@@ -43,13 +49,16 @@ use strum::IntoEnumIterator;
 pub mod cbor;
 pub mod conversion;
 pub mod dashboard;
+pub mod event_export;
 pub mod guards;
+pub mod history;
 pub mod icrc21;
 pub mod logs;
 pub mod management;
 pub mod nns_types;
 pub mod numeric;
 pub mod proposal;
+pub mod reconciliation;
 pub mod sns_distribution;
 pub mod sns_governance;
 pub mod state;
@@ -69,6 +78,13 @@ const LOGIC_DELAY: Duration = RETRY_DELAY;
 const LOGIC_DELAY: Duration = ONE_HOUR;
 
 const ONE_MINUTE: Duration = Duration::from_secs(60);
+
+// Instructions spent replaying events in post_upgrade before continuing in timers.
+const POST_UPGRADE_REPLAY_INSTRUCTIONS: u64 = 100_000_000_000;
+// Instructions spent replaying events in each timer.
+const REPLAY_CHUNK_INSTRUCTIONS: u64 = 20_000_000_000;
+// Instructions spent indexing the event log in each timer.
+const INDEX_CHUNK_INSTRUCTIONS: u64 = 10_000_000_000;
 const ONE_HOUR: Duration = Duration::from_secs(60 * 60);
 pub const ONE_DAY: Duration = Duration::from_secs(24 * 60 * 60);
 
@@ -91,9 +107,22 @@ pub const INITIAL_NEURON_STAKE: u64 = E8S + 42;
 
 pub const SNS_DISTRIBUTION_MEMO: u64 = 83_78_83;
 
+// A pending transfer failing this many times is dead-lettered, even on retryable errors.
+pub const MAX_TRANSFER_ATTEMPTS: u32 = 20;
+
+// Pending transfers are only attempted while their created_at_time is this recent, an hour
+// short of the 24 hours over which the ledgers deduplicate transactions.
+pub const TRANSFER_DEDUP_WINDOW: Duration = Duration::from_secs(23 * 60 * 60);
+
+// The ICP ledger deduplicates transactions created in the last 24 hours, with a 2 minutes drift.
+pub const DEPOSIT_DEDUP_WINDOW_NANOS: u64 = (ONE_DAY_SECONDS + 2 * 60) * SEC_NANOS;
+
 pub const NEURON_6M_APY: f64 = 0.071;
 pub const NEURON_8Y_APY: f64 = 0.134;
 
+pub const DEFAULT_INSTANT_WITHDRAWAL_MIN_FEE_BPS: u64 = 30;
+pub const DEFAULT_INSTANT_WITHDRAWAL_MAX_FEE_BPS: u64 = 300;
+
 pub const TVL_MIN: u64 = 2_000_000 * E8S;
 pub const TVL_MAX: u64 = 40_000_000 * E8S;
 pub const CUT_MIN_PERCENT: u64 = 10;
@@ -152,13 +181,186 @@ pub struct InitArg {
     pub wtn_ledger_id: Principal,
 }
 
-#[derive(Deserialize, CandidType, Encode, Decode, PartialEq, Eq, Clone, Debug)]
+#[derive(Deserialize, CandidType, Encode, Decode, PartialEq, Eq, Clone, Debug, Default)]
 pub struct UpgradeArg {
     // This is a legacy upgrade argument when both the nICP fee and WTN gov fee were equal.
     // At this point the gov fee gets calculated, and is not defined by parameters (see `compute_governance_8y_share_percent`).
     // The upgrade arg can only be used to change the 6m fee.
     #[n(0)]
     pub governance_fee_share_percent: Option<u64>,
+    #[n(1)]
+    pub liquidity_buffer_target_e8s: Option<u64>,
+    // Share of the ICP rewards for nICP holders routed to the liquidity buffer while it is below target.
+    #[n(2)]
+    pub liquidity_buffer_rewards_share_percent: Option<u64>,
+    // Fee of an instant withdrawal in basis points, when the buffer is at or above its target.
+    #[n(3)]
+    pub instant_withdrawal_min_fee_bps: Option<u64>,
+    // Fee of an instant withdrawal in basis points, when the buffer gets fully drained.
+    #[n(4)]
+    pub instant_withdrawal_max_fee_bps: Option<u64>,
+    // Withdrawals queued during this window are split into a single neuron, 0 disables batching.
+    #[n(5)]
+    pub withdrawal_epoch_seconds: Option<u64>,
+    #[n(6)]
+    pub conversion_limits: Option<ConversionLimits>,
+    // Rate limit of the conversion endpoints, for each caller and across all callers.
+    #[n(7)]
+    pub principal_rate_limit: Option<RateLimit>,
+    #[n(8)]
+    pub global_rate_limit: Option<RateLimit>,
+    // Conversions are paused when reconciliations drift by more than these amounts of ICP
+    // and nICP.
+    #[n(9)]
+    pub max_reconciliation_drift_e8s: Option<u64>,
+    #[n(10)]
+    pub max_reconciliation_nicp_drift_e8s: Option<u64>,
+}
+
+#[derive(Deserialize, CandidType, Encode, Decode, PartialEq, Eq, Clone, Debug, Default)]
+pub struct ConversionLimits {
+    #[n(0)]
+    pub minimum_deposit_amount_e8s: Option<u64>,
+    #[n(1)]
+    pub minimum_withdrawal_amount_e8s: Option<u64>,
+}
+
+impl ConversionLimits {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(minimum_deposit_amount_e8s) = self.minimum_deposit_amount_e8s
+            && minimum_deposit_amount_e8s <= DEFAULT_LEDGER_FEE
+        {
+            return Err(
+                "minimum_deposit_amount_e8s has to be greater than the ledger fee".to_string(),
+            );
+        }
+        // A withdrawal is split into a neuron of at least 1 ICP, the split costs a ledger fee.
+        if let Some(minimum_withdrawal_amount_e8s) = self.minimum_withdrawal_amount_e8s
+            && minimum_withdrawal_amount_e8s < E8S + DEFAULT_LEDGER_FEE
+        {
+            return Err(
+                "minimum_withdrawal_amount_e8s has to cover the minimum neuron stake".to_string(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The conversions accepted by the protocol, set by governance or automatically by the
+/// reconciliation when the tracked accounting drifts.
+#[derive(
+    CandidType, Serialize, Deserialize, Encode, Decode, Clone, Copy, Debug, Default, PartialEq, Eq,
+)]
+pub enum ProtocolMode {
+    #[default]
+    #[n(0)]
+    Normal,
+    #[n(1)]
+    DepositsPaused,
+    #[n(2)]
+    WithdrawalsPaused,
+    /// Also pauses the airdrop claims and the reward distributions.
+    #[n(3)]
+    FullyPaused,
+}
+
+impl ProtocolMode {
+    pub fn deposits_allowed(self) -> bool {
+        matches!(self, ProtocolMode::Normal | ProtocolMode::WithdrawalsPaused)
+    }
+
+    pub fn withdrawals_allowed(self) -> bool {
+        matches!(self, ProtocolMode::Normal | ProtocolMode::DepositsPaused)
+    }
+}
+
+impl fmt::Display for ProtocolMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolMode::Normal => write!(f, "Normal"),
+            ProtocolMode::DepositsPaused => write!(f, "Deposits Paused"),
+            ProtocolMode::WithdrawalsPaused => write!(f, "Withdrawals Paused"),
+            ProtocolMode::FullyPaused => write!(f, "Fully Paused"),
+        }
+    }
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+pub struct SetProtocolModeArg {
+    pub mode: ProtocolMode,
+    /// Shown to the users whose calls get rejected.
+    pub reason: Option<String>,
+}
+
+impl SetProtocolModeArg {
+    const MAX_REASON_LENGTH: usize = 256;
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(reason) = &self.reason
+            && reason.len() > Self::MAX_REASON_LENGTH
+        {
+            return Err(format!(
+                "reason has to be at most {} bytes long",
+                Self::MAX_REASON_LENGTH
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+pub struct RequeueTransferArg {
+    pub transfer_id: TransferId,
+    /// Redirects the transfer to this account, the transfer keeps its receiver if not set.
+    pub receiver: Option<Account>,
+    /// Set once the ledger was checked to hold no attempt of a transfer that may have been
+    /// executed, the transfer then gets a new `created_at_time`.
+    pub verified_not_executed: bool,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+pub struct RequeueWithdrawalPayoutArg {
+    pub withdrawal_id: WithdrawalId,
+    /// The account paid instead of the receiver that rejected the payout.
+    pub receiver: Account,
+}
+
+impl RequeueWithdrawalPayoutArg {
+    pub fn validate(&self, state: &State) -> Result<(), String> {
+        if self.receiver == NNS_GOVERNANCE_ID.into() {
+            return Err("cannot pay a withdrawal out to the NNS governance".to_string());
+        }
+        state
+            .requeued_withdrawal_payout_amount(self.withdrawal_id)
+            .map(|_| ())
+    }
+}
+
+impl RequeueTransferArg {
+    pub fn validate(&self, state: &State, now: u64) -> Result<(), String> {
+        let dead_letter = match state.dead_letter_transfers.get(&self.transfer_id) {
+            Some(dead_letter) => dead_letter,
+            None => {
+                return Err(format!(
+                    "transfer {} is not dead-lettered",
+                    self.transfer_id
+                ));
+            }
+        };
+        if self.receiver == Some(NNS_GOVERNANCE_ID.into()) {
+            return Err("cannot redirect a transfer to the NNS governance".to_string());
+        }
+        if dead_letter.maybe_executed
+            && !self.verified_not_executed
+            && dead_letter.transfer.is_out_of_dedup_window(now)
+        {
+            return Err(format!(
+                "transfer {} may have been executed and is out of the ledger deduplication window, check the ledger",
+                self.transfer_id
+            ));
+        }
+        Ok(())
+    }
 }
 
 #[derive(CandidType, Debug, Deserialize, PartialEq, Serialize)]
@@ -179,9 +381,45 @@ pub struct CanisterInfo {
     pub minimum_withdraw_amount: ICP,
     pub nicp_share_percent: u64,
     pub governance_share_percent: u64,
+    pub liquidity_buffer: ICP,
+    pub liquidity_buffer_target: ICP,
+    /// Set while the event log is being replayed, state changing calls are rejected meanwhile.
+    pub replay_progress: Option<ReplayProgress>,
+    pub protocol_mode: ProtocolMode,
+    pub protocol_mode_reason: Option<String>,
+    /// Estimated from the expected rewards of the main neurons.
+    pub estimated_apy: f64,
+    pub realized_apy: Vec<RealizedApy>,
 }
 
-#[derive(CandidType, Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Encode, Decode)]
+pub const REALIZED_APY_WINDOWS_DAYS: [u64; 4] = [7, 30, 90, 365];
+
+#[derive(CandidType, Debug, Deserialize, PartialEq, Serialize, Clone)]
+pub struct RealizedApy {
+    pub window_days: u64,
+    /// Not set if the exchange rate history does not cover the window yet.
+    pub apy: Option<f64>,
+}
+
+/// The APY earned by nICP holders over each of [REALIZED_APY_WINDOWS_DAYS],
+/// derived from the exchange rate history.
+pub fn compute_realized_apy(now: u64) -> Vec<RealizedApy> {
+    let current = read_state(|s| s.get_exchange_rate(now));
+    REALIZED_APY_WINDOWS_DAYS
+        .iter()
+        .map(|&window_days| RealizedApy {
+            window_days,
+            apy: get_exchange_rate_at(
+                now.saturating_sub(window_days * ONE_DAY_SECONDS * SEC_NANOS),
+            )
+            .and_then(|past| current.realized_apy_since(&past)),
+        })
+        .collect()
+}
+
+#[derive(
+    CandidType, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize, Encode, Decode,
+)]
 pub enum Unit {
     #[n(0)]
     ICP = 0,
@@ -223,6 +461,30 @@ impl Unit {
 pub struct ConversionArg {
     pub amount_e8s: u64,
     pub maybe_subaccount: Option<[u8; 32]>,
+    /// Forwarded to the ICP ledger by `icp_to_nicp`. Retrying a deposit with the same
+    /// `created_at_time` and amount returns the original `DepositSuccess`.
+    pub created_at_time: Option<u64>,
+    /// Forwarded to the ICP ledger by `icp_to_nicp`.
+    pub memo: Option<Memo>,
+    /// Account receiving the ICP of a withdrawal, defaults to the caller's account.
+    pub beneficiary: Option<Account>,
+    /// Account credited with the nICP of a deposit, defaults to the caller's account.
+    /// The ICP are always pulled from the caller's account.
+    pub receiver: Option<Account>,
+    /// The call fails before moving any funds if it would return less than this amount,
+    /// in nICP for deposits and in ICP for withdrawals.
+    pub min_amount_out_e8s: Option<u64>,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct NotifyIcpDepositArg {
+    /// The ICP ledger block index of the transfer to the caller's deposit account, made
+    /// in the last day. Notifying the same block again returns the original `DepositSuccess`.
+    pub block_index: u64,
+    /// Account credited with the nICP, defaults to the caller's account.
+    pub receiver: Option<Account>,
+    /// The call fails before moving any funds if it would return less than this amount of nICP.
+    pub min_amount_out_e8s: Option<u64>,
 }
 
 #[derive(CandidType, Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Encode, Decode)]
@@ -239,6 +501,37 @@ pub struct PendingTransfer {
     pub receiver: Account,
     #[n(5)]
     pub unit: Unit,
+    /// The time the transfer was enqueued at, sent to the ledger so that a transfer
+    /// attempted again is rejected as a duplicate of the first successful attempt.
+    #[n(6)]
+    pub created_at_time: u64,
+}
+
+impl PendingTransfer {
+    /// Returns true once the ledger may no longer report an attempt with this
+    /// `created_at_time` as a duplicate.
+    pub fn is_out_of_dedup_window(&self, now: u64) -> bool {
+        now >= self
+            .created_at_time
+            .saturating_add(TRANSFER_DEDUP_WINDOW.as_nanos() as u64)
+    }
+
+    /// Returns why the ledger would reject this transfer, it is then never attempted.
+    pub fn rejection_reason(&self) -> Option<String> {
+        let fee = self.unit.fee();
+        if self.amount <= fee {
+            return Some(format!(
+                "amount {} does not cover the {} fee {}",
+                DisplayAmount(self.amount),
+                self.unit,
+                DisplayAmount(fee)
+            ));
+        }
+        if self.receiver == NNS_GOVERNANCE_ID.into() {
+            return Some("cannot transfer to the NNS governance".to_string());
+        }
+        None
+    }
 }
 
 #[derive(CandidType, Clone, Debug, PartialEq, Eq, Deserialize)]
@@ -247,7 +540,7 @@ pub struct PendingWithdrawal {
     pub beneficiary: Account,
 }
 
-#[derive(CandidType, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct DepositSuccess {
     pub block_index: Nat,
     pub transfer_id: u64,
@@ -261,6 +554,45 @@ pub struct WithdrawalSuccess {
     pub icp_amount: Option<ICP>,
 }
 
+#[derive(CandidType, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum InstantWithdrawalSuccess {
+    /// The ICP are paid out of the liquidity buffer by the pending transfer `transfer_id`.
+    Instant {
+        block_index: Nat,
+        transfer_id: u64,
+        icp_amount: ICP,
+        fee: ICP,
+    },
+    /// The liquidity buffer could not cover the withdrawal, it follows the regular flow.
+    Queued(WithdrawalSuccess),
+}
+
+/// A preview of `icp_to_nicp`, computed from the state at `timestamp`.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DepositQuote {
+    pub icp_amount: ICP,
+    pub nicp_amount: nICP,
+    /// The ICP ledger fee charged on top of `icp_amount` to pull the deposit.
+    pub ledger_fee: ICP,
+    pub airdrop_amount: WTN,
+    pub to_liquidity_buffer: bool,
+    pub exchange_rate: u64,
+    pub timestamp: u64,
+}
+
+/// A preview of `nicp_to_icp` and `nicp_to_icp_instant`, computed from the state at `timestamp`.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct WithdrawalQuote {
+    pub nicp_amount: nICP,
+    pub icp_amount: ICP,
+    /// The fee of an instant withdrawal, not set if the liquidity buffer cannot cover it.
+    pub instant_withdrawal_fee: Option<ICP>,
+    /// When the ICP of a regular withdrawal should be paid out, in seconds.
+    pub estimated_completion_timestamp_secs: u64,
+    pub exchange_rate: u64,
+    pub timestamp: u64,
+}
+
 #[derive(CandidType, Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub enum ConversionError {
     TransferFromError(TransferFromError),
@@ -268,6 +600,8 @@ pub enum ConversionError {
     AmountTooLow { minimum_amount_e8s: u64 },
     GuardError { guard_error: GuardError },
     GenericError { code: i32, message: String },
+    SlippageExceeded { expected: u64, actual: u64 },
+    ProtocolPaused { reason: String },
 }
 
 #[derive(CandidType, Serialize, Deserialize, Debug)]
@@ -283,6 +617,10 @@ pub enum CancelWithdrawalError {
     GetFullNeuronError { message: String },
     TooLate,
     UnknownTimeLeft,
+    AmountTooLow { minimum_amount_e8s: u64 },
+    AmountTooHigh { maximum_amount_e8s: u64 },
+    SplitNeuronError { message: String },
+    ProtocolPaused { reason: String },
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -310,12 +648,120 @@ pub fn compute_neuron_staking_subaccount_bytes(controller: Principal, nonce: u64
     hasher.finalize().into()
 }
 
+/// Subaccount of the water neuron canister where `principal` can transfer ICP
+/// before calling `notify_icp_deposit`, same derivation as the boomerang staking subaccount.
+pub fn derive_deposit_subaccount(principal: Principal) -> [u8; 32] {
+    const DOMAIN: &[u8] = b"STAKE-ICP";
+
+    let mut hasher = Sha256::new();
+    hasher.update(DOMAIN);
+    hasher.update(principal.as_slice());
+    hasher.finalize().into()
+}
+
+/// Stores the current exchange rate in the history kept in stable memory.
+pub fn snapshot_exchange_rate() {
+    record_exchange_rate(read_state(|s| s.get_exchange_rate(timestamp_nanos())));
+}
+
+pub fn setup_timer() {
+    schedule_now(TaskType::MaybeInitializeMainNeurons);
+    schedule_now(TaskType::ProcessLogic);
+    schedule_now(TaskType::SpawnNeurons);
+    schedule_now(TaskType::ProcessVoting);
+    schedule_now(TaskType::ProcessEarlyVoting);
+    schedule_now(TaskType::MaybeDistributeICP);
+    schedule_now(TaskType::MaybeDistributeRewards);
+    schedule_now(TaskType::ProcessRewardsTransfer);
+    schedule_now(TaskType::ProcessPendingTransfers);
+    schedule_now(TaskType::SnapshotExchangeRate);
+    schedule_now(TaskType::CheckpointState);
+    schedule_now(TaskType::IndexEvents);
+    schedule_now(TaskType::Reconcile);
+    schedule_now(TaskType::PruneRateLimits);
+    schedule_now(TaskType::ProcessPendingMerges);
+}
+
+/// Rebuilds the state from the latest checkpoint and the event log. If the events do not
+/// fit in the post_upgrade instruction budget, the replay continues in timers and the
+/// canister rejects state changing calls until it completes. Returns the number of events
+/// replayed in this message.
+pub fn recover_state(upgrade_arg: Option<UpgradeArg>) -> u64 {
+    let (state, next_event) = initial_replay_state();
+    replace_state(state);
+    start_event_replay(next_event, upgrade_arg);
+    continue_event_replay(POST_UPGRADE_REPLAY_INSTRUCTIONS)
+}
+
+/// Replays events until the instruction limit, returns the number of events replayed.
+fn continue_event_replay(instruction_limit: u64) -> u64 {
+    let start = get_replay_next_event().expect("bug: no event replay in progress");
+    let next_event = mutate_state(|s| {
+        replay_events_until(s, start, || {
+            ic_cdk::api::instruction_counter() >= instruction_limit
+        })
+    });
+    let event_count = total_event_count();
+    log!(
+        INFO,
+        "[continue_event_replay] replayed events {start} to {next_event} out of {event_count}, consumed {} instructions",
+        ic_cdk::api::instruction_counter()
+    );
+    if next_event < event_count {
+        set_replay_next_event(next_event);
+        schedule_now(TaskType::ReplayEvents);
+        return next_event - start;
+    }
+
+    if let Some(upgrade_arg) = finish_event_replay() {
+        mutate_state(|s| process_event(s, EventType::Upgrade(upgrade_arg)));
+    }
+    mutate_state(|s| {
+        if let Some(entry) = s.proposals.last_entry() {
+            s.last_nns_proposal_processed = entry.key().clone();
+        }
+    });
+    setup_timer();
+    next_event - start
+}
+
+/// Saves the state along with the number of events it covers, so that
+/// upgrades only replay the events recorded after the checkpoint.
+pub fn checkpoint_state() {
+    let start = ic_cdk::api::instruction_counter();
+    let event_count = total_event_count();
+    read_state(|s| record_state_checkpoint(s, event_count));
+    log!(
+        INFO,
+        "[checkpoint_state] checkpointed the state after {event_count} events, consumed {} instructions",
+        ic_cdk::api::instruction_counter() - start
+    );
+}
+
 pub fn timer() {
     if is_canister_stopping() {
         return;
     }
     if let Some(task) = tasks::pop_if_ready() {
         let task_type = task.task_type;
+        if is_replaying() && task_type != TaskType::ReplayEvents {
+            schedule_after(RETRY_DELAY, task_type);
+            return;
+        }
+        if matches!(
+            task_type,
+            TaskType::MaybeDistributeICP
+                | TaskType::MaybeDistributeRewards
+                | TaskType::ProcessRewardsTransfer
+        ) && read_state(|s| s.effective_protocol_mode() == ProtocolMode::FullyPaused)
+        {
+            log!(
+                DEBUG,
+                "[timer] the protocol is fully paused, postponing {task_type:?}"
+            );
+            schedule_after(ONE_HOUR, task_type);
+            return;
+        }
         match task.task_type {
             TaskType::MaybeInitializeMainNeurons => {
                 ic_cdk::futures::spawn(async move {
@@ -405,9 +851,18 @@ pub fn timer() {
                     if error_count > 0 {
                         log!(
                             INFO,
-                            "[ProcessPendingTransfers] Failed to process {error_count} transfers, rescheduling task."
+                            "[ProcessPendingTransfers] Failed to process {error_count} transfers."
                         );
-                        schedule_after(RETRY_DELAY, TaskType::ProcessPendingTransfers);
+                    }
+                    if let Some(delay) =
+                        read_state(|s| s.next_transfer_attempt_delay(timestamp_nanos()))
+                    {
+                        log!(
+                            INFO,
+                            "[ProcessPendingTransfers] Transfers still pending, rescheduling task in {}s.",
+                            delay.as_secs()
+                        );
+                        schedule_after(delay, TaskType::ProcessPendingTransfers);
                     }
                 });
             }
@@ -509,6 +964,69 @@ pub fn timer() {
                     }
                 });
             }
+            TaskType::SnapshotExchangeRate => {
+                snapshot_exchange_rate();
+                schedule_after(ONE_HOUR, TaskType::SnapshotExchangeRate);
+            }
+            TaskType::CheckpointState => {
+                checkpoint_state();
+                schedule_after(ONE_DAY, TaskType::CheckpointState);
+            }
+            TaskType::ReplayEvents => {
+                continue_event_replay(REPLAY_CHUNK_INSTRUCTIONS);
+            }
+            TaskType::IndexEvents => {
+                let start = total_event_count();
+                let done = index_events_until(|| {
+                    ic_cdk::api::instruction_counter() >= INDEX_CHUNK_INSTRUCTIONS
+                });
+                log!(
+                    INFO,
+                    "[IndexEvents] indexed {} out of {start} events",
+                    indexed_event_count()
+                );
+                if !done {
+                    schedule_now(TaskType::IndexEvents);
+                }
+            }
+            TaskType::Reconcile => {
+                ic_cdk::futures::spawn(async move {
+                    let _guard = match TaskGuard::new(task_type) {
+                        Ok(guard) => guard,
+                        Err(_) => return,
+                    };
+
+                    if reconciliation::reconcile().await {
+                        schedule_after(
+                            reconciliation::DRIFTING_RECONCILIATION_DELAY,
+                            TaskType::Reconcile,
+                        );
+                    } else {
+                        schedule_after(ONE_HOUR, TaskType::Reconcile);
+                    }
+                });
+            }
+            TaskType::PruneRateLimits => {
+                guards::prune_rate_limit_buckets();
+                schedule_after(ONE_HOUR, TaskType::PruneRateLimits);
+            }
+            TaskType::ProcessPendingMerges => {
+                ic_cdk::futures::spawn(async move {
+                    let _guard = match TaskGuard::new(task_type) {
+                        Ok(guard) => guard,
+                        Err(_) => return,
+                    };
+
+                    let error_count = conversion::process_pending_partial_merges().await;
+                    if error_count > 0 {
+                        log!(
+                            INFO,
+                            "[ProcessPendingMerges] Failed to merge {error_count} split neurons, rescheduling task."
+                        );
+                        schedule_after(ONE_MINUTE, TaskType::ProcessPendingMerges);
+                    }
+                });
+            }
         }
     }
 }
@@ -600,17 +1118,69 @@ async fn configure_sns_voting_neuron() {
     );
 }
 
+/// Returns true if the transfer may succeed when attempted again, the other errors
+/// will not go away by themselves and dead-letter the transfer. A transfer that is too
+/// old gets a new `created_at_time` before its next attempt if it was not executed.
+pub fn is_retryable(error: &TransferError) -> bool {
+    match error {
+        TransferError::TemporarilyUnavailable
+        | TransferError::CreatedInFuture { .. }
+        | TransferError::InsufficientFunds { .. }
+        | TransferError::TooOld
+        | TransferError::GenericError { .. } => true,
+        TransferError::BadFee { .. }
+        | TransferError::BadBurn { .. }
+        | TransferError::Duplicate { .. } => false,
+    }
+}
+
+/// Returns true if an attempt of the transfer may be on the ledger after it failed with
+/// `error`. The ledger only checks the balance after looking for duplicates, so insufficient
+/// funds prove that no earlier attempt went through, while a generic error may be a lost reply.
+pub fn is_maybe_executed(maybe_executed_before: bool, error: &TransferError) -> bool {
+    match error {
+        TransferError::InsufficientFunds { .. } => false,
+        TransferError::GenericError { .. } => true,
+        _ => maybe_executed_before,
+    }
+}
+
+/// Returns the event recording a failed attempt of the transfer.
+fn transfer_failure_event(
+    state: &State,
+    transfer_id: TransferId,
+    error: &TransferError,
+) -> EventType {
+    let attempts = state.transfer_attempts(transfer_id) + 1;
+    let maybe_executed = is_maybe_executed(state.is_transfer_maybe_executed(transfer_id), error);
+    if is_retryable(error) && attempts < MAX_TRANSFER_ATTEMPTS {
+        EventType::TransferFailed {
+            transfer_id,
+            error: error.to_string(),
+            maybe_executed,
+        }
+    } else {
+        EventType::TransferDeadLettered {
+            transfer_id,
+            error: error.to_string(),
+            maybe_executed,
+        }
+    }
+}
+
 async fn process_pending_transfer() -> u64 {
     let mut error_count = 0;
 
+    let now = timestamp_nanos();
     let pending_transfers: Vec<PendingTransfer> = read_state(|s| {
         s.pending_transfers
             .values()
+            .filter(|transfer| s.next_transfer_attempt_at(transfer.transfer_id) <= now)
             .cloned()
             .collect::<Vec<PendingTransfer>>()
     });
 
-    for transfer in pending_transfers {
+    for mut transfer in pending_transfers {
         if is_canister_stopping() {
             log!(
                 INFO,
@@ -618,23 +1188,54 @@ async fn process_pending_transfer() -> u64 {
             );
             return error_count;
         }
-        let (ledger_id, fee) = (transfer.unit.ledger_id(), transfer.unit.fee());
-        if transfer.amount <= fee || transfer.receiver == NNS_GOVERNANCE_ID.into() {
+        if let Some(reason) = transfer.rejection_reason() {
             log!(
                 INFO,
-                "[process_pending_transfer] Impossible transfer with id {}, skipping.",
+                "[process_pending_transfer] Rejecting impossible transfer with id {}: {reason}",
                 transfer.transfer_id
             );
             mutate_state(|s| {
                 process_event(
                     s,
-                    EventType::TransferExecuted {
+                    EventType::TransferRejected {
+                        transfer_id: transfer.transfer_id,
+                        reason,
+                    },
+                );
+            });
+            continue;
+        }
+        if transfer.is_out_of_dedup_window(timestamp_nanos()) {
+            if read_state(|s| s.is_transfer_maybe_executed(transfer.transfer_id)) {
+                log!(
+                    INFO,
+                    "[process_pending_transfer] transfer id: {} may have been executed and is out of the deduplication window, dead-lettering it",
+                    transfer.transfer_id
+                );
+                mutate_state(|s| {
+                    process_event(
+                        s,
+                        EventType::TransferDeadLettered {
+                            transfer_id: transfer.transfer_id,
+                            error: "out of the deduplication window, check the ledger".to_string(),
+                            maybe_executed: true,
+                        },
+                    );
+                });
+                error_count += 1;
+                continue;
+            }
+            transfer = mutate_state(|s| {
+                process_event(
+                    s,
+                    EventType::TransferRestamped {
                         transfer_id: transfer.transfer_id,
-                        block_index: None,
                     },
                 );
+                s.pending_transfers[&transfer.transfer_id].clone()
             });
         }
+        let (ledger_id, fee) = (transfer.unit.ledger_id(), transfer.unit.fee());
         match crate::management::transfer(
             transfer.receiver,
             transfer
@@ -646,9 +1247,28 @@ async fn process_pending_transfer() -> u64 {
             transfer.from_subaccount,
             ledger_id,
             transfer.memo,
+            Some(transfer.created_at_time),
         )
         .await
         {
+            Err(TransferError::Duplicate { duplicate_of }) => {
+                // An earlier attempt succeeded but its reply was lost.
+                log!(
+                    INFO,
+                    "[process_pending_transfer] transfer id: {} already executed in block {}",
+                    transfer.transfer_id,
+                    duplicate_of
+                );
+                mutate_state(|s| {
+                    process_event(
+                        s,
+                        EventType::TransferExecuted {
+                            transfer_id: transfer.transfer_id,
+                            block_index: Some(duplicate_of.0.try_into().unwrap()),
+                        },
+                    );
+                });
+            }
             Ok(block_index) => {
                 log!(
                     INFO,
@@ -669,12 +1289,21 @@ async fn process_pending_transfer() -> u64 {
                 });
             }
             Err(error) => {
+                let event = read_state(|s| transfer_failure_event(s, transfer.transfer_id, &error));
                 log!(
-                    DEBUG,
-                    "[process_pending_transfer] failed to transfer margin: {}, with error: {}",
-                    transfer.amount,
-                    error
+                    INFO,
+                    "[process_pending_transfer] failed to transfer {} {} to {}, transfer id: {}, with error: {}, {}",
+                    DisplayAmount(transfer.amount),
+                    transfer.unit,
+                    transfer.receiver,
+                    transfer.transfer_id,
+                    error,
+                    match event {
+                        EventType::TransferFailed { .. } => "retrying later",
+                        _ => "dead-lettering it",
+                    }
                 );
+                mutate_state(|s| process_event(s, event));
                 error_count += 1;
             }
         }
@@ -702,6 +1331,7 @@ async fn initialize_main_neuron(
             None,
             ICP_LEDGER_ID,
             None,
+            None,
         )
         .await
         .map_err(|e| format!("{}", e))?;
@@ -795,7 +1425,14 @@ pub async fn process_start_dissolving() {
         let result = start_dissolving(*neuron_id).await;
         if result.is_ok() {
             mutate_state(|s| {
-                if let Some(withdrawal_id) = s.neuron_id_to_withdrawal_id(*neuron_id) {
+                if s.is_batch_neuron(*neuron_id) {
+                    process_event(
+                        s,
+                        EventType::StartedToDissolveBatch {
+                            neuron_id: *neuron_id,
+                        },
+                    );
+                } else if let Some(withdrawal_id) = s.neuron_id_to_withdrawal_id(*neuron_id) {
                     process_event(s, EventType::StartedToDissolve { withdrawal_id });
                 } else {
                     panic!(
@@ -905,6 +1542,17 @@ pub async fn process_disburse() {
                                         disburse_response.transfer_block_height
                                     );
                                     mutate_state(|s| {
+                                        if s.is_batch_neuron(neuron_id) {
+                                            process_event(
+                                                s,
+                                                EventType::DisbursedBatchNeuron {
+                                                    neuron_id,
+                                                    transfer_block_height: disburse_response
+                                                        .transfer_block_height,
+                                                },
+                                            );
+                                            return;
+                                        }
                                         match s.neuron_id_to_withdrawal_id(neuron_id) {
                                             Some(withdrawal_id) => {
                                                 process_event(
@@ -1055,6 +1703,7 @@ async fn dispatch_icp<R: CanisterRuntime>(runtime: &R) {
                             },
                         );
                     });
+                    snapshot_exchange_rate();
                     schedule_now(TaskType::ProcessPendingTransfers);
                     schedule_now(TaskType::MaybeDistributeRewards);
                 }
@@ -1111,6 +1760,10 @@ pub async fn process_witdhrawals_splitting() {
         return;
     }
 
+    if read_state(|s| s.withdrawal_epoch_seconds > 0) {
+        return process_withdrawals_batch_splitting().await;
+    }
+
     let requests_ids = read_state(|s| s.withdrawal_to_split.clone());
 
     for withdrawal_id in requests_ids {
@@ -1176,13 +1829,56 @@ pub async fn process_witdhrawals_splitting() {
     }
 }
 
+async fn process_withdrawals_batch_splitting() {
+    let withdrawal_ids = match read_state(|s| s.get_withdrawal_batch_to_split(timestamp_nanos())) {
+        Some(withdrawal_ids) => withdrawal_ids,
+        None => return,
+    };
+    let icp_due = read_state(|s| s.compute_withdrawal_batch_icp_due(&withdrawal_ids));
+
+    log!(
+        INFO,
+        "[process_withdrawals_batch_splitting] Trying to split a neuron of {icp_due} ICP for withdrawal ids: {withdrawal_ids:?}",
+    );
+    match split_neuron(SIX_MONTHS_NEURON_NONCE, icp_due.0).await {
+        Ok(manage_neuron_response) => {
+            if let Some(CommandResponse::Split(spawn_response)) =
+                manage_neuron_response.command.clone()
+                && let Some(created_neuron_id) = spawn_response.created_neuron_id
+            {
+                mutate_state(|s| {
+                    process_event(
+                        s,
+                        EventType::SplitNeuronBatch {
+                            withdrawal_ids,
+                            neuron_id: NeuronId {
+                                id: created_neuron_id.id,
+                            },
+                        },
+                    );
+                });
+                return;
+            }
+            log!(
+                INFO,
+                "[process_withdrawals_batch_splitting] failed to split neuron: {:?}",
+                manage_neuron_response
+            );
+        }
+        Err(e) => log!(
+            INFO,
+            "[process_withdrawals_batch_splitting] failed to split neuron: {e}"
+        ),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::sns_governance::CanisterRuntime;
     use crate::state::test::default_state;
     use crate::state::{ICP_LEDGER_ID, SNS_GOVERNANCE_SUBACCOUNT, replace_state};
     use crate::{
-        Account, E8S, NeuronOrigin, PendingTransfer, Unit, dispatch_icp, read_state,
+        Account, E8S, NeuronOrigin, PendingTransfer, Unit, dispatch_icp, mutate_state, read_state,
         self_canister_id,
     };
     use async_trait::async_trait;
@@ -1255,6 +1951,8 @@ mod test {
 
         read_state(|s| {
             assert_eq!(s.pending_transfers.len(), 4);
+            // The transfers are stamped with the time of the event enqueuing them.
+            let created_at_time = |transfer_id| s.pending_transfers[&transfer_id].created_at_time;
             assert_eq!(
                 s.pending_transfers[&0],
                 PendingTransfer {
@@ -1264,6 +1962,7 @@ mod test {
                     amount: 180 * E8S,
                     receiver: s.get_6m_neuron_account(),
                     unit: Unit::ICP,
+                    created_at_time: created_at_time(0),
                 }
             );
             assert_eq!(
@@ -1278,6 +1977,7 @@ mod test {
                         subaccount: Some(SNS_GOVERNANCE_SUBACCOUNT)
                     },
                     unit: Unit::ICP,
+                    created_at_time: created_at_time(1),
                 }
             );
             assert_eq!(
@@ -1289,6 +1989,7 @@ mod test {
                     amount: 180 * E8S,
                     receiver: s.get_6m_neuron_account(),
                     unit: Unit::ICP,
+                    created_at_time: created_at_time(2),
                 }
             );
             assert_eq!(
@@ -1303,8 +2004,39 @@ mod test {
                         subaccount: Some(SNS_GOVERNANCE_SUBACCOUNT)
                     },
                     unit: Unit::ICP,
+                    created_at_time: created_at_time(3),
                 }
             );
         });
     }
+
+    #[tokio::test]
+    async fn should_not_dispatch_icp_of_dead_lettered_transfers() {
+        let mut runtime = MockCanisterRuntime::new();
+        replace_state(default_state());
+
+        runtime
+            .expect_balance_of()
+            .times(2)
+            .return_const(Ok(200 * E8S));
+        dispatch_icp(&runtime).await;
+
+        mutate_state(|s| {
+            s.record_transfer_executed(0, Some(1), 0);
+            s.record_transfer_executed(1, Some(2), 0);
+            s.record_transfer_dead_lettered(2, "InsufficientFunds".to_string(), false, 0);
+            s.record_transfer_executed(3, Some(3), 0);
+        });
+        let tracked_6m_stake = read_state(|s| s.tracked_6m_stake);
+
+        // The ICP of the dead-lettered transfer are still in the 6 months subaccount.
+        runtime.checkpoint();
+        runtime.expect_balance_of().never();
+        dispatch_icp(&runtime).await;
+
+        read_state(|s| {
+            assert!(s.pending_transfers.is_empty());
+            assert_eq!(s.tracked_6m_stake, tracked_6m_stake);
+        });
+    }
 }
@@ -8,25 +8,33 @@ use ic_nns_governance_api::{
     manage_neuron_response::MergeResponse,
 };
 use icrc_ledger_types::icrc1::account::Account;
-use water_neuron::conversion::{MINIMUM_DEPOSIT_AMOUNT, MINIMUM_WITHDRAWAL_AMOUNT};
 use water_neuron::dashboard::DisplayAmount;
 use water_neuron::guards::GuardPrincipal;
+use water_neuron::history::AccountHistory;
 use water_neuron::icrc21::{ConsentInfo, ConsentMessageRequest, Icrc21Error, StandardRecord};
 use water_neuron::logs::INFO;
 use water_neuron::management::register_vote;
 use water_neuron::nns_types::{NeuronId, ProposalId};
 use water_neuron::numeric::{ICP, WTN};
+use water_neuron::reconciliation::ReconciliationReport;
 use water_neuron::sns_distribution::compute_rewards;
-use water_neuron::state::audit::{process_event, replay_events};
-use water_neuron::state::event::{EventType, GetEventsArg, GetEventsResult};
+use water_neuron::state::audit::{get_replay_progress, is_replaying, process_event};
+#[cfg(feature = "self_check")]
+use water_neuron::state::audit::{recover_state_from_checkpoint, replay_events};
+use water_neuron::state::event::{
+    Event, EventType, GetEventsArg, GetEventsFilteredArg, GetEventsFilteredResult, GetEventsResult,
+};
 use water_neuron::state::{
-    State, TransferStatus, WithdrawalDetails, mutate_state, read_state, replace_state,
+    DeadLetterTransfer, ExchangeRateHistory, State, TransferStatus, WithdrawalDetails,
+    mutate_state, read_state, replace_state,
 };
-use water_neuron::storage::total_event_count;
+use water_neuron::storage::{MAX_EVENTS_PER_QUERY, MAX_RECONCILIATION_REPORTS, total_event_count};
 use water_neuron::tasks::{TaskType, schedule_now};
 use water_neuron::{
-    CancelWithdrawalError, CanisterInfo, ConversionArg, ConversionError, DepositSuccess, LiquidArg,
-    Unit, UpgradeArg, WithdrawalSuccess,
+    CancelWithdrawalError, CanisterInfo, ConversionArg, ConversionError, ConversionLimits,
+    DepositQuote, DepositSuccess, InstantWithdrawalSuccess, LiquidArg, NotifyIcpDepositArg,
+    ProtocolMode, RequeueTransferArg, RequeueWithdrawalPayoutArg, SetProtocolModeArg, Unit,
+    UpgradeArg, WithdrawalQuote, WithdrawalSuccess,
 };
 
 fn reject_anonymous_call() {
@@ -45,7 +53,7 @@ fn init(args: LiquidArg) {
         }
         LiquidArg::Upgrade(_) => ic_cdk::trap("expected init args, got upgrade"),
     }
-    setup_timer();
+    water_neuron::setup_timer();
 }
 
 #[post_upgrade]
@@ -63,49 +71,83 @@ pub fn post_upgrade(args: LiquidArg) {
                         "governance_fee_share_percent has to be between 0 and 100".to_string()
                     );
                 }
+                if let Some(share_percent) = args.liquidity_buffer_rewards_share_percent
+                    && share_percent > 100
+                {
+                    return Err(
+                        "liquidity_buffer_rewards_share_percent has to be between 0 and 100"
+                            .to_string(),
+                    );
+                }
+                for fee_bps in [
+                    args.instant_withdrawal_min_fee_bps,
+                    args.instant_withdrawal_max_fee_bps,
+                ]
+                .into_iter()
+                .flatten()
+                {
+                    if fee_bps > 10_000 {
+                        return Err(
+                            "instant withdrawal fees have to be between 0 and 10_000 bps"
+                                .to_string(),
+                        );
+                    }
+                }
+                if let (Some(min_fee_bps), Some(max_fee_bps)) = (
+                    args.instant_withdrawal_min_fee_bps,
+                    args.instant_withdrawal_max_fee_bps,
+                ) && min_fee_bps > max_fee_bps
+                {
+                    return Err(
+                        "instant_withdrawal_min_fee_bps has to be lower than instant_withdrawal_max_fee_bps"
+                            .to_string(),
+                    );
+                }
+                if let Some(conversion_limits) = args.conversion_limits {
+                    conversion_limits.validate()?;
+                }
+                for rate_limit in [args.principal_rate_limit, args.global_rate_limit]
+                    .into_iter()
+                    .flatten()
+                {
+                    rate_limit.validate()?;
+                }
                 Ok(())
             }
 
-            replace_state(replay_events());
-
-            if let Some(args) = upgrade_arg {
-                if let Err(e) = validate_upgrade_args(args.clone()) {
-                    ic_cdk::trap(&e);
-                }
-                mutate_state(|s| process_event(s, EventType::Upgrade(args)));
+            if let Some(args) = &upgrade_arg
+                && let Err(e) = validate_upgrade_args(args.clone())
+            {
+                ic_cdk::trap(&e);
             }
 
-            mutate_state(|s| {
-                if let Some(entry) = s.proposals.last_entry() {
-                    s.last_nns_proposal_processed = entry.key().clone();
-                }
-            });
+            let replayed_event_count = water_neuron::recover_state(upgrade_arg);
 
             let end = ic_cdk::api::instruction_counter();
 
             let event_count = total_event_count();
             let instructions_consumed = end - start;
 
-            log!(
-                INFO,
-                "[upgrade]: replaying {event_count} events consumed {instructions_consumed} instructions ({} instructions per event on average)",
-                instructions_consumed / event_count
-            );
-            setup_timer();
+            if is_replaying() {
+                log!(
+                    INFO,
+                    "[upgrade]: the event log of {event_count} events does not fit in {instructions_consumed} instructions, the replay continues in timers",
+                );
+            } else {
+                log!(
+                    INFO,
+                    "[upgrade]: replaying {replayed_event_count} out of {event_count} events consumed {instructions_consumed} instructions ({} instructions per event on average)",
+                    instructions_consumed / replayed_event_count.max(1)
+                );
+            }
         }
     }
 }
 
-fn setup_timer() {
-    schedule_now(TaskType::MaybeInitializeMainNeurons);
-    schedule_now(TaskType::ProcessLogic);
-    schedule_now(TaskType::SpawnNeurons);
-    schedule_now(TaskType::ProcessVoting);
-    schedule_now(TaskType::ProcessEarlyVoting);
-    schedule_now(TaskType::MaybeDistributeICP);
-    schedule_now(TaskType::MaybeDistributeRewards);
-    schedule_now(TaskType::ProcessRewardsTransfer);
-    schedule_now(TaskType::ProcessPendingTransfers);
+fn reject_while_replaying() {
+    if is_replaying() {
+        ic_cdk::trap("call rejected: the canister is replaying its event log, retry later");
+    }
 }
 
 #[cfg(feature = "self_check")]
@@ -118,11 +160,20 @@ fn ok_or_die(result: Result<(), String>) {
 
 #[cfg(feature = "self_check")]
 fn check_invariants() -> Result<(), String> {
+    if is_replaying() {
+        return Ok(());
+    }
     read_state(|s| {
         let recovered_state = replay_events();
 
         recovered_state.is_equivalent_to(s)?;
 
+        if let Some(checkpoint_state) = recover_state_from_checkpoint() {
+            checkpoint_state
+                .is_equivalent_to(&recovered_state)
+                .map_err(|e| format!("checkpoint and tail differ from a full replay: {e}"))?;
+        }
+
         Ok(())
     })
 }
@@ -149,7 +200,6 @@ fn timer() {
 
 #[query]
 fn get_events(args: GetEventsArg) -> GetEventsResult {
-    const MAX_EVENTS_PER_QUERY: u64 = 2_000;
     let events = water_neuron::storage::with_event_iter(|it| {
         it.skip(args.start as usize)
             .take(args.length.min(MAX_EVENTS_PER_QUERY) as usize)
@@ -161,6 +211,33 @@ fn get_events(args: GetEventsArg) -> GetEventsResult {
     }
 }
 
+#[query]
+fn get_events_filtered(arg: GetEventsFilteredArg) -> GetEventsFilteredResult {
+    water_neuron::storage::get_events_filtered(&arg, arg.length.min(MAX_EVENTS_PER_QUERY) as usize)
+}
+
+#[query]
+fn get_exchange_rate_history(
+    from_ts: u64,
+    to_ts: u64,
+    granularity_nanos: u64,
+) -> ExchangeRateHistory {
+    const MAX_EXCHANGE_RATES_PER_QUERY: usize = 2_000;
+    water_neuron::storage::get_exchange_rate_history(
+        from_ts,
+        to_ts,
+        granularity_nanos,
+        MAX_EXCHANGE_RATES_PER_QUERY,
+    )
+}
+
+#[query]
+fn get_reconciliation_reports(length: u64) -> Vec<ReconciliationReport> {
+    water_neuron::storage::get_reconciliation_reports(
+        length.min(MAX_RECONCILIATION_REPORTS) as usize
+    )
+}
+
 #[query]
 fn get_airdrop_allocation(p: Option<Principal>) -> WTN {
     read_state(|s| {
@@ -203,6 +280,7 @@ async fn schedule_task(task: TaskType) {
         ic_cdk::api::msg_caller(),
         Principal::from_text("bo5bf-eaaaa-aaaam-abtza-cai").unwrap()
     );
+    reject_while_replaying();
 
     schedule_now(task);
 }
@@ -213,6 +291,7 @@ async fn process_logic() {
         ic_cdk::api::msg_caller(),
         Principal::from_text("bo5bf-eaaaa-aaaam-abtza-cai").unwrap()
     );
+    reject_while_replaying();
 
     water_neuron::refresh_stakes().await;
     water_neuron::process_witdhrawals_splitting().await;
@@ -348,6 +427,134 @@ async fn set_neuron_visibility_validate(
     ))
 }
 
+#[update(hidden = true)]
+fn set_conversion_limits(conversion_limits: ConversionLimits) -> Result<(), String> {
+    assert_eq!(
+        ic_cdk::api::msg_caller(),
+        read_state(|s| s.wtn_governance_id)
+    );
+    reject_while_replaying();
+
+    conversion_limits.validate()?;
+    mutate_state(|s| process_event(s, EventType::ConversionLimitsUpdated(conversion_limits)));
+    Ok(())
+}
+
+#[update(hidden = true)]
+fn set_conversion_limits_validate(conversion_limits: ConversionLimits) -> Result<String, String> {
+    assert_eq!(
+        ic_cdk::api::msg_caller(),
+        read_state(|s| s.wtn_governance_id)
+    );
+
+    conversion_limits.validate()?;
+    Ok(format!("Set conversion limits to {conversion_limits:?}"))
+}
+
+#[update(hidden = true)]
+fn set_protocol_mode(arg: SetProtocolModeArg) -> Result<(), String> {
+    assert_eq!(
+        ic_cdk::api::msg_caller(),
+        read_state(|s| s.wtn_governance_id)
+    );
+    reject_while_replaying();
+
+    arg.validate()?;
+    log!(INFO, "[set_protocol_mode] {arg:?}");
+    mutate_state(|s| {
+        process_event(
+            s,
+            EventType::ProtocolModeChanged {
+                mode: arg.mode,
+                reason: arg.reason,
+            },
+        )
+    });
+    Ok(())
+}
+
+#[update(hidden = true)]
+fn set_protocol_mode_validate(arg: SetProtocolModeArg) -> Result<String, String> {
+    assert_eq!(
+        ic_cdk::api::msg_caller(),
+        read_state(|s| s.wtn_governance_id)
+    );
+
+    arg.validate()?;
+    Ok(format!("Set the protocol mode to {arg:?}"))
+}
+
+#[update(hidden = true)]
+fn requeue_transfer(arg: RequeueTransferArg) -> Result<(), String> {
+    assert_eq!(
+        ic_cdk::api::msg_caller(),
+        read_state(|s| s.wtn_governance_id)
+    );
+    reject_while_replaying();
+
+    read_state(|s| arg.validate(s, water_neuron::timestamp_nanos()))?;
+    log!(INFO, "[requeue_transfer] {arg:?}");
+    mutate_state(|s| {
+        process_event(
+            s,
+            EventType::TransferRequeued {
+                transfer_id: arg.transfer_id,
+                receiver: arg.receiver,
+                verified_not_executed: arg.verified_not_executed,
+            },
+        )
+    });
+    schedule_now(TaskType::ProcessPendingTransfers);
+    Ok(())
+}
+
+#[update(hidden = true)]
+fn requeue_transfer_validate(arg: RequeueTransferArg) -> Result<String, String> {
+    assert_eq!(
+        ic_cdk::api::msg_caller(),
+        read_state(|s| s.wtn_governance_id)
+    );
+
+    read_state(|s| arg.validate(s, water_neuron::timestamp_nanos()))?;
+    Ok(format!("Requeue the dead-lettered transfer {arg:?}"))
+}
+
+#[update(hidden = true)]
+fn requeue_withdrawal_payout(arg: RequeueWithdrawalPayoutArg) -> Result<(), String> {
+    assert_eq!(
+        ic_cdk::api::msg_caller(),
+        read_state(|s| s.wtn_governance_id)
+    );
+    reject_while_replaying();
+
+    read_state(|s| arg.validate(s))?;
+    log!(INFO, "[requeue_withdrawal_payout] {arg:?}");
+    mutate_state(|s| {
+        process_event(
+            s,
+            EventType::WithdrawalPayoutRequeued {
+                withdrawal_id: arg.withdrawal_id,
+                receiver: arg.receiver,
+            },
+        )
+    });
+    schedule_now(TaskType::ProcessPendingTransfers);
+    Ok(())
+}
+
+#[update(hidden = true)]
+fn requeue_withdrawal_payout_validate(arg: RequeueWithdrawalPayoutArg) -> Result<String, String> {
+    assert_eq!(
+        ic_cdk::api::msg_caller(),
+        read_state(|s| s.wtn_governance_id)
+    );
+
+    read_state(|s| arg.validate(s))?;
+    Ok(format!(
+        "Pay the withdrawal out after its payout was rejected {arg:?}"
+    ))
+}
+
 #[update(hidden = true)]
 async fn start_dissolving_main_neuron(neuron_nonce: u64) -> Result<ManageNeuronResponse, String> {
     assert_eq!(
@@ -485,6 +692,7 @@ async fn approve_proposal_validate(id: u64) -> Result<String, String> {
 #[update]
 async fn claim_airdrop() -> Result<u64, ConversionError> {
     reject_anonymous_call();
+    reject_while_replaying();
 
     let rewards = read_state(|s| compute_rewards(s.total_icp_deposited, ICP::ONE));
     if rewards != WTN::ZERO {
@@ -495,6 +703,13 @@ async fn claim_airdrop() -> Result<u64, ConversionError> {
         ic_cdk::trap("21M ICP must be staked to unlock the airdrop");
     }
 
+    if let Some(reason) = read_state(|s| {
+        (s.effective_protocol_mode() == ProtocolMode::FullyPaused)
+            .then(|| s.protocol_pause_reason())
+    }) {
+        return Err(ConversionError::ProtocolPaused { reason });
+    }
+
     let caller = ic_cdk::api::msg_caller();
     let _guard_principal = GuardPrincipal::new(caller)
         .map_err(|guard_error| ConversionError::GuardError { guard_error })?;
@@ -520,6 +735,7 @@ async fn claim_airdrop() -> Result<u64, ConversionError> {
         None,
         wtn_ledger,
         None,
+        None,
     )
     .await
     {
@@ -559,10 +775,30 @@ fn get_info() -> CanisterInfo {
         stakers_count: s.account_to_deposits.keys().len(),
         total_icp_deposited: s.total_icp_deposited,
         nicp_supply: s.total_circulating_nicp,
-        minimum_deposit_amount: MINIMUM_DEPOSIT_AMOUNT,
-        minimum_withdraw_amount: MINIMUM_WITHDRAWAL_AMOUNT,
+        minimum_deposit_amount: s.minimum_deposit_amount,
+        minimum_withdraw_amount: s.minimum_withdrawal_amount,
         nicp_share_percent: s.governance_fee_share_percent,
         governance_share_percent: s.compute_governance_8y_share_percent(),
+        liquidity_buffer: s.liquidity_buffer,
+        liquidity_buffer_target: s.liquidity_buffer_target,
+        replay_progress: get_replay_progress(),
+        protocol_mode: s.effective_protocol_mode(),
+        protocol_mode_reason: s.effective_protocol_mode_reason(),
+        estimated_apy: s.compute_nicp_apy(),
+        realized_apy: water_neuron::compute_realized_apy(water_neuron::timestamp_nanos()),
+    })
+}
+
+#[query]
+fn get_account_history(account: Account, cursor: Option<u64>, limit: u64) -> AccountHistory {
+    const MAX_ENTRIES_PER_QUERY: u64 = 100;
+    read_state(|s| {
+        water_neuron::history::get_account_history(
+            s,
+            account,
+            cursor,
+            limit.min(MAX_ENTRIES_PER_QUERY) as usize,
+        )
     })
 }
 
@@ -605,24 +841,75 @@ fn get_transfer_statuses(ids: Vec<u64>) -> Vec<TransferStatus> {
     read_state(|s| ids.iter().map(|id| s.get_transfer_status(*id)).collect())
 }
 
+#[query]
+fn get_dead_letter_transfers() -> Vec<DeadLetterTransfer> {
+    read_state(|s| s.dead_letter_transfers.values().cloned().collect())
+}
+
 #[update]
 async fn nicp_to_icp(arg: ConversionArg) -> Result<WithdrawalSuccess, ConversionError> {
     reject_anonymous_call();
+    reject_while_replaying();
     check_postcondition(water_neuron::conversion::nicp_to_icp(arg).await)
 }
 
+#[update]
+async fn nicp_to_icp_instant(
+    arg: ConversionArg,
+) -> Result<InstantWithdrawalSuccess, ConversionError> {
+    reject_anonymous_call();
+    reject_while_replaying();
+    check_postcondition(water_neuron::conversion::nicp_to_icp_instant(arg).await)
+}
+
+#[query]
+fn quote_icp_to_nicp(amount_e8s: u64) -> Result<DepositQuote, ConversionError> {
+    water_neuron::conversion::quote_icp_to_nicp(amount_e8s)
+}
+
+#[query]
+fn quote_nicp_to_icp(amount_e8s: u64) -> Result<WithdrawalQuote, ConversionError> {
+    water_neuron::conversion::quote_nicp_to_icp(amount_e8s)
+}
+
 #[update]
 async fn icp_to_nicp(arg: ConversionArg) -> Result<DepositSuccess, ConversionError> {
     reject_anonymous_call();
+    reject_while_replaying();
     check_postcondition(water_neuron::conversion::icp_to_nicp(arg).await)
 }
 
+#[query]
+fn get_deposit_account(principal: Principal) -> Account {
+    read_state(|s| s.get_deposit_account(principal))
+}
+
+#[update]
+async fn notify_icp_deposit(arg: NotifyIcpDepositArg) -> Result<DepositSuccess, ConversionError> {
+    reject_anonymous_call();
+    reject_while_replaying();
+    check_postcondition(water_neuron::conversion::notify_icp_deposit(arg).await)
+}
+
 #[update]
 async fn cancel_withdrawal(neuron_id: NeuronId) -> Result<MergeResponse, CancelWithdrawalError> {
     reject_anonymous_call();
+    reject_while_replaying();
     check_postcondition(water_neuron::conversion::cancel_withdrawal(neuron_id).await)
 }
 
+#[update]
+async fn cancel_withdrawal_partial(
+    neuron_id: NeuronId,
+    icp_amount_e8s: u64,
+) -> Result<MergeResponse, CancelWithdrawalError> {
+    reject_anonymous_call();
+    reject_while_replaying();
+    check_postcondition(
+        water_neuron::conversion::cancel_withdrawal_partial(neuron_id, icp_amount_e8s).await,
+    )
+}
+
 #[query]
 fn icrc10_supported_standards() -> Vec<StandardRecord> {
     water_neuron::icrc21::icrc10_supported_standards()
@@ -680,6 +967,11 @@ fn http_request(req: HttpRequest) -> HttpResponse {
                     s.tracked_6m_stake.0 as f64,
                     "6 months neuron tracked stake",
                 )?;
+                w.encode_gauge(
+                    "liquidity_buffer",
+                    s.liquidity_buffer.0 as f64,
+                    "ICP available for instant withdrawals.",
+                )?;
                 w.encode_gauge(
                     "neuron_8y_stake",
                     s.main_neuron_8y_stake.0 as f64,
@@ -705,7 +997,22 @@ fn http_request(req: HttpRequest) -> HttpResponse {
                     s.compute_daily_fees() as f64,
                     "The maturity generated by both neurons.",
                 )?;
-                w.encode_gauge("apy", s.compute_nicp_apy(), "The APY of the protocol.")?;
+                w.encode_gauge(
+                    "apy",
+                    s.compute_nicp_apy(),
+                    "The estimated APY of the protocol, from the expected neuron rewards.",
+                )?;
+                for realized_apy in
+                    water_neuron::compute_realized_apy(water_neuron::timestamp_nanos())
+                {
+                    if let Some(apy) = realized_apy.apy {
+                        w.encode_gauge(
+                            &format!("realized_apy_{}d", realized_apy.window_days),
+                            apy,
+                            "The APY earned by nICP holders over the window.",
+                        )?;
+                    }
+                }
                 w.encode_gauge(
                     "next_transfer_id",
                     s.transfer_id as f64,
@@ -753,6 +1060,72 @@ fn http_request(req: HttpRequest) -> HttpResponse {
                     s.compute_governance_8y_share_percent() as f64,
                     "Share of the 8 year neuron that goes to the DAO",
                 )?;
+                w.encode_gauge(
+                    "protocol_deposits_paused",
+                    !s.effective_protocol_mode().deposits_allowed() as u8 as f64,
+                    "Whether deposits are paused.",
+                )?;
+                w.encode_gauge(
+                    "protocol_withdrawals_paused",
+                    !s.effective_protocol_mode().withdrawals_allowed() as u8 as f64,
+                    "Whether withdrawals are paused.",
+                )?;
+                w.encode_gauge(
+                    "protocol_fully_paused",
+                    (s.effective_protocol_mode() == ProtocolMode::FullyPaused) as u8 as f64,
+                    "Whether the protocol is fully paused.",
+                )?;
+                w.encode_gauge(
+                    "dead_letter_transfers",
+                    s.dead_letter_transfers.len() as f64,
+                    "Count of transfers dead-lettered until requeued by governance.",
+                )?;
+                w.encode_gauge(
+                    "rejected_transfers",
+                    s.rejected_transfers.len() as f64,
+                    "Count of transfers rejected without calling the ledger.",
+                )?;
+                w.encode_gauge(
+                    "pending_partial_merges",
+                    s.pending_partial_merges.len() as f64,
+                    "Count of neurons split on a partial cancellation and not merged yet.",
+                )?;
+                if let Some(report) = water_neuron::storage::get_reconciliation_reports(1).pop() {
+                    if let Some(stake_drift_e8s) = report.stake_drift_e8s {
+                        w.encode_gauge(
+                            "reconciliation_6m_stake_drift",
+                            stake_drift_e8s as f64,
+                            "6 months neuron fetched stake minus the expected stake.",
+                        )?;
+                    }
+                    if let Some(nicp_supply_drift_e8s) = report.nicp_supply_drift_e8s {
+                        w.encode_gauge(
+                            "reconciliation_nicp_supply_drift",
+                            nicp_supply_drift_e8s as f64,
+                            "nICP ledger supply minus the expected supply.",
+                        )?;
+                    }
+                    w.encode_gauge(
+                        "reconciliation_balance_shortfall",
+                        report.balance_shortfall_e8s as f64,
+                        "ICP missing on the subaccounts to cover the pending transfers.",
+                    )?;
+                    w.encode_gauge(
+                        "reconciliation_max_icp_drift",
+                        report.max_icp_drift_e8s as f64,
+                        "Largest ICP drift of the latest reconciliation.",
+                    )?;
+                    w.encode_gauge(
+                        "reconciliation_max_nicp_drift",
+                        report.max_nicp_drift_e8s as f64,
+                        "nICP drift of the latest reconciliation.",
+                    )?;
+                    w.encode_gauge(
+                        "reconciliation_timestamp_seconds",
+                        (report.timestamp / water_neuron::SEC_NANOS) as f64,
+                        "Time of the latest reconciliation.",
+                    )?;
+                }
                 if let Some(latest_distribution_icp_per_vp) = s.latest_distribution_icp_per_vp {
                     w.encode_gauge(
                         "latest_distribution_icp_per_vp",
@@ -780,6 +1153,41 @@ fn http_request(req: HttpRequest) -> HttpResponse {
                 .build();
             }
         }
+    } else if req.path() == "/events" {
+        use std::str::FromStr;
+        use water_neuron::event_export::{ExportFormat, export_events};
+
+        fn parse_param<T: FromStr>(req: &HttpRequest, name: &str, default: T) -> Result<T, String> {
+            match req.raw_query_param(name) {
+                Some(arg) => {
+                    T::from_str(arg).map_err(|_| format!("failed to parse the '{name}' parameter"))
+                }
+                None => Ok(default),
+            }
+        }
+
+        let params = parse_param(&req, "start", 0_u64).and_then(|start| {
+            let length = parse_param(&req, "length", MAX_EVENTS_PER_QUERY)?;
+            let format = parse_param(&req, "format", ExportFormat::Json)?;
+            Ok((start, length, format))
+        });
+        let (start, length, format) = match params {
+            Ok(params) => params,
+            Err(error) => {
+                return HttpResponseBuilder::bad_request()
+                    .with_body_and_content_length(error)
+                    .build();
+            }
+        };
+        let events: Vec<Event> = water_neuron::storage::with_event_iter(|it| {
+            it.skip(start as usize)
+                .take(length.min(MAX_EVENTS_PER_QUERY) as usize)
+                .collect()
+        });
+        return HttpResponseBuilder::ok()
+            .header("Content-Type", format.content_type())
+            .with_body_and_content_length(export_events(start, &events, format))
+            .build();
     } else if req.path() == "/api/metadata" {
         use serde_json;
 
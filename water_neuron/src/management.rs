@@ -1,6 +1,6 @@
 use crate::compute_neuron_staking_subaccount_bytes;
 use crate::nns_types::{NeuronId, ProposalId};
-use crate::state::{NNS_GOVERNANCE_ID, SIX_MONTHS_NEURON_NONCE, read_state};
+use crate::state::{ICP_LEDGER_ID, NNS_GOVERNANCE_ID, SIX_MONTHS_NEURON_NONCE, read_state};
 use candid::{Nat, Principal};
 use ic_nns_governance_api::{
     GovernanceError, ListNeurons, ListNeuronsResponse, ListProposalInfoRequest,
@@ -19,6 +19,7 @@ use ic_sns_governance_api::pb::v1::{
     ManageNeuronResponse as ManageSnsNeuronResponse, manage_neuron::Command as SnsCommand,
 };
 use icp_ledger::protobuf::AccountIdentifier;
+use icp_ledger::{CandidBlock, GetBlocksArgs, GetBlocksResult, QueryBlocksResponse};
 use icrc_ledger_client_cdk::{CdkRuntime, ICRC1Client};
 use icrc_ledger_types::icrc1::account::Account;
 use icrc_ledger_types::icrc1::transfer::{TransferArg, TransferError};
@@ -40,6 +41,7 @@ pub async fn transfer(
     from_subaccount: Option<[u8; 32]>,
     ledger_canister_id: Principal,
     memo: Option<u64>,
+    created_at_time: Option<u64>,
 ) -> Result<u64, TransferError> {
     let client = ICRC1Client {
         runtime: CdkRuntime,
@@ -50,7 +52,7 @@ pub async fn transfer(
             from_subaccount,
             to: to.into(),
             fee,
-            created_at_time: None,
+            created_at_time,
             memo: memo.map(|m| m.into()),
             amount,
         })
@@ -79,6 +81,57 @@ pub async fn balance_of(
         .unwrap())
 }
 
+/// Fetches the ICP ledger block at `block_index`, from the archive holding it once it was
+/// archived. Returns `None` if the ledger has no such block yet.
+pub async fn get_icp_block(block_index: u64) -> Result<Option<CandidBlock>, String> {
+    let args = GetBlocksArgs {
+        start: block_index,
+        length: 1,
+    };
+    let response: QueryBlocksResponse =
+        ic_cdk::call::Call::unbounded_wait(ICP_LEDGER_ID, "query_blocks")
+            .with_arg(args.clone())
+            .await
+            .map_err(|e| format!("Error while calling the ICP ledger: {e}"))?
+            .candid()
+            .map_err(|e| format!("Error while decoding the ICP ledger blocks: {e}"))?;
+    if response.first_block_index == block_index
+        && let Some(block) = response.blocks.into_iter().next()
+    {
+        return Ok(Some(block));
+    }
+    let Some(archived) = response.archived_blocks.into_iter().find(|range| {
+        range.start <= block_index && block_index < range.start.saturating_add(range.length)
+    }) else {
+        return Ok(None);
+    };
+    let result: GetBlocksResult = ic_cdk::call::Call::unbounded_wait(
+        archived.callback.canister_id,
+        &archived.callback.method,
+    )
+    .with_arg(args)
+    .await
+    .map_err(|e| format!("Error while calling the ICP archive: {e}"))?
+    .candid()
+    .map_err(|e| format!("Error while decoding the ICP archive blocks: {e}"))?;
+    let blocks = result
+        .map_err(|e| format!("The ICP archive failed to return block {block_index}: {e:?}"))?
+        .blocks;
+    Ok(blocks.into_iter().next())
+}
+
+pub async fn total_supply(ledger_canister_id: Principal) -> Result<u64, String> {
+    let supply: Nat = ic_cdk::call::Call::unbounded_wait(ledger_canister_id, "icrc1_total_supply")
+        .await
+        .map_err(|e| format!("Error while calling the ledger: {e}"))?
+        .candid()
+        .map_err(|e| format!("Error while decoding the total supply: {e}"))?;
+    supply
+        .0
+        .try_into()
+        .map_err(|_| "the total supply does not fit in a u64".to_string())
+}
+
 /// Call the list_neurons from the management canister.
 /// This canister needs to be a hot key of the neurons in order to have full neuron access.
 /// At most this endpoint can return 9_400 neurons following estimations.
@@ -297,6 +350,21 @@ pub async fn split_neuron(
     .await
 }
 
+pub async fn split_neuron_by_id(
+    neuron_id: NeuronId,
+    amount_e8s: u64,
+) -> Result<ManageNeuronResponse, String> {
+    assert!(read_state(|s| s.is_neuron_allowed_to_dissolve(neuron_id)));
+    manage_neuron(
+        ManageNeuronProposalCommand::Split(Split {
+            amount_e8s,
+            memo: None,
+        }),
+        NeuronNonceOrId::Id(neuron_id),
+    )
+    .await
+}
+
 pub async fn stop_dissolvement(neuron_id: NeuronId) -> Result<ManageNeuronResponse, String> {
     assert!(read_state(|s| s.is_neuron_allowed_to_dissolve(neuron_id)));
     manage_neuron(
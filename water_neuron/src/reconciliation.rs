@@ -0,0 +1,536 @@
+use crate::logs::INFO;
+use crate::management::{balance_of, refresh_neuron, total_supply};
+use crate::numeric::ICP;
+use crate::state::audit::process_event;
+use crate::state::event::EventType;
+use crate::state::{SIX_MONTHS_NEURON_NONCE, State, mutate_state, read_state};
+use crate::storage::{get_reconciliation_reports, record_reconciliation_report};
+use crate::{Unit, fetch_neuron_stake, self_canister_id, timestamp_nanos};
+use candid::{CandidType, Principal};
+use ic_canister_log::log;
+use icrc_ledger_types::icrc1::account::Account;
+use minicbor::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// The number of consecutive reports drifting beyond the thresholds that pause the protocol.
+pub const DRIFTING_REPORTS_BEFORE_PAUSE: usize = 2;
+
+/// The delay before the next reconciliation once a report drifts beyond the thresholds.
+pub const DRIFTING_RECONCILIATION_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// The ledger balance of a subaccount sending pending or dead-lettered transfers.
+#[derive(CandidType, Serialize, Deserialize, Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct SubaccountBalance {
+    #[n(0)]
+    pub unit: Unit,
+    #[cbor(n(1), with = "minicbor::bytes")]
+    pub subaccount: Option<[u8; 32]>,
+    /// The amount of the unsettled transfers from this subaccount, fees included.
+    #[n(2)]
+    pub pending_e8s: u64,
+    /// Not set if the ledger could not be queried.
+    #[n(3)]
+    pub balance_e8s: Option<u64>,
+}
+
+impl SubaccountBalance {
+    pub fn shortfall_e8s(&self) -> u64 {
+        self.balance_e8s
+            .map(|balance| self.pending_e8s.saturating_sub(balance))
+            .unwrap_or_default()
+    }
+}
+
+/// The tracked accounting compared against the neuron and the ledgers at `timestamp`.
+#[derive(CandidType, Serialize, Deserialize, Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct ReconciliationReport {
+    #[n(0)]
+    pub timestamp: u64,
+    #[n(1)]
+    pub tracked_6m_stake_e8s: u64,
+    /// The tracked stake plus the ICP due of the withdrawals not split yet,
+    /// minus the ICP still to be transferred to the 6 months neuron.
+    #[n(11)]
+    pub expected_6m_stake_e8s: u64,
+    /// Not set if the 6 months neuron could not be fetched.
+    #[n(2)]
+    pub neuron_6m_stake_e8s: Option<u64>,
+    /// The nICP in circulation minus the nICP still to be minted by unsettled transfers.
+    #[n(3)]
+    pub expected_nicp_supply_e8s: u64,
+    /// Not set if the nICP ledger could not be queried.
+    #[n(4)]
+    pub nicp_ledger_supply_e8s: Option<u64>,
+    #[n(5)]
+    pub balances: Vec<SubaccountBalance>,
+    /// The neuron stake minus the expected stake.
+    #[n(6)]
+    pub stake_drift_e8s: Option<i64>,
+    /// The ledger supply minus the expected supply.
+    #[n(7)]
+    pub nicp_supply_drift_e8s: Option<i64>,
+    /// The ICP missing for the pending ICP transfers, summed over the subaccounts.
+    #[n(8)]
+    pub balance_shortfall_e8s: u64,
+    /// The largest of the ICP drifts above, compared against `max_reconciliation_drift_e8s`.
+    #[n(9)]
+    pub max_icp_drift_e8s: u64,
+    /// The nICP supply drift, compared against `max_reconciliation_nicp_drift_e8s`.
+    #[n(12)]
+    pub max_nicp_drift_e8s: u64,
+    /// False if one of the neuron or the ledgers could not be queried.
+    #[n(10)]
+    pub complete: bool,
+}
+
+fn drift(actual: Option<u64>, expected: u64) -> Option<i64> {
+    actual.map(|actual| (actual as i128 - expected as i128) as i64)
+}
+
+/// The accounting expected from the neuron and the ledgers, read from the state before
+/// querying them as the state changes while awaiting.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExpectedAccounting {
+    pub tracked_6m_stake_e8s: u64,
+    pub expected_6m_stake_e8s: u64,
+    pub expected_nicp_supply_e8s: u64,
+    /// The balances to fetch, with the amount of the pending transfers.
+    pub balances: Vec<SubaccountBalance>,
+}
+
+impl ExpectedAccounting {
+    pub fn new(state: &State) -> Self {
+        Self {
+            tracked_6m_stake_e8s: state.tracked_6m_stake.0,
+            expected_6m_stake_e8s: expected_6m_stake_e8s(state),
+            expected_nicp_supply_e8s: expected_nicp_supply_e8s(state),
+            balances: pending_transfers_by_subaccount(state),
+        }
+    }
+}
+
+impl ReconciliationReport {
+    pub fn new(
+        expected: ExpectedAccounting,
+        timestamp: u64,
+        neuron_6m_stake_e8s: Option<u64>,
+        nicp_ledger_supply_e8s: Option<u64>,
+    ) -> Self {
+        let stake_drift_e8s = drift(neuron_6m_stake_e8s, expected.expected_6m_stake_e8s);
+        let nicp_supply_drift_e8s =
+            drift(nicp_ledger_supply_e8s, expected.expected_nicp_supply_e8s);
+        let balance_shortfall_e8s = expected
+            .balances
+            .iter()
+            .filter(|balance| balance.unit == Unit::ICP)
+            .map(|balance| balance.shortfall_e8s())
+            .sum();
+        let max_icp_drift_e8s = stake_drift_e8s
+            .map(i64::unsigned_abs)
+            .unwrap_or_default()
+            .max(balance_shortfall_e8s);
+        let max_nicp_drift_e8s = nicp_supply_drift_e8s
+            .map(i64::unsigned_abs)
+            .unwrap_or_default();
+        let complete = neuron_6m_stake_e8s.is_some()
+            && nicp_ledger_supply_e8s.is_some()
+            && expected
+                .balances
+                .iter()
+                .all(|balance| balance.balance_e8s.is_some());
+        Self {
+            timestamp,
+            tracked_6m_stake_e8s: expected.tracked_6m_stake_e8s,
+            expected_6m_stake_e8s: expected.expected_6m_stake_e8s,
+            neuron_6m_stake_e8s,
+            expected_nicp_supply_e8s: expected.expected_nicp_supply_e8s,
+            nicp_ledger_supply_e8s,
+            balances: expected.balances,
+            stake_drift_e8s,
+            nicp_supply_drift_e8s,
+            balance_shortfall_e8s,
+            max_icp_drift_e8s,
+            max_nicp_drift_e8s,
+            complete,
+        }
+    }
+
+    /// Returns why the report drifts beyond the thresholds set by governance, if it does.
+    pub fn excessive_drift(&self, state: &State) -> Option<String> {
+        if let Some(max_drift_e8s) = state.max_reconciliation_drift_e8s
+            && self.max_icp_drift_e8s > max_drift_e8s
+        {
+            return Some(format!(
+                "reconciliation ICP drift of {} e8s exceeds {max_drift_e8s} e8s",
+                self.max_icp_drift_e8s
+            ));
+        }
+        if let Some(max_drift_e8s) = state.max_reconciliation_nicp_drift_e8s
+            && self.max_nicp_drift_e8s > max_drift_e8s
+        {
+            return Some(format!(
+                "reconciliation nICP drift of {} e8s exceeds {max_drift_e8s} e8s",
+                self.max_nicp_drift_e8s
+            ));
+        }
+        None
+    }
+}
+
+/// The withdrawals are taken out of the tracked stake when requested, the neuron only loses
+/// their ICP once split. The rewards are tracked when dispatched, before their transfer lands.
+fn expected_6m_stake_e8s(state: &State) -> u64 {
+    let unsplit_icp_due: u64 = state
+        .withdrawal_to_split
+        .iter()
+        .filter_map(|withdrawal_id| state.withdrawal_id_to_request.get(withdrawal_id))
+        .map(|request| request.icp_due.0)
+        .sum();
+    let neuron_6m_account = state.get_6m_neuron_account();
+    let pending_icp_to_neuron: u64 = state
+        .unsettled_transfers()
+        .filter(|transfer| transfer.unit == Unit::ICP && transfer.receiver == neuron_6m_account)
+        .map(|transfer| transfer.amount.saturating_sub(transfer.unit.fee()))
+        .sum();
+    state
+        .tracked_6m_stake
+        .0
+        .saturating_add(unsplit_icp_due)
+        .saturating_sub(pending_icp_to_neuron)
+}
+
+fn expected_nicp_supply_e8s(state: &State) -> u64 {
+    let pending_mints: u64 = state
+        .unsettled_transfers()
+        .filter(|transfer| transfer.unit == Unit::NICP)
+        .map(|transfer| transfer.amount)
+        .sum();
+    state.total_circulating_nicp.0.saturating_sub(pending_mints)
+}
+
+/// Sums the unsettled ICP and WTN transfers by subaccount, nICP transfers are mints.
+pub fn pending_transfers_by_subaccount(state: &State) -> Vec<SubaccountBalance> {
+    let mut balances: Vec<SubaccountBalance> = vec![];
+    for transfer in state.unsettled_transfers() {
+        if transfer.unit == Unit::NICP {
+            continue;
+        }
+        match balances.iter_mut().find(|balance| {
+            balance.unit == transfer.unit && balance.subaccount == transfer.from_subaccount
+        }) {
+            Some(balance) => balance.pending_e8s += transfer.amount,
+            None => balances.push(SubaccountBalance {
+                unit: transfer.unit.clone(),
+                subaccount: transfer.from_subaccount,
+                pending_e8s: transfer.amount,
+                balance_e8s: None,
+            }),
+        }
+    }
+    balances
+}
+
+/// Returns the event pausing or resuming the protocol following the report. The protocol is
+/// fully paused once [DRIFTING_REPORTS_BEFORE_PAUSE] consecutive reports drift beyond the
+/// thresholds set by governance, so that a transfer landing while the neuron and the ledgers
+/// are queried does not pause it. The pause is lifted once a complete report is back within
+/// the thresholds, which restores the mode set by governance.
+pub fn drift_transition(
+    state: &State,
+    previous_reports: &[ReconciliationReport],
+    report: &ReconciliationReport,
+) -> Option<EventType> {
+    if let Some(reason) = report.excessive_drift(state) {
+        if state.automatic_pause_reason.is_some() {
+            return None;
+        }
+        let drifting_reports = 1 + previous_reports
+            .iter()
+            .take_while(|previous| previous.excessive_drift(state).is_some())
+            .count();
+        if drifting_reports < DRIFTING_REPORTS_BEFORE_PAUSE {
+            return None;
+        }
+        return Some(EventType::ProtocolPaused { reason });
+    }
+    if state.automatic_pause_reason.is_some() && report.complete {
+        return Some(EventType::ProtocolResumed);
+    }
+    None
+}
+
+async fn fetch_balance(unit: &Unit, subaccount: Option<[u8; 32]>) -> Option<u64> {
+    let account = Account {
+        owner: self_canister_id(),
+        subaccount,
+    };
+    match balance_of(account, unit.ledger_id()).await {
+        Ok(balance) => Some(balance),
+        Err(e) => {
+            log!(
+                INFO,
+                "[reconcile] failed to fetch the {unit} balance of {account}: {e}"
+            );
+            None
+        }
+    }
+}
+
+async fn fetch_neuron_6m_stake() -> Option<u64> {
+    let neuron_id = read_state(|s| s.neuron_id_6m)?;
+    let _ = refresh_neuron(SIX_MONTHS_NEURON_NONCE).await;
+    match fetch_neuron_stake(neuron_id.id).await {
+        Ok(stake) => {
+            mutate_state(|s| s.main_neuron_6m_staked = stake);
+            Some(stake.0)
+        }
+        Err(e) => {
+            log!(INFO, "[reconcile] failed to fetch the 6 months neuron: {e}");
+            None
+        }
+    }
+}
+
+async fn fetch_nicp_supply(nicp_ledger_id: Principal) -> Option<u64> {
+    match total_supply(nicp_ledger_id).await {
+        Ok(supply) => Some(supply),
+        Err(e) => {
+            log!(INFO, "[reconcile] failed to fetch the nICP supply: {e}");
+            None
+        }
+    }
+}
+
+/// Compares the tracked accounting against the 6 months neuron and the ledgers, records
+/// the report and pauses the protocol if the drift is too large. Returns true if the report
+/// drifts beyond the thresholds.
+pub async fn reconcile() -> bool {
+    let mut expected = read_state(ExpectedAccounting::new);
+    let neuron_6m_stake_e8s = fetch_neuron_6m_stake().await;
+    let nicp_ledger_supply_e8s = fetch_nicp_supply(read_state(|s| s.nicp_ledger_id)).await;
+    for balance in expected.balances.iter_mut() {
+        balance.balance_e8s = fetch_balance(&balance.unit, balance.subaccount).await;
+    }
+
+    let report = ReconciliationReport::new(
+        expected,
+        timestamp_nanos(),
+        neuron_6m_stake_e8s,
+        nicp_ledger_supply_e8s,
+    );
+    log!(
+        INFO,
+        "[reconcile] stake drift: {:?}, nICP supply drift: {:?}, balance shortfall: {} ({})",
+        report.stake_drift_e8s,
+        report.nicp_supply_drift_e8s,
+        ICP::from_e8s(report.balance_shortfall_e8s),
+        if report.complete {
+            "complete"
+        } else {
+            "incomplete"
+        }
+    );
+    let previous_reports = get_reconciliation_reports(DRIFTING_REPORTS_BEFORE_PAUSE - 1);
+    if let Some(event) = read_state(|s| drift_transition(s, &previous_reports, &report)) {
+        log!(INFO, "[reconcile] {event:?}");
+        mutate_state(|s| process_event(s, event));
+    }
+    let drifting = read_state(|s| report.excessive_drift(s).is_some());
+    record_reconciliation_report(report);
+    drifting
+}
+
+#[test]
+fn should_pause_and_resume_on_drift() {
+    use crate::numeric::nICP;
+    use crate::state::test::default_state;
+    use crate::{PendingTransfer, ProtocolMode};
+
+    let mut state = default_state();
+    state.tracked_6m_stake = ICP::from_e8s(1_000);
+    state.total_circulating_nicp = nICP::from_e8s(500);
+    let transfer = |transfer_id, unit, from_subaccount, amount| PendingTransfer {
+        transfer_id,
+        from_subaccount,
+        memo: None,
+        amount,
+        receiver: Principal::anonymous().into(),
+        unit,
+        created_at_time: 0,
+    };
+    for transfer in [
+        transfer(0, Unit::ICP, None, 100),
+        transfer(1, Unit::ICP, None, 50),
+        transfer(2, Unit::ICP, Some([1; 32]), 20),
+        transfer(3, Unit::NICP, None, 200),
+        transfer(4, Unit::WTN, Some([2; 32]), 10_000),
+    ] {
+        state
+            .pending_transfers
+            .insert(transfer.transfer_id, transfer);
+    }
+
+    let mut expected = ExpectedAccounting::new(&state);
+    assert_eq!(
+        expected
+            .balances
+            .iter()
+            .map(|balance| (balance.subaccount, balance.pending_e8s))
+            .collect::<Vec<_>>(),
+        vec![(None, 150), (Some([1; 32]), 20), (Some([2; 32]), 10_000)]
+    );
+    expected.balances[0].balance_e8s = Some(140);
+    expected.balances[1].balance_e8s = Some(1_000);
+    // The WTN shortfall is reported but not compared against the ICP threshold.
+    expected.balances[2].balance_e8s = Some(0);
+
+    let report = ReconciliationReport::new(expected.clone(), 0, Some(990), Some(305));
+    assert_eq!(report.expected_6m_stake_e8s, 1_000);
+    assert_eq!(report.expected_nicp_supply_e8s, 300);
+    assert_eq!(report.stake_drift_e8s, Some(-10));
+    assert_eq!(report.nicp_supply_drift_e8s, Some(5));
+    assert_eq!(report.balance_shortfall_e8s, 10);
+    assert_eq!(report.max_icp_drift_e8s, 10);
+    assert_eq!(report.max_nicp_drift_e8s, 5);
+    assert!(report.complete);
+
+    // Nothing happens without a threshold.
+    assert_eq!(drift_transition(&state, &[], &report), None);
+
+    // The nICP drift is only compared against the nICP threshold.
+    state.max_reconciliation_drift_e8s = Some(10);
+    state.max_reconciliation_nicp_drift_e8s = Some(5);
+    assert_eq!(report.excessive_drift(&state), None);
+    state.max_reconciliation_nicp_drift_e8s = Some(4);
+    assert!(report.excessive_drift(&state).is_some());
+    state.max_reconciliation_nicp_drift_e8s = None;
+
+    // A single drifting report does not pause the protocol.
+    state.max_reconciliation_drift_e8s = Some(5);
+    assert_eq!(drift_transition(&state, &[], &report), None);
+    let event = drift_transition(&state, &[report.clone()], &report)
+        .expect("two consecutive reports exceed the threshold");
+    assert!(matches!(event, EventType::ProtocolPaused { .. }));
+    crate::state::audit::apply_state_transition(&mut state, &event, 0);
+    assert_eq!(state.effective_protocol_mode(), ProtocolMode::FullyPaused);
+    assert_eq!(drift_transition(&state, &[report.clone()], &report), None);
+
+    // An incomplete report does not resume the protocol.
+    let incomplete = ReconciliationReport::new(
+        ExpectedAccounting {
+            balances: vec![],
+            ..expected.clone()
+        },
+        1,
+        None,
+        Some(300),
+    );
+    assert_eq!(incomplete.max_icp_drift_e8s, 0);
+    assert_eq!(drift_transition(&state, &[], &incomplete), None);
+
+    let within = ReconciliationReport::new(
+        ExpectedAccounting {
+            balances: vec![],
+            ..expected.clone()
+        },
+        2,
+        Some(1_000),
+        Some(300),
+    );
+    assert_eq!(
+        drift_transition(&state, &[report.clone()], &within),
+        Some(EventType::ProtocolResumed)
+    );
+
+    crate::state::audit::apply_state_transition(&mut state, &EventType::ProtocolResumed, 3);
+    assert_eq!(state.effective_protocol_mode(), ProtocolMode::Normal);
+    assert_eq!(drift_transition(&state, &[], &within), None);
+}
+
+#[test]
+fn should_restore_the_governance_mode_on_resume() {
+    use crate::ProtocolMode;
+    use crate::state::audit::apply_state_transition;
+    use crate::state::test::default_state;
+
+    let mut state = default_state();
+    state.max_reconciliation_drift_e8s = Some(5);
+    apply_state_transition(
+        &mut state,
+        &EventType::ProtocolModeChanged {
+            mode: ProtocolMode::DepositsPaused,
+            reason: Some("maintenance".to_string()),
+        },
+        0,
+    );
+
+    let expected = ExpectedAccounting::new(&state);
+    let drifting = ReconciliationReport::new(
+        expected.clone(),
+        1,
+        Some(expected.expected_6m_stake_e8s + 10),
+        Some(expected.expected_nicp_supply_e8s),
+    );
+    let event = drift_transition(&state, &[drifting.clone()], &drifting)
+        .expect("two consecutive reports exceed the threshold");
+    apply_state_transition(&mut state, &event, 1);
+    assert_eq!(state.effective_protocol_mode(), ProtocolMode::FullyPaused);
+    assert_eq!(state.protocol_mode, ProtocolMode::DepositsPaused);
+
+    let within = ReconciliationReport::new(
+        expected.clone(),
+        2,
+        Some(expected.expected_6m_stake_e8s),
+        Some(expected.expected_nicp_supply_e8s),
+    );
+    let event = drift_transition(&state, &[drifting], &within).expect("the drift is resolved");
+    assert_eq!(event, EventType::ProtocolResumed);
+    apply_state_transition(&mut state, &event, 2);
+    assert_eq!(
+        state.effective_protocol_mode(),
+        ProtocolMode::DepositsPaused
+    );
+    assert_eq!(state.protocol_pause_reason(), "maintenance");
+}
+
+#[test]
+fn should_expect_unsplit_withdrawals_and_pending_rewards_in_the_stake() {
+    use crate::numeric::nICP;
+    use crate::state::test::default_state;
+    use crate::{DEFAULT_LEDGER_FEE, NeuronOrigin};
+
+    let mut state = default_state();
+    let caller: Account = Principal::anonymous().into();
+    state.record_icp_deposit(caller, None, ICP::from_unscaled(100), 0, None, false, 0);
+    let tracked_6m_stake_e8s = state.tracked_6m_stake.0;
+
+    // The withdrawn ICP leave the neuron only once split.
+    state.record_nicp_withdrawal(caller, None, nICP::from_unscaled(10), 1, 0);
+    assert_eq!(
+        state.tracked_6m_stake.0,
+        tracked_6m_stake_e8s - ICP::from_unscaled(10).0
+    );
+    assert_eq!(expected_6m_stake_e8s(&state), tracked_6m_stake_e8s);
+
+    // The rewards are only in the neuron once their transfer lands.
+    state.record_dispatch_icp_rewards(
+        ICP::from_unscaled(100),
+        ICP::ZERO,
+        0,
+        NeuronOrigin::NICPSixMonths,
+    );
+    assert_eq!(expected_6m_stake_e8s(&state), tracked_6m_stake_e8s);
+    let reward_transfer_id = state
+        .pending_transfers
+        .values()
+        .find(|transfer| transfer.receiver == state.get_6m_neuron_account())
+        .map(|transfer| transfer.transfer_id)
+        .unwrap();
+    let reward_amount = state.pending_transfers[&reward_transfer_id].amount;
+    state.record_transfer_executed(reward_transfer_id, Some(1), 0);
+    assert_eq!(
+        expected_6m_stake_e8s(&state),
+        tracked_6m_stake_e8s + reward_amount - DEFAULT_LEDGER_FEE
+    );
+}
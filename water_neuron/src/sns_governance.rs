@@ -85,6 +85,7 @@ impl CanisterRuntime for IcCanisterRuntime {
             Some(SNS_GOVERNANCE_SUBACCOUNT),
             ICP_LEDGER_ID,
             Some(SNS_DISTRIBUTION_MEMO),
+            None,
         )
         .await
     }
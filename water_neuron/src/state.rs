@@ -1,13 +1,21 @@
+use crate::conversion::{MINIMUM_DEPOSIT_AMOUNT, MINIMUM_WITHDRAWAL_AMOUNT};
+use crate::guards::{
+    DEFAULT_GLOBAL_RATE_LIMIT, DEFAULT_PRINCIPAL_RATE_LIMIT, RateLimit, TokenBucket,
+};
 use crate::nns_types::{NeuronId, ProposalId};
 use crate::numeric::{ICP, WTN, nICP};
 use crate::sns_distribution::compute_rewards;
 use crate::tasks::TaskType;
 use crate::{
-    CUT_MAX_PERCENT, CUT_MIN_PERCENT, DEFAULT_LEDGER_FEE, E8S, FeeMetrics, InitArg, NEURON_6M_APY,
-    NEURON_8Y_APY, ONE_WEEK_SECONDS, PendingTransfer, SEC_NANOS, TVL_MAX, TVL_MIN, Unit,
-    UpgradeArg, compute_neuron_staking_subaccount_bytes, self_canister_id, timestamp_nanos,
+    CUT_MAX_PERCENT, CUT_MIN_PERCENT, ConversionLimits, DEFAULT_INSTANT_WITHDRAWAL_MAX_FEE_BPS,
+    DEFAULT_INSTANT_WITHDRAWAL_MIN_FEE_BPS, DEFAULT_LEDGER_FEE, DEPOSIT_DEDUP_WINDOW_NANOS,
+    DepositQuote, DepositSuccess, E8S, FeeMetrics, InitArg, MIN_DISSOLVE_DELAY_FOR_REWARDS,
+    NEURON_6M_APY, NEURON_8Y_APY, ONE_DAY, ONE_HOUR, ONE_WEEK_SECONDS, ONE_YEAR_SECONDS,
+    PendingTransfer, ProtocolMode, RETRY_DELAY, SEC_NANOS, TRANSFER_DEDUP_WINDOW, TVL_MAX, TVL_MIN,
+    Unit, UpgradeArg, WithdrawalQuote, compute_neuron_staking_subaccount_bytes,
+    derive_deposit_subaccount, self_canister_id, timestamp_nanos,
 };
-use candid::{CandidType, Principal};
+use candid::{CandidType, Nat, Principal};
 use icrc_ledger_types::icrc1::account::Account;
 use minicbor::{Decode, Encode};
 use rust_decimal::Decimal;
@@ -15,6 +23,7 @@ use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
 use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::fmt;
+use std::time::Duration;
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 
@@ -36,6 +45,19 @@ pub const ICP_LEDGER_ID: Principal = Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0
 pub const NNS_GOVERNANCE_ID: Principal = Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 1, 1, 1]);
 
 pub const SNS_GOVERNANCE_SUBACCOUNT: [u8; 32] = [9; 32];
+pub const LIQUIDITY_BUFFER_SUBACCOUNT: [u8; 32] = [10; 32];
+pub const WITHDRAWAL_PAYOUTS_SUBACCOUNT: [u8; 32] = [11; 32];
+// Collects the funds held for rejected transfers.
+pub const REJECTED_TRANSFERS_SUBACCOUNT: [u8; 32] = [12; 32];
+
+// Merging a neuron into the 6 months neuron costs two times the ICP ledger transaction fee.
+// Once to calculate the effects of merging two neurons (step 1).
+// Once to operate the transaction of the source neuron stake to the target neuron (step 5).
+// Here is the link to the according merge_neurons function used:
+// https://github.com/dfinity/ic/blob/714c85c6a4245fb5b39e76f5c8003e6d90e49c4d/rs/nns/governance/src/governance.rs#L2780
+pub const NEURON_MERGE_ICP_FEE: ICP = ICP::from_e8s(2 * DEFAULT_LEDGER_FEE);
+// On top of the merge fees, splitting the cancelled ICP out of a neuron costs one more.
+pub const NEURON_SPLIT_AND_MERGE_ICP_FEE: ICP = ICP::from_e8s(3 * DEFAULT_LEDGER_FEE);
 
 pub type TransferId = u64;
 pub type WithdrawalId = u64;
@@ -81,6 +103,8 @@ impl fmt::Display for NeuronOrigin {
 pub enum TransferStatus {
     Pending(PendingTransfer),
     Executed(ExecutedTransfer),
+    DeadLettered(DeadLetterTransfer),
+    Rejected { reason: String },
     Unknown,
 }
 
@@ -91,6 +115,59 @@ pub struct ExecutedTransfer {
     pub block_index: Option<u64>,
 }
 
+/// The failed attempts of a pending transfer.
+#[derive(CandidType, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TransferRetry {
+    pub attempts: u32,
+    pub last_error: String,
+    pub last_attempt_at: u64,
+    /// Whether an attempt may have been executed by the ledger, e.g. its reply was lost.
+    pub maybe_executed: bool,
+}
+
+impl TransferRetry {
+    /// The delay doubles with every attempt, starting from [RETRY_DELAY] up to a day. The
+    /// attempt following the last one made an hour before `created_at_time` leaves the
+    /// [TRANSFER_DEDUP_WINDOW] is brought forward, so that an earlier attempt that went
+    /// through is still reported as a duplicate.
+    pub fn next_attempt_at(&self, created_at_time: u64) -> u64 {
+        let backoff = RETRY_DELAY
+            .saturating_mul(1 << self.attempts.saturating_sub(1).min(31))
+            .min(ONE_DAY);
+        let next_attempt_at = self
+            .last_attempt_at
+            .saturating_add(backoff.as_nanos() as u64);
+        let last_attempt_in_window =
+            created_at_time.saturating_add((TRANSFER_DEDUP_WINDOW - ONE_HOUR).as_nanos() as u64);
+        if self.last_attempt_at < last_attempt_in_window {
+            next_attempt_at.min(last_attempt_in_window)
+        } else {
+            next_attempt_at
+        }
+    }
+}
+
+/// A transfer the ledger would reject, it was never attempted.
+#[derive(CandidType, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RejectedTransfer {
+    pub transfer: PendingTransfer,
+    pub reason: String,
+    pub timestamp: u64,
+}
+
+/// A transfer that failed with a terminal error or too many times, it is only
+/// attempted again once requeued by governance.
+#[derive(CandidType, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DeadLetterTransfer {
+    pub transfer: PendingTransfer,
+    pub attempts: u32,
+    pub error: String,
+    pub timestamp: u64,
+    /// Whether an attempt may have been executed by the ledger, the transfer keeps its
+    /// `created_at_time` when requeued unless governance checked the ledger.
+    pub maybe_executed: bool,
+}
+
 #[derive(CandidType, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
 pub struct WithdrawalRequest {
     pub withdrawal_id: WithdrawalId,
@@ -100,6 +177,14 @@ pub struct WithdrawalRequest {
     pub icp_due: ICP,
     pub neuron_id: Option<NeuronId>,
     pub timestamp: u64,
+    pub beneficiary: Option<Account>,
+}
+
+impl WithdrawalRequest {
+    /// The account receiving the ICP of the withdrawal.
+    pub fn icp_receiver(&self) -> Account {
+        self.beneficiary.unwrap_or(self.receiver)
+    }
 }
 
 #[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
@@ -114,21 +199,89 @@ pub struct DisburseRequest {
     pub neuron_id: NeuronId,
 }
 
-#[derive(CandidType, Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+/// The ICP split out of a withdrawal neuron on a partial cancellation, the
+/// receiver gets nICP once the split neuron is merged into the 6 months neuron.
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct PendingPartialMerge {
+    pub neuron_id: NeuronId,
+    pub withdrawal_id: WithdrawalId,
+    pub receiver: Account,
+    pub icp_amount: ICP,
+    /// Unset if a batched withdrawal was split out of its batch neuron as a whole.
+    pub partial: bool,
+}
+
+/// The withdrawal cancelled by merging a neuron into the 6 months neuron and the
+/// transfer minting the nICP back to its receiver.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Cancellation {
+    pub withdrawal_id: WithdrawalId,
+    pub transfer_id: TransferId,
+    /// Set if only part of the withdrawal was cancelled.
+    pub partial: bool,
+    /// The ICP ledger fees paid by the neuron operations.
+    pub icp_fee: ICP,
+}
+
+#[derive(CandidType, Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Encode, Decode)]
 pub struct ExchangeRate {
+    #[n(0)]
     pub short_term_neuron_stake: ICP,
+    #[n(1)]
     pub nicp_supply: nICP,
+    #[n(2)]
     pub timestamp: u64,
+    #[n(3)]
+    pub exchange_rate: u64,
+    /// The ICP of the liquidity buffer backing nICP along with the 6 months neuron,
+    /// unknown for the snapshots taken before it was recorded.
+    #[n(4)]
+    pub liquidity_buffer: Option<ICP>,
+}
+
+#[derive(CandidType, Debug, Deserialize, Eq, PartialEq, Clone)]
+pub struct ExchangeRateHistory {
+    pub exchange_rates: Vec<ExchangeRate>,
+    /// Set if more snapshots are in the range, pass it as the `from_ts` of the next call.
+    pub next_from_ts: Option<u64>,
+}
+
+impl ExchangeRate {
+    /// The annualized growth of the ICP value of one nICP since `past`.
+    pub fn realized_apy_since(&self, past: &ExchangeRate) -> Option<f64> {
+        let elapsed_nanos = self.timestamp.checked_sub(past.timestamp)?;
+        if elapsed_nanos == 0 || self.exchange_rate == 0 || past.exchange_rate == 0 {
+            return None;
+        }
+        // The exchange rate is in nICP per ICP, it goes down as rewards are dispatched.
+        let growth = past.exchange_rate as f64 / self.exchange_rate as f64;
+        let years = elapsed_nanos as f64 / (ONE_YEAR_SECONDS * SEC_NANOS) as f64;
+        Some(growth.powf(1.0 / years) - 1.0)
+    }
 }
 
 #[derive(CandidType, Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
 pub enum WithdrawalStatus {
     WaitingToSplitNeuron,
-    WaitingToStartDissolving { neuron_id: NeuronId },
-    WaitingDissolvement { neuron_id: NeuronId },
-    ConversionDone { transfer_block_height: u64 },
+    WaitingToStartDissolving {
+        neuron_id: NeuronId,
+    },
+    WaitingDissolvement {
+        neuron_id: NeuronId,
+    },
+    WaitingTransfer {
+        transfer_id: TransferId,
+    },
+    ConversionDone {
+        transfer_block_height: u64,
+    },
     NotFound,
     Cancelled,
+    /// The payout transfer was rejected, the withdrawal waits for governance to requeue it.
+    PayoutFailed {
+        transfer_id: TransferId,
+        reason: String,
+    },
 }
 
 impl fmt::Display for WithdrawalStatus {
@@ -141,11 +294,18 @@ impl fmt::Display for WithdrawalStatus {
             WithdrawalStatus::WaitingDissolvement { neuron_id } => {
                 write!(f, "Waiting dissolvement of {}", neuron_id.id)
             }
+            WithdrawalStatus::WaitingTransfer { transfer_id } => {
+                write!(f, "Waiting payout transfer {transfer_id}")
+            }
             WithdrawalStatus::ConversionDone {
                 transfer_block_height,
             } => write!(f, "Neuron Disbursed at index: {transfer_block_height}"),
             WithdrawalStatus::NotFound => write!(f, "Neuron Not Found"),
             WithdrawalStatus::Cancelled => write!(f, "Withdrawal Cancelled"),
+            WithdrawalStatus::PayoutFailed {
+                transfer_id,
+                reason,
+            } => write!(f, "Payout transfer {transfer_id} rejected: {reason}"),
         }
     }
 }
@@ -177,8 +337,20 @@ pub struct State {
     pub withdrawal_id_to_request: BTreeMap<WithdrawalId, WithdrawalRequest>,
     pub neuron_id_to_withdrawal_id: BTreeMap<NeuronId, WithdrawalId>,
 
+    // Withdrawals batched into a single neuron per epoch, 0 disables batching.
+    pub withdrawal_epoch_seconds: u64,
+    pub batch_neuron_to_withdrawal_ids: BTreeMap<NeuronId, Vec<WithdrawalId>>,
+    pub withdrawal_payout_transfers: BTreeMap<TransferId, WithdrawalId>,
+    pub withdrawal_id_to_payout_transfer: BTreeMap<WithdrawalId, TransferId>,
+    // Withdrawals whose payout transfer was rejected, until governance requeues it.
+    pub withdrawal_payout_rejected: BTreeMap<WithdrawalId, TransferId>,
+
     // Cancel Withdrawal
     pub withdrawal_cancelled: BTreeSet<WithdrawalId>,
+    // The cancellations by neuron merged into the 6 months neuron.
+    pub cancellations: BTreeMap<NeuronId, Cancellation>,
+    // Neurons split out of a withdrawal neuron, by id, waiting to be merged.
+    pub pending_partial_merges: BTreeMap<NeuronId, PendingPartialMerge>,
 
     // Neurons To Disburse
     pub to_disburse: BTreeMap<NeuronId, DisburseRequest>,
@@ -189,11 +361,23 @@ pub struct State {
     // Transfer queues
     pub pending_transfers: BTreeMap<TransferId, PendingTransfer>,
     pub transfer_executed: BTreeMap<TransferId, ExecutedTransfer>,
+    pub transfer_retries: BTreeMap<TransferId, TransferRetry>,
+    pub dead_letter_transfers: BTreeMap<TransferId, DeadLetterTransfer>,
+    pub rejected_transfers: BTreeMap<TransferId, RejectedTransfer>,
+    // The funds of rejected transfers and the rounding dust of batch payouts left in
+    // their subaccount, swept to REJECTED_TRANSFERS_SUBACCOUNT once they cover the ledger fee.
+    pub unswept_rejected_funds: BTreeMap<(Unit, Option<[u8; 32]>), u64>,
 
     // Maps for tracking purposes.
     pub account_to_deposits: BTreeMap<Account, Vec<TransferId>>,
     pub account_to_withdrawals: BTreeMap<Account, Vec<WithdrawalId>>,
 
+    // Deposits made with a created_at_time, keyed by (payer, created_at_time, amount).
+    pub deduplicated_deposits: BTreeMap<(Account, u64, ICP), DepositSuccess>,
+    // Deposits swept from a deposit subaccount, keyed by (deposit account, block index of the
+    // transfer to it), with the time they were notified.
+    pub notified_deposits: BTreeMap<(Account, u64), (u64, DepositSuccess)>,
+
     // Neurons
     pub neuron_id_6m: Option<NeuronId>,
     pub main_neuron_6m_staked: ICP,
@@ -201,14 +385,49 @@ pub struct State {
     pub neuron_id_8y: Option<NeuronId>,
     pub main_neuron_8y_stake: ICP,
 
+    // Conversion limits.
+    pub minimum_deposit_amount: ICP,
+    pub minimum_withdrawal_amount: ICP,
+
+    // Liquidity buffer paying out instant withdrawals, its ICP back nICP like the 6m stake.
+    pub liquidity_buffer: ICP,
+    pub liquidity_buffer_target: ICP,
+    pub liquidity_buffer_rewards_share_percent: u64,
+    pub instant_withdrawal_min_fee_bps: u64,
+    pub instant_withdrawal_max_fee_bps: u64,
+
     // Some canister ids.
     pub nicp_ledger_id: Principal,
     pub wtn_governance_id: Principal,
     pub wtn_ledger_id: Principal,
 
-    // Guards
+    // Guards, they are not part of the state checkpoints.
+    #[serde(skip)]
     pub principal_guards: BTreeSet<Principal>,
+    #[serde(skip)]
     pub active_tasks: BTreeSet<TaskType>,
+    // The liquidity buffer reserved by the instant withdrawals burning nICP.
+    #[serde(skip)]
+    pub liquidity_buffer_reservations: BTreeMap<Principal, ICP>,
+
+    // Rate limits
+    pub principal_rate_limit: RateLimit,
+    pub global_rate_limit: RateLimit,
+    #[serde(skip)]
+    pub principal_rate_limit_buckets: BTreeMap<Principal, TokenBucket>,
+    #[serde(skip)]
+    pub global_rate_limit_bucket: Option<TokenBucket>,
+
+    // Reconciliation, conversions are paused when it drifts by more than these amounts
+    // of ICP and nICP.
+    pub max_reconciliation_drift_e8s: Option<u64>,
+    pub max_reconciliation_nicp_drift_e8s: Option<u64>,
+
+    // Conversions accepted by the protocol, as set by governance. The reconciliation fully
+    // pauses the protocol on top of this mode, the pause is lifted once the drift is resolved.
+    pub protocol_mode: ProtocolMode,
+    pub protocol_mode_reason: Option<String>,
+    pub automatic_pause_reason: Option<String>,
 
     // ICP Distribution
     pub latest_distribution_icp_per_vp: Option<f64>,
@@ -233,23 +452,53 @@ impl State {
             maturity_neuron_to_block_indicies: Default::default(),
             withdrawal_finalized: Default::default(),
             withdrawal_id_to_request: BTreeMap::default(),
+            withdrawal_epoch_seconds: 0,
+            batch_neuron_to_withdrawal_ids: BTreeMap::default(),
+            withdrawal_payout_transfers: BTreeMap::default(),
+            withdrawal_id_to_payout_transfer: BTreeMap::default(),
+            withdrawal_payout_rejected: BTreeMap::default(),
             withdrawal_cancelled: BTreeSet::default(),
+            cancellations: BTreeMap::default(),
+            pending_partial_merges: BTreeMap::default(),
             account_to_deposits: BTreeMap::default(),
             account_to_withdrawals: BTreeMap::default(),
+            deduplicated_deposits: BTreeMap::default(),
+            notified_deposits: BTreeMap::default(),
             transfer_id: 0,
             withdrawal_id: 0,
             voted_proposals: BTreeSet::default(),
             pending_transfers: BTreeMap::default(),
             transfer_executed: BTreeMap::default(),
+            transfer_retries: BTreeMap::default(),
+            dead_letter_transfers: BTreeMap::default(),
+            rejected_transfers: BTreeMap::default(),
+            unswept_rejected_funds: BTreeMap::default(),
             neuron_id_6m: None,
             neuron_id_8y: None,
             main_neuron_6m_staked: ICP::ZERO,
             main_neuron_8y_stake: ICP::ZERO,
+            minimum_deposit_amount: MINIMUM_DEPOSIT_AMOUNT,
+            minimum_withdrawal_amount: MINIMUM_WITHDRAWAL_AMOUNT,
+            liquidity_buffer: ICP::ZERO,
+            liquidity_buffer_target: ICP::ZERO,
+            liquidity_buffer_rewards_share_percent: 0,
+            instant_withdrawal_min_fee_bps: DEFAULT_INSTANT_WITHDRAWAL_MIN_FEE_BPS,
+            instant_withdrawal_max_fee_bps: DEFAULT_INSTANT_WITHDRAWAL_MAX_FEE_BPS,
             nicp_ledger_id: init_arg.nicp_ledger_id,
             wtn_governance_id: init_arg.wtn_governance_id,
             wtn_ledger_id: init_arg.wtn_ledger_id,
             principal_guards: BTreeSet::default(),
             active_tasks: BTreeSet::default(),
+            liquidity_buffer_reservations: BTreeMap::default(),
+            principal_rate_limit: DEFAULT_PRINCIPAL_RATE_LIMIT,
+            global_rate_limit: DEFAULT_GLOBAL_RATE_LIMIT,
+            principal_rate_limit_buckets: BTreeMap::default(),
+            global_rate_limit_bucket: None,
+            max_reconciliation_drift_e8s: None,
+            max_reconciliation_nicp_drift_e8s: None,
+            protocol_mode: ProtocolMode::Normal,
+            protocol_mode_reason: None,
+            automatic_pause_reason: None,
             latest_distribution_icp_per_vp: None,
             last_nns_proposal_processed: Default::default(),
             last_distribution_ts: timestamp_nanos(),
@@ -264,16 +513,72 @@ impl State {
         let mut res: Vec<NeuronId> = vec![];
         for withdrawal_id in self.withdrawal_to_start_dissolving.iter() {
             if let Some(req) = self.get_withdrawal_request(*withdrawal_id) {
-                res.push(req.neuron_id.expect("bug: neuron_id should be set"));
+                let neuron_id = req.neuron_id.expect("bug: neuron_id should be set");
+                // Withdrawals of the same batch share their neuron.
+                if !res.contains(&neuron_id) {
+                    res.push(neuron_id);
+                }
             }
         }
         res
     }
 
+    /// Returns the withdrawals to split into a single neuron once the epoch
+    /// of the oldest queued withdrawal is over.
+    pub fn get_withdrawal_batch_to_split(&self, now: u64) -> Option<Vec<WithdrawalId>> {
+        let oldest_timestamp = self
+            .withdrawal_to_split
+            .iter()
+            .filter_map(|id| self.withdrawal_id_to_request.get(id))
+            .map(|request| request.timestamp)
+            .min()?;
+        let epoch_end = oldest_timestamp
+            .saturating_add(self.withdrawal_epoch_seconds.saturating_mul(SEC_NANOS));
+        if now < epoch_end {
+            return None;
+        }
+        Some(self.withdrawal_to_split.iter().copied().collect())
+    }
+
+    pub fn compute_withdrawal_batch_icp_due(&self, withdrawal_ids: &[WithdrawalId]) -> ICP {
+        ICP::from_e8s(
+            withdrawal_ids
+                .iter()
+                .map(|id| {
+                    self.withdrawal_id_to_request
+                        .get(id)
+                        .expect("bug: withdrawal id to request should be set")
+                        .icp_due
+                        .0
+                })
+                .sum(),
+        )
+    }
+
+    pub fn is_batch_neuron(&self, neuron_id: NeuronId) -> bool {
+        self.batch_neuron_to_withdrawal_ids.contains_key(&neuron_id)
+    }
+
     pub fn neuron_id_to_withdrawal_id(&self, neuron_id: NeuronId) -> Option<WithdrawalId> {
         self.neuron_id_to_withdrawal_id.get(&neuron_id).copied()
     }
 
+    /// Returns the withdrawal dissolving alone in `neuron_id`, which includes a batch
+    /// neuron holding a single withdrawal.
+    pub fn sole_withdrawal_of_neuron(&self, neuron_id: NeuronId) -> Option<WithdrawalId> {
+        match self.neuron_id_to_withdrawal_id.get(&neuron_id) {
+            Some(withdrawal_id) => Some(*withdrawal_id),
+            None => match self
+                .batch_neuron_to_withdrawal_ids
+                .get(&neuron_id)?
+                .as_slice()
+            {
+                [withdrawal_id] => Some(*withdrawal_id),
+                _ => None,
+            },
+        }
+    }
+
     pub fn get_withdrawal_status(&self, withdrawal_id: WithdrawalId) -> WithdrawalStatus {
         if self.withdrawal_to_split.contains(&withdrawal_id) {
             return WithdrawalStatus::WaitingToSplitNeuron;
@@ -303,6 +608,23 @@ impl State {
             };
         }
 
+        if let Some(transfer_id) = self.withdrawal_id_to_payout_transfer.get(&withdrawal_id) {
+            return WithdrawalStatus::WaitingTransfer {
+                transfer_id: *transfer_id,
+            };
+        }
+
+        if let Some(transfer_id) = self.withdrawal_payout_rejected.get(&withdrawal_id) {
+            return WithdrawalStatus::PayoutFailed {
+                transfer_id: *transfer_id,
+                reason: self
+                    .rejected_transfers
+                    .get(transfer_id)
+                    .map(|rejected| rejected.reason.clone())
+                    .unwrap_or_default(),
+            };
+        }
+
         if let Some(block_index) = self.withdrawal_finalized.get(&withdrawal_id) {
             return WithdrawalStatus::ConversionDone {
                 transfer_block_height: *block_index,
@@ -323,19 +645,73 @@ impl State {
         if let Some(transfer) = self.transfer_executed.get(&id) {
             return TransferStatus::Executed(transfer.clone());
         }
+        if let Some(transfer) = self.dead_letter_transfers.get(&id) {
+            return TransferStatus::DeadLettered(transfer.clone());
+        }
+        if let Some(rejected) = self.rejected_transfers.get(&id) {
+            return TransferStatus::Rejected {
+                reason: rejected.reason.clone(),
+            };
+        }
         TransferStatus::Unknown
     }
 
+    pub fn transfer_attempts(&self, transfer_id: TransferId) -> u32 {
+        self.transfer_retries
+            .get(&transfer_id)
+            .map(|retry| retry.attempts)
+            .unwrap_or_default()
+    }
+
+    /// The time at which the pending transfer is due, transfers that never failed are due now.
+    pub fn next_transfer_attempt_at(&self, transfer_id: TransferId) -> u64 {
+        match (
+            self.transfer_retries.get(&transfer_id),
+            self.pending_transfers.get(&transfer_id),
+        ) {
+            (Some(retry), Some(transfer)) => retry.next_attempt_at(transfer.created_at_time),
+            _ => 0,
+        }
+    }
+
+    /// Returns true if an attempt of the pending transfer may have been executed by the ledger.
+    pub fn is_transfer_maybe_executed(&self, transfer_id: TransferId) -> bool {
+        self.transfer_retries
+            .get(&transfer_id)
+            .is_some_and(|retry| retry.maybe_executed)
+    }
+
+    /// The delay until the next pending transfer is due, if any transfer is pending.
+    pub fn next_transfer_attempt_delay(&self, now: u64) -> Option<Duration> {
+        self.pending_transfers
+            .keys()
+            .map(|transfer_id| self.next_transfer_attempt_at(*transfer_id))
+            .min()
+            .map(|next_attempt_at| {
+                Duration::from_nanos(next_attempt_at.saturating_sub(now)).max(RETRY_DELAY)
+            })
+    }
+
+    /// The transfers whose funds are still held by the protocol, pending or dead-lettered.
+    pub fn unsettled_transfers(&self) -> impl Iterator<Item = &PendingTransfer> {
+        self.pending_transfers.values().chain(
+            self.dead_letter_transfers
+                .values()
+                .map(|dead_letter| &dead_letter.transfer),
+        )
+    }
+
+    /// Dead-lettered transfers still hold their ICP in the source subaccount, they count
+    /// as processing until settled so that the balance is not dispatched twice.
     pub fn is_processing_icp_transfer_from_neuron(&self) -> bool {
-        self.pending_transfers.values().any(|transfer| {
+        self.unsettled_transfers().any(|transfer| {
             NeuronOrigin::iter()
                 .any(|origin| transfer.from_subaccount == Some(origin.to_subaccount()))
         })
     }
 
     pub fn is_processing_icp_transfer_from_sns_subaccount(&self) -> bool {
-        self.pending_transfers
-            .values()
+        self.unsettled_transfers()
             .any(|transfer| transfer.from_subaccount == Some(SNS_GOVERNANCE_SUBACCOUNT))
     }
 
@@ -344,13 +720,19 @@ impl State {
         self.neuron_id_6m != Some(neuron_id) && self.neuron_id_8y != Some(neuron_id)
     }
 
+    /// The ICP backing the circulating nICP.
+    pub fn total_icp_backing(&self) -> ICP {
+        ICP::from_e8s(self.tracked_6m_stake.0 + self.liquidity_buffer.0)
+    }
+
     pub fn get_icp_to_ncip_exchange_rate(&self) -> Decimal {
+        let total_icp_backing = self.total_icp_backing();
         // If we didn't mint any nicp yet the exchange rate is 1.
-        if self.total_circulating_nicp == nICP::ZERO || self.tracked_6m_stake == ICP::ZERO {
+        if self.total_circulating_nicp == nICP::ZERO || total_icp_backing == ICP::ZERO {
             return Decimal::ONE;
         }
 
-        Decimal::from(self.total_circulating_nicp.0) / Decimal::from(self.tracked_6m_stake.0)
+        Decimal::from(self.total_circulating_nicp.0) / Decimal::from(total_icp_backing.0)
     }
 
     pub fn get_icp_to_ncip_exchange_rate_e8s(&self) -> u64 {
@@ -359,6 +741,16 @@ impl State {
         exchange_rate.mantissa() as u64
     }
 
+    pub fn get_exchange_rate(&self, timestamp: u64) -> ExchangeRate {
+        ExchangeRate {
+            short_term_neuron_stake: self.tracked_6m_stake,
+            nicp_supply: self.total_circulating_nicp,
+            timestamp,
+            exchange_rate: self.get_icp_to_ncip_exchange_rate_e8s(),
+            liquidity_buffer: Some(self.liquidity_buffer),
+        }
+    }
+
     pub fn compute_governance_share_e8s(&self, balance: u64, neuron_type: NeuronOrigin) -> u64 {
         let share_percent = match neuron_type {
             NeuronOrigin::NICPSixMonths => self.governance_fee_share_percent,
@@ -381,6 +773,55 @@ impl State {
         ICP::from_e8s(result.mantissa() as u64)
     }
 
+    /// The WTN airdropped for a deposit of `amount` ICP.
+    pub fn compute_airdrop_rewards(&self, amount: ICP) -> WTN {
+        compute_rewards(self.total_icp_deposited, amount)
+    }
+
+    /// Estimates when a withdrawal queued at `now` gets disbursed: once its
+    /// epoch is over it is split and dissolves for the 6m neuron dissolve delay.
+    pub fn estimate_withdrawal_completion_secs(&self, now: u64) -> u64 {
+        let split_at = if self.withdrawal_epoch_seconds == 0 {
+            now
+        } else {
+            let oldest_timestamp = self
+                .withdrawal_to_split
+                .iter()
+                .filter_map(|id| self.withdrawal_id_to_request.get(id))
+                .map(|request| request.timestamp)
+                .min()
+                .unwrap_or(now);
+            oldest_timestamp
+                .saturating_add(self.withdrawal_epoch_seconds.saturating_mul(SEC_NANOS))
+                .max(now)
+        };
+        split_at / SEC_NANOS + MIN_DISSOLVE_DELAY_FOR_REWARDS
+    }
+
+    pub fn quote_icp_to_nicp(&self, amount: ICP, now: u64) -> DepositQuote {
+        DepositQuote {
+            icp_amount: amount,
+            nicp_amount: self.convert_icp_to_nicp(amount),
+            ledger_fee: ICP::from_e8s(DEFAULT_LEDGER_FEE),
+            airdrop_amount: self.compute_airdrop_rewards(amount),
+            to_liquidity_buffer: self.should_deposit_to_liquidity_buffer(amount),
+            exchange_rate: self.get_icp_to_ncip_exchange_rate_e8s(),
+            timestamp: now,
+        }
+    }
+
+    pub fn quote_nicp_to_icp(&self, amount: nICP, now: u64) -> WithdrawalQuote {
+        let icp_amount = self.convert_nicp_to_icp(amount);
+        WithdrawalQuote {
+            nicp_amount: amount,
+            icp_amount,
+            instant_withdrawal_fee: self.compute_instant_withdrawal_fee(icp_amount),
+            estimated_completion_timestamp_secs: self.estimate_withdrawal_completion_secs(now),
+            exchange_rate: self.get_icp_to_ncip_exchange_rate_e8s(),
+            timestamp: now,
+        }
+    }
+
     pub fn increment_transfer_id(&mut self) -> u64 {
         let transfer_id = self.transfer_id;
         self.transfer_id += 1;
@@ -435,10 +876,140 @@ impl State {
         }
     }
 
+    /// Deposits are sent to the liquidity buffer as long as they fit under its target.
+    pub fn should_deposit_to_liquidity_buffer(&self, amount: ICP) -> bool {
+        self.liquidity_buffer.0.saturating_add(amount.0) <= self.liquidity_buffer_target.0
+    }
+
+    /// Returns the part of the ICP rewards for nICP holders routed to the liquidity buffer.
+    pub fn compute_liquidity_buffer_rewards_share(&self, nicp_amount: ICP) -> ICP {
+        let missing = self
+            .liquidity_buffer_target
+            .0
+            .saturating_sub(self.liquidity_buffer.0);
+        let share = (nicp_amount.0 as u128 * self.liquidity_buffer_rewards_share_percent as u128
+            / 100) as u64;
+        // The remaining rewards still have to pay for their transfer to the 6m neuron.
+        let share = share
+            .min(missing)
+            .min(nicp_amount.0.saturating_sub(DEFAULT_LEDGER_FEE));
+        if share <= DEFAULT_LEDGER_FEE {
+            return ICP::ZERO;
+        }
+        ICP::from_e8s(share)
+    }
+
+    /// The liquidity buffer not reserved by the instant withdrawals in flight.
+    pub fn available_liquidity_buffer(&self) -> ICP {
+        let reserved: u64 = self
+            .liquidity_buffer_reservations
+            .values()
+            .map(|amount| amount.0)
+            .sum();
+        ICP::from_e8s(self.liquidity_buffer.0.saturating_sub(reserved))
+    }
+
+    /// Returns the fee charged to withdraw `icp_due` out of the liquidity buffer, or `None` if
+    /// the buffer cannot cover it. The fee goes linearly from the minimum to the maximum rate
+    /// as the withdrawal drains the buffer below its target.
+    /// A returned fee always leaves more than a ledger fee of `icp_due` to transfer out.
+    pub fn compute_instant_withdrawal_fee(&self, icp_due: ICP) -> Option<ICP> {
+        let buffer_after = self.available_liquidity_buffer().checked_sub(icp_due)?;
+        let target = self.liquidity_buffer_target.0;
+        let utilisation_bps = if target == 0 {
+            10_000
+        } else {
+            target.saturating_sub(buffer_after.0) as u128 * 10_000 / target as u128
+        };
+        let min_fee_bps = self.instant_withdrawal_min_fee_bps as u128;
+        let max_fee_bps = (self.instant_withdrawal_max_fee_bps as u128).max(min_fee_bps);
+        let fee_bps = min_fee_bps + (max_fee_bps - min_fee_bps) * utilisation_bps / 10_000;
+        let fee = ICP::from_e8s((icp_due.0 as u128 * fee_bps / 10_000) as u64);
+        if icp_due.checked_sub(fee)?.0 <= DEFAULT_LEDGER_FEE {
+            return None;
+        }
+        Some(fee)
+    }
+
     pub fn record_upgrade(&mut self, upgrade_arg: UpgradeArg) {
         if let Some(governance_fee_share_percent) = upgrade_arg.governance_fee_share_percent {
             self.governance_fee_share_percent = governance_fee_share_percent;
         }
+        if let Some(liquidity_buffer_target_e8s) = upgrade_arg.liquidity_buffer_target_e8s {
+            self.liquidity_buffer_target = ICP::from_e8s(liquidity_buffer_target_e8s);
+        }
+        if let Some(share_percent) = upgrade_arg.liquidity_buffer_rewards_share_percent {
+            self.liquidity_buffer_rewards_share_percent = share_percent;
+        }
+        if let Some(min_fee_bps) = upgrade_arg.instant_withdrawal_min_fee_bps {
+            self.instant_withdrawal_min_fee_bps = min_fee_bps;
+        }
+        if let Some(max_fee_bps) = upgrade_arg.instant_withdrawal_max_fee_bps {
+            self.instant_withdrawal_max_fee_bps = max_fee_bps;
+        }
+        if let Some(withdrawal_epoch_seconds) = upgrade_arg.withdrawal_epoch_seconds {
+            self.withdrawal_epoch_seconds = withdrawal_epoch_seconds;
+        }
+        if let Some(conversion_limits) = upgrade_arg.conversion_limits {
+            self.record_conversion_limits(conversion_limits);
+        }
+        if let Some(principal_rate_limit) = upgrade_arg.principal_rate_limit {
+            self.principal_rate_limit = principal_rate_limit;
+        }
+        if let Some(global_rate_limit) = upgrade_arg.global_rate_limit {
+            self.global_rate_limit = global_rate_limit;
+        }
+        if let Some(max_drift_e8s) = upgrade_arg.max_reconciliation_drift_e8s {
+            self.max_reconciliation_drift_e8s = Some(max_drift_e8s);
+        }
+        if let Some(max_drift_e8s) = upgrade_arg.max_reconciliation_nicp_drift_e8s {
+            self.max_reconciliation_nicp_drift_e8s = Some(max_drift_e8s);
+        }
+    }
+
+    pub fn record_protocol_paused(&mut self, reason: String) {
+        self.automatic_pause_reason = Some(reason);
+    }
+
+    pub fn record_protocol_resumed(&mut self) {
+        self.automatic_pause_reason = None;
+    }
+
+    pub fn record_protocol_mode_changed(&mut self, mode: ProtocolMode, reason: Option<String>) {
+        self.protocol_mode = mode;
+        self.protocol_mode_reason = reason;
+    }
+
+    /// The mode enforced on conversions, the protocol is fully paused while the reconciliation
+    /// paused it, whatever the mode set by governance.
+    pub fn effective_protocol_mode(&self) -> ProtocolMode {
+        if self.automatic_pause_reason.is_some() {
+            ProtocolMode::FullyPaused
+        } else {
+            self.protocol_mode
+        }
+    }
+
+    pub fn effective_protocol_mode_reason(&self) -> Option<String> {
+        self.automatic_pause_reason
+            .clone()
+            .or_else(|| self.protocol_mode_reason.clone())
+    }
+
+    /// The reason returned to the calls rejected by the protocol mode.
+    pub fn protocol_pause_reason(&self) -> String {
+        self.effective_protocol_mode_reason()
+            .unwrap_or_else(|| format!("the protocol mode is {}", self.effective_protocol_mode()))
+    }
+
+    pub fn record_conversion_limits(&mut self, conversion_limits: ConversionLimits) {
+        if let Some(minimum_deposit_amount_e8s) = conversion_limits.minimum_deposit_amount_e8s {
+            self.minimum_deposit_amount = ICP::from_e8s(minimum_deposit_amount_e8s);
+        }
+        if let Some(minimum_withdrawal_amount_e8s) = conversion_limits.minimum_withdrawal_amount_e8s
+        {
+            self.minimum_withdrawal_amount = ICP::from_e8s(minimum_withdrawal_amount_e8s);
+        }
     }
 
     pub fn record_transfer_executed(
@@ -460,12 +1031,276 @@ impl State {
             }
             None => ic_cdk::trap(format!("transfer with id {transfer_id} not found")),
         }
+        self.transfer_retries.remove(&transfer_id);
+        if let Some(withdrawal_id) = self.withdrawal_payout_transfers.remove(&transfer_id) {
+            self.withdrawal_id_to_payout_transfer.remove(&withdrawal_id);
+            assert!(
+                self.withdrawal_finalized
+                    .insert(withdrawal_id, block_index.unwrap_or_default())
+                    .is_none()
+            );
+        }
+    }
+
+    pub fn record_transfer_failed(
+        &mut self,
+        transfer_id: TransferId,
+        error: String,
+        maybe_executed: bool,
+        timestamp: u64,
+    ) {
+        if !self.pending_transfers.contains_key(&transfer_id) {
+            ic_cdk::trap(format!("transfer with id {transfer_id} not found"));
+        }
+        let attempts = self.transfer_attempts(transfer_id) + 1;
+        self.transfer_retries.insert(
+            transfer_id,
+            TransferRetry {
+                attempts,
+                last_error: error,
+                last_attempt_at: timestamp,
+                maybe_executed,
+            },
+        );
+    }
+
+    pub fn record_transfer_dead_lettered(
+        &mut self,
+        transfer_id: TransferId,
+        error: String,
+        maybe_executed: bool,
+        timestamp: u64,
+    ) {
+        let transfer = match self.pending_transfers.remove(&transfer_id) {
+            Some(transfer) => transfer,
+            None => ic_cdk::trap(format!("transfer with id {transfer_id} not found")),
+        };
+        let attempts = self.transfer_attempts(transfer_id) + 1;
+        self.transfer_retries.remove(&transfer_id);
+        self.dead_letter_transfers.insert(
+            transfer_id,
+            DeadLetterTransfer {
+                transfer,
+                attempts,
+                error,
+                timestamp,
+                maybe_executed,
+            },
+        );
+    }
+
+    /// A rejected withdrawal payout fails the withdrawal until governance requeues it.
+    /// The nICP of a rejected mint are not in circulation, the ICP or WTN of other
+    /// transfers are swept to the rejected transfers subaccount.
+    pub fn record_transfer_rejected(
+        &mut self,
+        transfer_id: TransferId,
+        reason: String,
+        timestamp: u64,
+    ) {
+        let transfer = match self.pending_transfers.remove(&transfer_id) {
+            Some(transfer) => transfer,
+            None => ic_cdk::trap(format!("transfer with id {transfer_id} not found")),
+        };
+        self.transfer_retries.remove(&transfer_id);
+        if let Some(withdrawal_id) = self.withdrawal_payout_transfers.remove(&transfer_id) {
+            self.withdrawal_id_to_payout_transfer.remove(&withdrawal_id);
+            self.withdrawal_payout_rejected
+                .insert(withdrawal_id, transfer_id);
+        }
+        if transfer.unit == Unit::NICP {
+            self.total_circulating_nicp = self
+                .total_circulating_nicp
+                .checked_sub(nICP::from_e8s(transfer.amount))
+                .expect("bug: the rejected mint should be in circulation");
+        } else {
+            self.record_unswept_funds(
+                transfer.unit.clone(),
+                transfer.from_subaccount,
+                transfer.amount,
+                timestamp,
+            );
+        }
+        self.rejected_transfers.insert(
+            transfer_id,
+            RejectedTransfer {
+                transfer,
+                reason,
+                timestamp,
+            },
+        );
+    }
+
+    /// Leaves `amount` in `from_subaccount` until the funds left there cover the ledger fee,
+    /// they are then swept to the rejected transfers subaccount.
+    fn record_unswept_funds(
+        &mut self,
+        unit: Unit,
+        from_subaccount: Option<[u8; 32]>,
+        amount: u64,
+        timestamp: u64,
+    ) {
+        if amount == 0 {
+            return;
+        }
+        let key = (unit.clone(), from_subaccount);
+        let unswept = self.unswept_rejected_funds.entry(key.clone()).or_default();
+        *unswept += amount;
+        if *unswept > unit.fee() {
+            let amount = *unswept;
+            self.unswept_rejected_funds.remove(&key);
+            let sweep_id = self.increment_transfer_id();
+            self.pending_transfers.insert(
+                sweep_id,
+                PendingTransfer {
+                    transfer_id: sweep_id,
+                    from_subaccount,
+                    memo: None,
+                    amount,
+                    receiver: Account {
+                        owner: self_canister_id(),
+                        subaccount: Some(REJECTED_TRANSFERS_SUBACCOUNT),
+                    },
+                    unit,
+                    created_at_time: timestamp,
+                },
+            );
+        }
+    }
+
+    /// Returns the amount of the payout requeued for the withdrawal, its rejected payout was
+    /// swept to the rejected transfers subaccount at the cost of a ledger fee.
+    pub fn requeued_withdrawal_payout_amount(
+        &self,
+        withdrawal_id: WithdrawalId,
+    ) -> Result<ICP, String> {
+        let transfer_id = self
+            .withdrawal_payout_rejected
+            .get(&withdrawal_id)
+            .ok_or(format!(
+                "the payout of withdrawal {withdrawal_id} was not rejected"
+            ))?;
+        let rejected = self
+            .rejected_transfers
+            .get(transfer_id)
+            .expect("bug: the rejected payout should be tracked");
+        match rejected.transfer.amount.checked_sub(DEFAULT_LEDGER_FEE) {
+            Some(amount) if amount > DEFAULT_LEDGER_FEE => Ok(ICP::from_e8s(amount)),
+            _ => Err(format!(
+                "the rejected payout {transfer_id} of withdrawal {withdrawal_id} does not cover the fees"
+            )),
+        }
+    }
+
+    /// Pays the withdrawal out from the rejected transfers subaccount, its status is back
+    /// to waiting on the new payout transfer.
+    pub fn record_withdrawal_payout_requeued(
+        &mut self,
+        withdrawal_id: WithdrawalId,
+        receiver: Account,
+        timestamp: u64,
+    ) {
+        let amount = self
+            .requeued_withdrawal_payout_amount(withdrawal_id)
+            .unwrap_or_else(|e| ic_cdk::trap(format!("bug: {e}")));
+        self.withdrawal_payout_rejected.remove(&withdrawal_id);
+        let transfer_id = self.record_icp_pending_transfer(
+            REJECTED_TRANSFERS_SUBACCOUNT,
+            receiver,
+            amount,
+            None,
+            timestamp,
+        );
+        self.withdrawal_payout_transfers
+            .insert(transfer_id, withdrawal_id);
+        self.withdrawal_id_to_payout_transfer
+            .insert(withdrawal_id, transfer_id);
+    }
+
+    /// The requeued transfer only gets a new `created_at_time` if none of its attempts can
+    /// be on the ledger, otherwise a second attempt could pay the receiver twice.
+    pub fn record_transfer_requeued(
+        &mut self,
+        transfer_id: TransferId,
+        receiver: Option<Account>,
+        verified_not_executed: bool,
+        timestamp: u64,
+    ) {
+        let dead_letter = match self.dead_letter_transfers.remove(&transfer_id) {
+            Some(dead_letter) => dead_letter,
+            None => ic_cdk::trap(format!(
+                "dead-lettered transfer with id {transfer_id} not found"
+            )),
+        };
+        let mut transfer = dead_letter.transfer;
+        if let Some(receiver) = receiver {
+            transfer.receiver = receiver;
+        }
+        if dead_letter.maybe_executed && !verified_not_executed {
+            self.transfer_retries.insert(
+                transfer_id,
+                TransferRetry {
+                    attempts: 0,
+                    last_error: dead_letter.error,
+                    last_attempt_at: dead_letter.timestamp,
+                    maybe_executed: true,
+                },
+            );
+        } else {
+            transfer.created_at_time = timestamp;
+        }
+        self.pending_transfers.insert(transfer_id, transfer);
+    }
+
+    pub fn record_transfer_restamped(&mut self, transfer_id: TransferId, timestamp: u64) {
+        if self.is_transfer_maybe_executed(transfer_id) {
+            ic_cdk::trap(format!(
+                "bug: transfer with id {transfer_id} may have been executed"
+            ));
+        }
+        match self.pending_transfers.get_mut(&transfer_id) {
+            Some(transfer) => transfer.created_at_time = timestamp,
+            None => ic_cdk::trap(format!("transfer with id {transfer_id} not found")),
+        }
     }
 
-    pub fn record_icp_deposit(&mut self, receiver: Account, amount: ICP, block_index: u64) {
+    pub fn get_notified_deposit(
+        &self,
+        deposit_account: Account,
+        deposit_block_index: u64,
+    ) -> Option<DepositSuccess> {
+        self.notified_deposits
+            .get(&(deposit_account, deposit_block_index))
+            .map(|(_, deposit)| deposit.clone())
+    }
+
+    pub fn get_deduplicated_deposit(
+        &self,
+        account: Account,
+        created_at_time: u64,
+        amount: ICP,
+    ) -> Option<DepositSuccess> {
+        self.deduplicated_deposits
+            .get(&(account, created_at_time, amount))
+            .cloned()
+    }
+
+    /// Records a deposit of `amount` ICP pulled from `from`, or from `receiver` if not set,
+    /// minting nICP to `receiver`. The airdrop is attributed to the receiver.
+    /// Returns the id of the nICP transfer.
+    pub fn record_icp_deposit(
+        &mut self,
+        receiver: Account,
+        from: Option<Account>,
+        amount: ICP,
+        block_index: u64,
+        created_at_time: Option<u64>,
+        to_liquidity_buffer: bool,
+        timestamp: u64,
+    ) -> TransferId {
         let nicp_to_mint = self.convert_icp_to_nicp(amount);
         self.total_circulating_nicp += nicp_to_mint;
-        let rewards = compute_rewards(self.total_icp_deposited, amount);
+        let rewards = self.compute_airdrop_rewards(amount);
         if rewards > WTN::ZERO {
             self.airdrop
                 .entry(receiver.owner)
@@ -473,7 +1308,11 @@ impl State {
                 .or_insert(rewards);
         }
         self.total_icp_deposited += amount;
-        self.tracked_6m_stake += amount;
+        if to_liquidity_buffer {
+            self.liquidity_buffer += amount;
+        } else {
+            self.tracked_6m_stake += amount;
+        }
         let transfer_id = self.increment_transfer_id();
         assert_eq!(
             self.pending_transfers.insert(
@@ -484,7 +1323,8 @@ impl State {
                     amount: nicp_to_mint.0,
                     receiver,
                     unit: Unit::NICP,
-                    memo: Some(block_index)
+                    memo: Some(block_index),
+                    created_at_time: timestamp,
                 }
             ),
             None
@@ -493,13 +1333,67 @@ impl State {
             .entry(receiver)
             .and_modify(|deposits| deposits.push(transfer_id))
             .or_insert(vec![transfer_id]);
+
+        if let Some(created_at_time) = created_at_time {
+            // The ledger rejects transactions older than the deduplication window,
+            // past that point there is no retry left to answer.
+            self.deduplicated_deposits
+                .retain(|(_, deposit_created_at_time, _), _| {
+                    deposit_created_at_time.saturating_add(DEPOSIT_DEDUP_WINDOW_NANOS) >= timestamp
+                });
+            self.deduplicated_deposits.insert(
+                (from.unwrap_or(receiver), created_at_time, amount),
+                DepositSuccess {
+                    block_index: Nat::from(block_index),
+                    transfer_id,
+                    nicp_amount: Some(nicp_to_mint),
+                },
+            );
+        }
+        transfer_id
+    }
+
+    /// Records that the transfer at `deposit_block_index` to `deposit_account` was converted
+    /// by sweeping the deposit account at `block_index`, minting with `transfer_id`.
+    pub fn record_notified_deposit(
+        &mut self,
+        deposit_account: Account,
+        deposit_block_index: u64,
+        block_index: u64,
+        transfer_id: TransferId,
+        timestamp: u64,
+    ) {
+        let nicp_amount = self
+            .pending_transfers
+            .get(&transfer_id)
+            .map(|transfer| nICP::from_e8s(transfer.amount));
+        // Blocks older than a day are rejected before being notified, see `deposit_block_amount`.
+        self.notified_deposits.retain(|_, (notified_at, _)| {
+            notified_at.saturating_add(DEPOSIT_DEDUP_WINDOW_NANOS) >= timestamp
+        });
+        self.notified_deposits.insert(
+            (deposit_account, deposit_block_index),
+            (
+                timestamp,
+                DepositSuccess {
+                    block_index: Nat::from(block_index),
+                    transfer_id,
+                    nicp_amount,
+                },
+            ),
+        );
     }
 
     pub fn record_claimed_airdrop(&mut self, caller: Principal) {
         assert!(self.airdrop.remove(&caller).is_some());
     }
 
-    pub fn record_pending_neuron_transfer(&mut self, amount: u64, receiver: Account) {
+    pub fn record_pending_neuron_transfer(
+        &mut self,
+        amount: u64,
+        receiver: Account,
+        timestamp: u64,
+    ) {
         let transfer_id = self.increment_transfer_id();
         let unit = Unit::WTN;
         assert!(amount >= unit.fee());
@@ -513,7 +1407,8 @@ impl State {
                     amount,
                     receiver,
                     unit,
-                    memo: None
+                    memo: None,
+                    created_at_time: timestamp,
                 }
             ),
             None
@@ -526,7 +1421,8 @@ impl State {
         receiver: impl Into<Account>,
         amount: ICP,
         memo: Option<u64>,
-    ) {
+        timestamp: u64,
+    ) -> TransferId {
         let transfer_id = self.increment_transfer_id();
         let unit = Unit::ICP;
         assert!(amount.0 >= unit.fee());
@@ -540,11 +1436,13 @@ impl State {
                     amount: amount.0,
                     receiver: receiver.into(),
                     unit,
-                    memo
+                    memo,
+                    created_at_time: timestamp,
                 }
             ),
             None
         );
+        transfer_id
     }
 
     pub fn record_dispatch_icp_rewards(
@@ -568,11 +1466,30 @@ impl State {
             }
         }
 
+        let liquidity_buffer_amount =
+            self.compute_liquidity_buffer_rewards_share(neuron_6m_icp_amount);
+        if liquidity_buffer_amount > ICP::ZERO {
+            self.record_icp_pending_transfer(
+                from_neuron_type.to_subaccount(),
+                self.get_liquidity_buffer_account(),
+                liquidity_buffer_amount,
+                None,
+                timestamp,
+            );
+            self.liquidity_buffer += liquidity_buffer_amount
+                .checked_sub(ICP::from_e8s(DEFAULT_LEDGER_FEE))
+                .unwrap();
+        }
+        let neuron_6m_icp_amount = neuron_6m_icp_amount
+            .checked_sub(liquidity_buffer_amount)
+            .unwrap();
+
         self.record_icp_pending_transfer(
             from_neuron_type.to_subaccount(),
             self.get_6m_neuron_account(),
             neuron_6m_icp_amount,
             None,
+            timestamp,
         );
 
         self.tracked_6m_stake += neuron_6m_icp_amount
@@ -583,12 +1500,14 @@ impl State {
             self.get_sns_account(),
             sns_gov_amount,
             None,
+            timestamp,
         );
     }
 
     pub fn record_nicp_withdrawal(
         &mut self,
         receiver: Account,
+        beneficiary: Option<Account>,
         nicp_burned: nICP,
         nicp_burn_index: u64,
         timestamp: u64,
@@ -619,7 +1538,8 @@ impl State {
                     nicp_burn_index,
                     icp_due,
                     neuron_id: None,
-                    timestamp
+                    timestamp,
+                    beneficiary,
                 }
             ),
             None
@@ -628,9 +1548,43 @@ impl State {
         withdrawal_id
     }
 
-    pub fn record_neuron_split(&mut self, withdrawal_id: u64, neuron_id: NeuronId) {
-        assert!(self.withdrawal_to_split.remove(&withdrawal_id));
-        self.withdrawal_id_to_request
+    pub fn record_instant_withdrawal(
+        &mut self,
+        receiver: Account,
+        beneficiary: Option<Account>,
+        nicp_burned: nICP,
+        fee: ICP,
+        timestamp: u64,
+    ) -> TransferId {
+        let icp_due = self.convert_nicp_to_icp(nicp_burned);
+        let icp_to_transfer = icp_due
+            .checked_sub(fee)
+            .expect("bug: the instant withdrawal fee should be lower than the ICP due");
+        self.liquidity_buffer = self
+            .liquidity_buffer
+            .checked_sub(icp_to_transfer)
+            .expect("bug: the liquidity buffer should cover the instant withdrawal");
+        self.total_circulating_nicp = self
+            .total_circulating_nicp
+            .checked_sub(nicp_burned)
+            .unwrap_or_else(|| {
+                panic!(
+                    "bug: trying to burn more nicp {nicp_burned} than nicp tracked {}",
+                    self.total_circulating_nicp
+                )
+            });
+        self.record_icp_pending_transfer(
+            LIQUIDITY_BUFFER_SUBACCOUNT,
+            beneficiary.unwrap_or(receiver),
+            icp_to_transfer,
+            None,
+            timestamp,
+        )
+    }
+
+    pub fn record_neuron_split(&mut self, withdrawal_id: u64, neuron_id: NeuronId) {
+        assert!(self.withdrawal_to_split.remove(&withdrawal_id));
+        self.withdrawal_id_to_request
             .entry(withdrawal_id)
             .and_modify(|n| n.neuron_id = Some(neuron_id));
         assert!(self.withdrawal_to_start_dissolving.insert(withdrawal_id));
@@ -641,37 +1595,202 @@ impl State {
         );
     }
 
-    pub fn record_neuron_merge(&mut self, neuron_id: NeuronId) {
-        let withdrawal_id: &u64 = self.neuron_id_to_withdrawal_id.get(&neuron_id).unwrap();
+    pub fn record_neuron_batch_split(
+        &mut self,
+        withdrawal_ids: Vec<WithdrawalId>,
+        neuron_id: NeuronId,
+    ) {
+        for withdrawal_id in &withdrawal_ids {
+            assert!(self.withdrawal_to_split.remove(withdrawal_id));
+            self.withdrawal_id_to_request
+                .entry(*withdrawal_id)
+                .and_modify(|n| n.neuron_id = Some(neuron_id));
+            assert!(self.withdrawal_to_start_dissolving.insert(*withdrawal_id));
+        }
+        assert!(
+            self.batch_neuron_to_withdrawal_ids
+                .insert(neuron_id, withdrawal_ids)
+                .is_none()
+        );
+    }
+
+    /// Returns the nICP minted and the fee withheld when cancelling the withdrawal
+    /// associated to `neuron_id`.
+    pub fn quote_neuron_merge(&self, neuron_id: NeuronId) -> Option<(nICP, nICP)> {
+        let withdrawal_id = self.sole_withdrawal_of_neuron(neuron_id)?;
+        let icp_stake_e8s = self
+            .withdrawal_id_to_request
+            .get(&withdrawal_id)?
+            .icp_due
+            .checked_sub(NEURON_MERGE_ICP_FEE)?;
+        Some(self.compute_cancellation_nicp(icp_stake_e8s))
+    }
+
+    /// Returns the nICP minted and the fee withheld when merging `split_neuron_id`.
+    pub fn quote_partial_neuron_merge(&self, split_neuron_id: NeuronId) -> Option<(nICP, nICP)> {
+        let icp_stake_e8s = self
+            .pending_partial_merges
+            .get(&split_neuron_id)?
+            .icp_amount
+            .checked_sub(NEURON_SPLIT_AND_MERGE_ICP_FEE)?;
+        Some(self.compute_cancellation_nicp(icp_stake_e8s))
+    }
+
+    /// `minted` is the nICP minted and the fee withheld as recorded by the event, they
+    /// are computed from the current exchange rate for the events not recording them.
+    pub fn record_neuron_merge(
+        &mut self,
+        neuron_id: NeuronId,
+        minted: Option<(nICP, nICP)>,
+        timestamp: u64,
+    ) {
+        let withdrawal_id = self.sole_withdrawal_of_neuron(neuron_id).unwrap();
         assert!(
-            self.withdrawal_to_start_dissolving.remove(withdrawal_id)
-                || (self.withdrawal_to_disburse.remove(withdrawal_id)
+            self.withdrawal_to_start_dissolving.remove(&withdrawal_id)
+                || (self.withdrawal_to_disburse.remove(&withdrawal_id)
                     && self.to_disburse.remove(&neuron_id).is_some())
         );
 
         let withdrawal_request = self
             .withdrawal_id_to_request
-            .get(withdrawal_id)
+            .get(&withdrawal_id)
             .unwrap()
             .clone();
 
-        self.withdrawal_cancelled.insert(*withdrawal_id);
-        assert!(self.neuron_id_to_withdrawal_id.remove(&neuron_id).is_some());
+        self.withdrawal_cancelled.insert(withdrawal_id);
+        if self.neuron_id_to_withdrawal_id.remove(&neuron_id).is_none() {
+            assert!(
+                self.batch_neuron_to_withdrawal_ids
+                    .remove(&neuron_id)
+                    .is_some()
+            );
+        }
 
-        // Merging the neurons costs two times the ICP ledger transaction fee.
-        // Once to calculate the effects of merging two neurons (step 1).
-        // Once to operate the transaction of the source neuron stake to the target neuron (step 5).
-        // Here is the link to the according merge_neurons function used:
-        // https://github.com/dfinity/ic/blob/714c85c6a4245fb5b39e76f5c8003e6d90e49c4d/rs/nns/governance/src/governance.rs#L2780
         let icp_stake_e8s = withdrawal_request
             .icp_due
-            .checked_sub(ICP::from_e8s(2 * DEFAULT_LEDGER_FEE))
+            .checked_sub(NEURON_MERGE_ICP_FEE)
             .expect("ICP due should be greater than 10.");
+        let transfer_id = self.record_cancelled_stake(
+            withdrawal_request.receiver,
+            icp_stake_e8s,
+            minted,
+            timestamp,
+        );
+        self.cancellations.insert(
+            neuron_id,
+            Cancellation {
+                withdrawal_id,
+                transfer_id,
+                partial: false,
+                icp_fee: NEURON_MERGE_ICP_FEE,
+            },
+        );
+    }
+
+    /// Records the merge of `icp_amount` split out of the withdrawal neuron `neuron_id`,
+    /// the remaining withdrawal keeps its status.
+    /// The ICP split out of the withdrawal neuron are no longer due to the withdrawal, they are
+    /// tracked until the split neuron gets merged into the 6 months neuron.
+    /// A withdrawal of a batch neuron split out as a whole leaves the batch, cancelled.
+    pub fn record_partial_neuron_split(
+        &mut self,
+        neuron_id: NeuronId,
+        split_neuron_id: NeuronId,
+        icp_amount: ICP,
+        withdrawal_id: Option<WithdrawalId>,
+    ) {
+        let withdrawal_id = withdrawal_id.unwrap_or_else(|| {
+            self.sole_withdrawal_of_neuron(neuron_id)
+                .expect("bug: neuron id should be associated to a withdrawal")
+        });
+        let withdrawal_request = self
+            .withdrawal_id_to_request
+            .get_mut(&withdrawal_id)
+            .expect("bug: withdrawal id to request should be set");
+        let receiver = withdrawal_request.receiver;
+        let partial = withdrawal_request.icp_due != icp_amount;
+        if !partial {
+            let batch_withdrawal_ids = self
+                .batch_neuron_to_withdrawal_ids
+                .get_mut(&neuron_id)
+                .expect("bug: only a batched withdrawal can be split out as a whole");
+            batch_withdrawal_ids.retain(|id| *id != withdrawal_id);
+            assert!(!batch_withdrawal_ids.is_empty());
+            assert!(
+                self.withdrawal_to_start_dissolving.remove(&withdrawal_id)
+                    || self.withdrawal_to_disburse.remove(&withdrawal_id)
+            );
+            self.withdrawal_cancelled.insert(withdrawal_id);
+        } else {
+            withdrawal_request.icp_due = withdrawal_request
+                .icp_due
+                .checked_sub(icp_amount)
+                .expect("bug: cannot cancel more than the ICP due");
+        }
+        assert_eq!(
+            self.pending_partial_merges.insert(
+                split_neuron_id,
+                PendingPartialMerge {
+                    neuron_id,
+                    withdrawal_id,
+                    receiver,
+                    icp_amount,
+                    partial,
+                },
+            ),
+            None
+        );
+    }
+
+    pub fn record_partial_neuron_merge(
+        &mut self,
+        split_neuron_id: NeuronId,
+        minted: Option<(nICP, nICP)>,
+        timestamp: u64,
+    ) {
+        let pending_merge = self
+            .pending_partial_merges
+            .remove(&split_neuron_id)
+            .expect("bug: the merged neuron should have been split for a cancellation");
+
+        let icp_stake_e8s = pending_merge
+            .icp_amount
+            .checked_sub(NEURON_SPLIT_AND_MERGE_ICP_FEE)
+            .expect("bug: the cancelled amount should cover the fees");
+        let transfer_id =
+            self.record_cancelled_stake(pending_merge.receiver, icp_stake_e8s, minted, timestamp);
+        self.cancellations.insert(
+            split_neuron_id,
+            Cancellation {
+                withdrawal_id: pending_merge.withdrawal_id,
+                transfer_id,
+                partial: pending_merge.partial,
+                icp_fee: NEURON_SPLIT_AND_MERGE_ICP_FEE,
+            },
+        );
+    }
+
+    /// Returns the nICP minted for `icp_stake_e8s` staked back and the fee withheld.
+    fn compute_cancellation_nicp(&self, icp_stake_e8s: ICP) -> (nICP, nICP) {
         let nicp_stake_value_e8s = self.convert_icp_to_nicp(icp_stake_e8s);
 
         // 0.5% fee when a withdrawal is cancelled.
         let nicp_fee = nICP::from_e8s(nicp_stake_value_e8s.0.checked_div(200).unwrap());
         let nicp_to_mint = nicp_stake_value_e8s.checked_sub(nicp_fee).unwrap();
+        (nicp_to_mint, nicp_fee)
+    }
+
+    /// Stakes `icp_stake_e8s` back into the 6 months neuron, returns the id of the
+    /// transfer minting the nICP to `receiver`.
+    fn record_cancelled_stake(
+        &mut self,
+        receiver: Account,
+        icp_stake_e8s: ICP,
+        minted: Option<(nICP, nICP)>,
+        timestamp: u64,
+    ) -> TransferId {
+        let (nicp_to_mint, _nicp_fee) =
+            minted.unwrap_or_else(|| self.compute_cancellation_nicp(icp_stake_e8s));
         self.total_circulating_nicp += nicp_to_mint;
 
         self.tracked_6m_stake += icp_stake_e8s;
@@ -684,17 +1803,19 @@ impl State {
                     transfer_id,
                     from_subaccount: None,
                     amount: nicp_to_mint.0,
-                    receiver: withdrawal_request.receiver,
+                    receiver,
                     unit: Unit::NICP,
-                    memo: None
+                    memo: None,
+                    created_at_time: timestamp,
                 }
             ),
             None
         );
         self.account_to_deposits
-            .entry(withdrawal_request.receiver)
+            .entry(receiver)
             .and_modify(|deposits| deposits.push(transfer_id))
             .or_insert(vec![transfer_id]);
+        transfer_id
     }
 
     pub fn record_started_to_dissolve_neuron(&mut self, withdrawal_id: WithdrawalId) {
@@ -709,7 +1830,7 @@ impl State {
             self.to_disburse.insert(
                 neuron_id,
                 DisburseRequest {
-                    receiver: request.receiver,
+                    receiver: request.icp_receiver(),
                     neuron_id,
                 },
             ),
@@ -719,6 +1840,68 @@ impl State {
         assert!(self.withdrawal_to_disburse.insert(withdrawal_id));
     }
 
+    pub fn record_started_to_dissolve_batch_neuron(&mut self, neuron_id: NeuronId) {
+        let withdrawal_ids = self
+            .batch_neuron_to_withdrawal_ids
+            .get(&neuron_id)
+            .expect("bug: batch neuron should be tracked");
+        for withdrawal_id in withdrawal_ids {
+            assert!(self.withdrawal_to_start_dissolving.remove(withdrawal_id));
+            assert!(self.withdrawal_to_disburse.insert(*withdrawal_id));
+        }
+        assert_eq!(
+            self.to_disburse.insert(
+                neuron_id,
+                DisburseRequest {
+                    receiver: self.get_withdrawal_payouts_account(),
+                    neuron_id,
+                },
+            ),
+            None
+        );
+    }
+
+    /// Pays the disbursed ICP out to the batched withdrawals, pro rata of their ICP due.
+    /// The rounding dust stays in the payouts subaccount until it is swept.
+    pub fn record_batch_neuron_disbursed(&mut self, neuron_id: NeuronId, timestamp: u64) {
+        let withdrawal_ids = self
+            .batch_neuron_to_withdrawal_ids
+            .remove(&neuron_id)
+            .expect("bug: batch neuron should be tracked");
+        assert!(self.to_disburse.remove(&neuron_id).is_some());
+
+        let total_icp_due = self.compute_withdrawal_batch_icp_due(&withdrawal_ids);
+        // Splitting and disbursing the neuron both cost a ledger fee.
+        let disbursed = total_icp_due
+            .checked_sub(ICP::from_e8s(2 * DEFAULT_LEDGER_FEE))
+            .expect("bug: the batch ICP due should cover the fees");
+        let mut paid_out = 0;
+        for withdrawal_id in withdrawal_ids {
+            assert!(self.withdrawal_to_disburse.remove(&withdrawal_id));
+            let receiver = self.withdrawal_id_to_request[&withdrawal_id].icp_receiver();
+            let icp_due = self.withdrawal_id_to_request[&withdrawal_id].icp_due;
+            let amount = (disbursed.0 as u128 * icp_due.0 as u128 / total_icp_due.0 as u128) as u64;
+            paid_out += amount;
+            let transfer_id = self.record_icp_pending_transfer(
+                WITHDRAWAL_PAYOUTS_SUBACCOUNT,
+                receiver,
+                ICP::from_e8s(amount),
+                None,
+                timestamp,
+            );
+            self.withdrawal_payout_transfers
+                .insert(transfer_id, withdrawal_id);
+            self.withdrawal_id_to_payout_transfer
+                .insert(withdrawal_id, transfer_id);
+        }
+        self.record_unswept_funds(
+            Unit::ICP,
+            Some(WITHDRAWAL_PAYOUTS_SUBACCOUNT),
+            disbursed.0 - paid_out,
+            timestamp,
+        );
+    }
+
     pub fn record_neuron_disbursed(&mut self, withdrawal_id: WithdrawalId, block_index: u64) {
         assert!(self.withdrawal_to_disburse.remove(&withdrawal_id));
         let request = self
@@ -749,7 +1932,7 @@ impl State {
                 neuron_id,
                 DisburseRequest {
                     receiver: Account {
-                        owner: ic_cdk::api::canister_self(),
+                        owner: self_canister_id(),
                         subaccount: Some(neuron_kind.to_subaccount()),
                     },
                     neuron_id,
@@ -795,6 +1978,30 @@ impl State {
         }
     }
 
+    // Account where `principal` transfers ICP before calling `notify_icp_deposit`.
+    pub fn get_deposit_account(&self, principal: Principal) -> Account {
+        Account {
+            owner: self_canister_id(),
+            subaccount: Some(derive_deposit_subaccount(principal)),
+        }
+    }
+
+    // Account from which the instant withdrawals are paid.
+    pub fn get_liquidity_buffer_account(&self) -> Account {
+        Account {
+            owner: self_canister_id(),
+            subaccount: Some(LIQUIDITY_BUFFER_SUBACCOUNT),
+        }
+    }
+
+    // Account receiving the batch neurons stake, paid out to each withdrawal.
+    pub fn get_withdrawal_payouts_account(&self) -> Account {
+        Account {
+            owner: self_canister_id(),
+            subaccount: Some(WITHDRAWAL_PAYOUTS_SUBACCOUNT),
+        }
+    }
+
     pub fn is_equivalent_to(&self, other: &Self) -> Result<(), String> {
         use ic_utils_ensure::ensure_eq;
 
@@ -808,6 +2015,56 @@ impl State {
             other.total_circulating_nicp,
             "total_circulating_nicp do not match"
         );
+        ensure_eq!(
+            self.minimum_deposit_amount,
+            other.minimum_deposit_amount,
+            "minimum_deposit_amount do not match"
+        );
+        ensure_eq!(
+            self.minimum_withdrawal_amount,
+            other.minimum_withdrawal_amount,
+            "minimum_withdrawal_amount do not match"
+        );
+        ensure_eq!(
+            self.principal_rate_limit,
+            other.principal_rate_limit,
+            "principal_rate_limit do not match"
+        );
+        ensure_eq!(
+            self.global_rate_limit,
+            other.global_rate_limit,
+            "global_rate_limit do not match"
+        );
+        ensure_eq!(
+            self.max_reconciliation_drift_e8s,
+            other.max_reconciliation_drift_e8s,
+            "max_reconciliation_drift_e8s do not match"
+        );
+        ensure_eq!(
+            self.max_reconciliation_nicp_drift_e8s,
+            other.max_reconciliation_nicp_drift_e8s,
+            "max_reconciliation_nicp_drift_e8s do not match"
+        );
+        ensure_eq!(
+            self.protocol_mode,
+            other.protocol_mode,
+            "protocol_mode do not match"
+        );
+        ensure_eq!(
+            self.protocol_mode_reason,
+            other.protocol_mode_reason,
+            "protocol_mode_reason do not match"
+        );
+        ensure_eq!(
+            self.automatic_pause_reason,
+            other.automatic_pause_reason,
+            "automatic_pause_reason do not match"
+        );
+        ensure_eq!(
+            self.liquidity_buffer,
+            other.liquidity_buffer,
+            "liquidity_buffer do not match"
+        );
         ensure_eq!(
             self.transfer_id,
             other.transfer_id,
@@ -849,11 +2106,41 @@ impl State {
             other.to_disburse,
             "to_disburse do not match"
         );
+        ensure_eq!(
+            self.pending_partial_merges,
+            other.pending_partial_merges,
+            "pending_partial_merges do not match"
+        );
+        ensure_eq!(
+            self.cancellations,
+            other.cancellations,
+            "cancellations do not match"
+        );
         ensure_eq!(
             self.pending_transfers,
             other.pending_transfers,
             "pending_transfers do not match"
         );
+        ensure_eq!(
+            self.transfer_retries,
+            other.transfer_retries,
+            "transfer_retries do not match"
+        );
+        ensure_eq!(
+            self.dead_letter_transfers,
+            other.dead_letter_transfers,
+            "dead_letter_transfers do not match"
+        );
+        ensure_eq!(
+            self.rejected_transfers,
+            other.rejected_transfers,
+            "rejected_transfers do not match"
+        );
+        ensure_eq!(
+            self.unswept_rejected_funds,
+            other.unswept_rejected_funds,
+            "unswept_rejected_funds do not match"
+        );
         ensure_eq!(
             self.account_to_withdrawals,
             other.account_to_withdrawals,
@@ -865,6 +2152,36 @@ impl State {
             other.account_to_deposits,
             "account_to_deposits do not match"
         );
+        ensure_eq!(
+            self.deduplicated_deposits,
+            other.deduplicated_deposits,
+            "deduplicated_deposits do not match"
+        );
+        ensure_eq!(
+            self.notified_deposits,
+            other.notified_deposits,
+            "notified_deposits do not match"
+        );
+        ensure_eq!(
+            self.batch_neuron_to_withdrawal_ids,
+            other.batch_neuron_to_withdrawal_ids,
+            "batch_neuron_to_withdrawal_ids do not match"
+        );
+        ensure_eq!(
+            self.withdrawal_payout_transfers,
+            other.withdrawal_payout_transfers,
+            "withdrawal_payout_transfers do not match"
+        );
+        ensure_eq!(
+            self.withdrawal_id_to_payout_transfer,
+            other.withdrawal_id_to_payout_transfer,
+            "withdrawal_id_to_payout_transfer do not match"
+        );
+        ensure_eq!(
+            self.withdrawal_payout_rejected,
+            other.withdrawal_payout_rejected,
+            "withdrawal_payout_rejected do not match"
+        );
         ensure_eq!(
             self.neuron_id_6m,
             other.neuron_id_6m,
@@ -939,6 +2256,7 @@ pub mod test {
     use crate::state::{ICP_LEDGER_ID, NNS_GOVERNANCE_ID, State, WTN, WithdrawalStatus};
     use crate::{E8S, ICP, InitArg, NeuronId, NeuronOrigin, PendingTransfer, Unit, nICP};
     use candid::Principal;
+    use icrc_ledger_types::icrc1::account::Account;
     use std::str::FromStr;
 
     pub fn default_state() -> State {
@@ -1045,7 +2363,15 @@ pub mod test {
         let mut state = default_state();
 
         let caller = Principal::from_str("2chl6-4hpzw-vqaaa-aaaaa-c").unwrap();
-        state.record_icp_deposit(caller.into(), ICP::from_unscaled(80_001), 0);
+        state.record_icp_deposit(
+            caller.into(),
+            None,
+            ICP::from_unscaled(80_001),
+            0,
+            None,
+            false,
+            0,
+        );
         assert_eq!(
             state.pending_transfers.get(&0).unwrap(),
             &PendingTransfer {
@@ -1055,6 +2381,7 @@ pub mod test {
                 amount: 80_001 * E8S,
                 receiver: caller.into(),
                 unit: Unit::NICP,
+                created_at_time: 0,
             }
         );
         assert_eq!(
@@ -1065,6 +2392,664 @@ pub mod test {
         assert_eq!(state.airdrop.get(&caller), None);
     }
 
+    #[test]
+    fn should_deduplicate_deposits() {
+        use crate::{DEPOSIT_DEDUP_WINDOW_NANOS, DepositSuccess};
+        use candid::Nat;
+
+        let mut state = default_state();
+        let caller: Account = Principal::from_str("2chl6-4hpzw-vqaaa-aaaaa-c")
+            .unwrap()
+            .into();
+        let created_at_time = 1_000;
+
+        state.record_icp_deposit(
+            caller,
+            None,
+            ICP::ONE,
+            42,
+            Some(created_at_time),
+            false,
+            created_at_time,
+        );
+        state.record_icp_deposit(caller, None, ICP::TWO, 43, None, false, created_at_time);
+        assert_eq!(
+            state.get_deduplicated_deposit(caller, created_at_time, ICP::ONE),
+            Some(DepositSuccess {
+                block_index: Nat::from(42_u64),
+                transfer_id: 0,
+                nicp_amount: Some(nICP::ONE),
+            })
+        );
+        assert_eq!(
+            state.get_deduplicated_deposit(caller, created_at_time, ICP::TWO),
+            None
+        );
+
+        // Entries are dropped once the ledger deduplication window has passed.
+        let later = created_at_time + DEPOSIT_DEDUP_WINDOW_NANOS + 1;
+        state.record_icp_deposit(caller, None, ICP::ONE, 44, Some(later), false, later);
+        assert_eq!(
+            state.get_deduplicated_deposit(caller, created_at_time, ICP::ONE),
+            None
+        );
+        assert!(
+            state
+                .get_deduplicated_deposit(caller, later, ICP::ONE)
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn should_deposit_on_behalf_of_receiver() {
+        let mut state = default_state();
+        let payer: Account = Principal::from_str("2chl6-4hpzw-vqaaa-aaaaa-c")
+            .unwrap()
+            .into();
+        let receiver: Account = Principal::anonymous().into();
+        let created_at_time = 1_000;
+
+        state.record_icp_deposit(
+            receiver,
+            Some(payer),
+            ICP::from_unscaled(10),
+            42,
+            Some(created_at_time),
+            false,
+            created_at_time,
+        );
+        assert_eq!(state.pending_transfers.get(&0).unwrap().receiver, receiver);
+        assert_eq!(state.account_to_deposits.get(&receiver), Some(&vec![0]));
+        assert_eq!(state.account_to_deposits.get(&payer), None);
+        assert!(state.airdrop.contains_key(&receiver.owner));
+        assert!(!state.airdrop.contains_key(&payer.owner));
+        // Retries are deduplicated on the account the ICP were pulled from.
+        assert!(
+            state
+                .get_deduplicated_deposit(payer, created_at_time, ICP::from_unscaled(10))
+                .is_some()
+        );
+        assert!(
+            state
+                .get_deduplicated_deposit(receiver, created_at_time, ICP::from_unscaled(10))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn should_track_notified_deposits() {
+        use crate::state::audit::apply_state_transition;
+        use crate::state::event::EventType;
+        use crate::{DEPOSIT_DEDUP_WINDOW_NANOS, DepositSuccess};
+
+        let mut state = default_state();
+        let caller = Principal::from_str("2chl6-4hpzw-vqaaa-aaaaa-c").unwrap();
+        let deposit_account = state.get_deposit_account(caller);
+        assert_ne!(
+            deposit_account,
+            state.get_deposit_account(Principal::anonymous())
+        );
+
+        let deposit = |block_index, deposit_block_index| EventType::IcpDeposit {
+            receiver: caller.into(),
+            amount: ICP::from_unscaled(10),
+            block_index,
+            created_at_time: None,
+            to_liquidity_buffer: None,
+            from: Some(deposit_account),
+            deposit_block_index,
+        };
+        apply_state_transition(&mut state, &deposit(42, Some(7)), 0);
+        // The deposit is keyed by the transfer to the deposit account, not by the sweep.
+        assert_eq!(
+            state.get_notified_deposit(deposit_account, 7),
+            Some(DepositSuccess {
+                block_index: 42_u64.into(),
+                transfer_id: 0,
+                nicp_amount: Some(nICP::from_unscaled(10)),
+            })
+        );
+        assert_eq!(state.get_notified_deposit(deposit_account, 42), None);
+        assert_eq!(
+            state.get_notified_deposit(state.get_deposit_account(Principal::anonymous()), 7),
+            None
+        );
+
+        // Deposits pulled with an approval are not notified deposits.
+        apply_state_transition(&mut state, &deposit(43, None), 1);
+        assert_eq!(state.notified_deposits.len(), 1);
+
+        // The notified deposits are pruned once the deposit account has been swept for a day.
+        apply_state_transition(
+            &mut state,
+            &deposit(44, Some(8)),
+            DEPOSIT_DEDUP_WINDOW_NANOS + 1,
+        );
+        assert_eq!(state.get_notified_deposit(deposit_account, 7), None);
+        assert!(state.get_notified_deposit(deposit_account, 8).is_some());
+    }
+
+    #[test]
+    fn should_update_conversion_limits() {
+        use crate::conversion::{MINIMUM_DEPOSIT_AMOUNT, MINIMUM_WITHDRAWAL_AMOUNT};
+        use crate::{ConversionLimits, DEFAULT_LEDGER_FEE, UpgradeArg};
+
+        let mut state = default_state();
+        assert_eq!(state.minimum_deposit_amount, MINIMUM_DEPOSIT_AMOUNT);
+        assert_eq!(state.minimum_withdrawal_amount, MINIMUM_WITHDRAWAL_AMOUNT);
+
+        assert!(
+            ConversionLimits {
+                minimum_deposit_amount_e8s: Some(DEFAULT_LEDGER_FEE),
+                minimum_withdrawal_amount_e8s: None,
+            }
+            .validate()
+            .is_err()
+        );
+        assert!(
+            ConversionLimits {
+                minimum_deposit_amount_e8s: None,
+                minimum_withdrawal_amount_e8s: Some(E8S),
+            }
+            .validate()
+            .is_err()
+        );
+
+        let limits = ConversionLimits {
+            minimum_deposit_amount_e8s: Some(E8S / 2),
+            minimum_withdrawal_amount_e8s: None,
+        };
+        assert_eq!(limits.validate(), Ok(()));
+        state.record_conversion_limits(limits);
+        assert_eq!(state.minimum_deposit_amount, ICP::from_e8s(E8S / 2));
+        assert_eq!(state.minimum_withdrawal_amount, MINIMUM_WITHDRAWAL_AMOUNT);
+
+        state.record_upgrade(UpgradeArg {
+            conversion_limits: Some(ConversionLimits {
+                minimum_deposit_amount_e8s: None,
+                minimum_withdrawal_amount_e8s: Some(2 * E8S),
+            }),
+            ..Default::default()
+        });
+        assert_eq!(state.minimum_deposit_amount, ICP::from_e8s(E8S / 2));
+        assert_eq!(state.minimum_withdrawal_amount, ICP::from_unscaled(2));
+    }
+
+    #[test]
+    fn should_track_protocol_mode() {
+        use crate::{ProtocolMode, SetProtocolModeArg};
+
+        let mut state = default_state();
+        assert_eq!(state.protocol_mode, ProtocolMode::Normal);
+        assert!(state.protocol_mode.deposits_allowed());
+        assert!(state.protocol_mode.withdrawals_allowed());
+
+        state.record_protocol_mode_changed(ProtocolMode::DepositsPaused, None);
+        assert!(!state.protocol_mode.deposits_allowed());
+        assert!(state.protocol_mode.withdrawals_allowed());
+        assert_eq!(
+            state.protocol_pause_reason(),
+            "the protocol mode is Deposits Paused"
+        );
+        assert_eq!(
+            state.effective_protocol_mode(),
+            ProtocolMode::DepositsPaused
+        );
+
+        // The automatic pause applies on top of the mode set by governance.
+        state.record_protocol_paused("drift".to_string());
+        assert_eq!(state.effective_protocol_mode(), ProtocolMode::FullyPaused);
+        assert!(!state.effective_protocol_mode().withdrawals_allowed());
+        assert_eq!(state.protocol_pause_reason(), "drift");
+        assert_eq!(state.protocol_mode, ProtocolMode::DepositsPaused);
+
+        // Resuming restores the mode set by governance.
+        state.record_protocol_resumed();
+        assert_eq!(
+            state.effective_protocol_mode(),
+            ProtocolMode::DepositsPaused
+        );
+        assert!(!state.effective_protocol_mode().deposits_allowed());
+        assert!(state.effective_protocol_mode().withdrawals_allowed());
+        assert_eq!(state.automatic_pause_reason, None);
+
+        assert!(
+            SetProtocolModeArg {
+                mode: ProtocolMode::FullyPaused,
+                reason: Some("x".repeat(257)),
+            }
+            .validate()
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn should_retry_and_dead_letter_transfers() {
+        use crate::state::{SNS_GOVERNANCE_SUBACCOUNT, TransferStatus};
+        use crate::{
+            DEFAULT_LEDGER_FEE, MAX_TRANSFER_ATTEMPTS, ONE_DAY, ONE_HOUR, RETRY_DELAY,
+            RequeueTransferArg, TRANSFER_DEDUP_WINDOW, is_maybe_executed, is_retryable,
+        };
+        use candid::Nat;
+        use icrc_ledger_types::icrc1::transfer::TransferError;
+
+        let mut state = default_state();
+        let receiver = Account {
+            owner: Principal::anonymous(),
+            subaccount: None,
+        };
+        let transfer_id = state.record_icp_pending_transfer(
+            SNS_GOVERNANCE_SUBACCOUNT,
+            receiver,
+            ICP::ONE,
+            None,
+            0,
+        );
+        assert_eq!(state.next_transfer_attempt_at(transfer_id), 0);
+        assert_eq!(state.next_transfer_attempt_delay(0), Some(RETRY_DELAY));
+
+        let retry_delay_nanos = RETRY_DELAY.as_nanos() as u64;
+        state.record_transfer_failed(transfer_id, "unavailable".to_string(), false, 0);
+        assert_eq!(
+            state.next_transfer_attempt_at(transfer_id),
+            retry_delay_nanos
+        );
+        state.record_transfer_failed(transfer_id, "unavailable".to_string(), false, 100);
+        assert_eq!(state.transfer_attempts(transfer_id), 2);
+        assert_eq!(
+            state.next_transfer_attempt_at(transfer_id),
+            100 + 2 * retry_delay_nanos
+        );
+        for _ in 2..MAX_TRANSFER_ATTEMPTS {
+            state.record_transfer_failed(transfer_id, "unavailable".to_string(), false, 0);
+        }
+        // The last attempt is made while the ledger still reports duplicates.
+        let last_attempt_in_window = (TRANSFER_DEDUP_WINDOW - ONE_HOUR).as_nanos() as u64;
+        assert_eq!(
+            state.next_transfer_attempt_at(transfer_id),
+            last_attempt_in_window
+        );
+        state.record_transfer_failed(
+            transfer_id,
+            "unavailable".to_string(),
+            false,
+            last_attempt_in_window,
+        );
+        assert_eq!(
+            state.next_transfer_attempt_at(transfer_id),
+            last_attempt_in_window + ONE_DAY.as_nanos() as u64
+        );
+        let transfer = &state.pending_transfers[&transfer_id];
+        assert!(!transfer.is_out_of_dedup_window(last_attempt_in_window));
+        assert!(transfer.is_out_of_dedup_window(TRANSFER_DEDUP_WINDOW.as_nanos() as u64));
+
+        // A transfer that was provably not executed may get a new created_at_time.
+        let now = 2 * ONE_DAY.as_nanos() as u64;
+        state.record_transfer_restamped(transfer_id, now);
+        assert_eq!(state.pending_transfers[&transfer_id].created_at_time, now);
+
+        assert!(is_retryable(&TransferError::TemporarilyUnavailable));
+        assert!(is_retryable(&TransferError::TooOld));
+        assert!(!is_retryable(&TransferError::BadFee {
+            expected_fee: Nat::from(DEFAULT_LEDGER_FEE),
+        }));
+        let generic_error = TransferError::GenericError {
+            error_code: Nat::from(0_u8),
+            message: "reply lost".to_string(),
+        };
+        let insufficient_funds = TransferError::InsufficientFunds {
+            balance: Nat::from(0_u8),
+        };
+        assert!(is_maybe_executed(false, &generic_error));
+        assert!(is_maybe_executed(true, &TransferError::TooOld));
+        assert!(!is_maybe_executed(true, &insufficient_funds));
+
+        let arg = RequeueTransferArg {
+            transfer_id,
+            receiver: Some(Principal::management_canister().into()),
+            verified_not_executed: false,
+        };
+        assert!(arg.validate(&state, now).is_err());
+
+        state.record_transfer_failed(transfer_id, generic_error.to_string(), true, now);
+        assert!(state.is_transfer_maybe_executed(transfer_id));
+        state.record_transfer_dead_lettered(transfer_id, "bad fee".to_string(), true, now + 42);
+        assert!(state.pending_transfers.is_empty());
+        assert!(state.transfer_retries.is_empty());
+        assert_eq!(state.next_transfer_attempt_delay(0), None);
+        assert_eq!(state.unsettled_transfers().count(), 1);
+        match state.get_transfer_status(transfer_id) {
+            TransferStatus::DeadLettered(dead_letter) => {
+                assert_eq!(dead_letter.attempts, MAX_TRANSFER_ATTEMPTS + 3);
+                assert_eq!(dead_letter.error, "bad fee");
+                assert_eq!(dead_letter.timestamp, now + 42);
+                assert!(dead_letter.maybe_executed);
+            }
+            status => panic!("unexpected transfer status {status:?}"),
+        }
+
+        assert_eq!(arg.validate(&state, now), Ok(()));
+        assert!(
+            RequeueTransferArg {
+                transfer_id,
+                receiver: Some(NNS_GOVERNANCE_ID.into()),
+                verified_not_executed: false,
+            }
+            .validate(&state, now)
+            .is_err()
+        );
+        // Out of the deduplication window, a transfer that may have been executed
+        // is only requeued once governance checked the ledger.
+        let later = now + ONE_DAY.as_nanos() as u64;
+        assert!(arg.validate(&state, later).is_err());
+        assert_eq!(
+            RequeueTransferArg {
+                verified_not_executed: true,
+                ..arg.clone()
+            }
+            .validate(&state, later),
+            Ok(())
+        );
+
+        // Requeued without checking the ledger, the transfer keeps its created_at_time.
+        state.record_transfer_requeued(transfer_id, arg.receiver, false, now + 43);
+        assert!(state.dead_letter_transfers.is_empty());
+        assert_eq!(state.transfer_attempts(transfer_id), 0);
+        assert!(state.is_transfer_maybe_executed(transfer_id));
+        let transfer = state.pending_transfers.get(&transfer_id).unwrap();
+        assert_eq!(transfer.receiver, Principal::management_canister().into());
+        assert_eq!(transfer.created_at_time, now);
+
+        state.record_transfer_dead_lettered(transfer_id, "too old".to_string(), true, later);
+        state.record_transfer_requeued(transfer_id, None, true, later + 1);
+        assert!(!state.is_transfer_maybe_executed(transfer_id));
+        assert_eq!(
+            state.pending_transfers[&transfer_id].created_at_time,
+            later + 1
+        );
+    }
+
+    #[test]
+    fn should_fail_and_requeue_rejected_withdrawal_payouts() {
+        use crate::state::audit::apply_state_transition;
+        use crate::state::event::EventType;
+        use crate::state::{REJECTED_TRANSFERS_SUBACCOUNT, WITHDRAWAL_PAYOUTS_SUBACCOUNT};
+        use crate::{DEFAULT_LEDGER_FEE, RequeueWithdrawalPayoutArg};
+
+        let mut state = default_state();
+        let withdrawal_id = 3;
+        let rejected_id = state.record_icp_pending_transfer(
+            WITHDRAWAL_PAYOUTS_SUBACCOUNT,
+            NNS_GOVERNANCE_ID,
+            ICP::ONE,
+            None,
+            0,
+        );
+        state
+            .withdrawal_payout_transfers
+            .insert(rejected_id, withdrawal_id);
+        let reason = state.pending_transfers[&rejected_id]
+            .rejection_reason()
+            .expect("the NNS governance cannot receive transfers");
+        state.record_transfer_rejected(rejected_id, reason.clone(), 1);
+        assert_eq!(
+            state.get_withdrawal_status(withdrawal_id),
+            WithdrawalStatus::PayoutFailed {
+                transfer_id: rejected_id,
+                reason,
+            }
+        );
+
+        let receiver: Account = Principal::anonymous().into();
+        assert!(
+            RequeueWithdrawalPayoutArg {
+                withdrawal_id,
+                receiver: NNS_GOVERNANCE_ID.into(),
+            }
+            .validate(&state)
+            .is_err()
+        );
+        assert!(
+            RequeueWithdrawalPayoutArg {
+                withdrawal_id: withdrawal_id + 1,
+                receiver,
+            }
+            .validate(&state)
+            .is_err()
+        );
+        let arg = RequeueWithdrawalPayoutArg {
+            withdrawal_id,
+            receiver,
+        };
+        assert_eq!(arg.validate(&state), Ok(()));
+
+        apply_state_transition(
+            &mut state,
+            &EventType::WithdrawalPayoutRequeued {
+                withdrawal_id,
+                receiver,
+            },
+            2,
+        );
+        let payout_id = state.transfer_id - 1;
+        assert_eq!(
+            state.get_withdrawal_status(withdrawal_id),
+            WithdrawalStatus::WaitingTransfer {
+                transfer_id: payout_id
+            }
+        );
+        let payout = &state.pending_transfers[&payout_id];
+        assert_eq!(payout.from_subaccount, Some(REJECTED_TRANSFERS_SUBACCOUNT));
+        assert_eq!(payout.receiver, receiver);
+        assert_eq!(payout.amount, ICP::ONE.0 - DEFAULT_LEDGER_FEE);
+        assert!(arg.validate(&state).is_err());
+
+        state.record_transfer_executed(payout_id, Some(9), 3);
+        assert_eq!(
+            state.get_withdrawal_status(withdrawal_id),
+            WithdrawalStatus::ConversionDone {
+                transfer_block_height: 9
+            }
+        );
+    }
+
+    #[test]
+    fn should_reject_transfers_and_sweep_dust() {
+        use crate::state::{
+            REJECTED_TRANSFERS_SUBACCOUNT, TransferStatus, WITHDRAWAL_PAYOUTS_SUBACCOUNT,
+        };
+        use crate::{DEFAULT_LEDGER_FEE, self_canister_id};
+
+        let mut state = default_state();
+        let receiver: Account = Principal::anonymous().into();
+        let dust = ICP::from_e8s(DEFAULT_LEDGER_FEE * 3 / 5);
+        let first = state.record_icp_pending_transfer(
+            WITHDRAWAL_PAYOUTS_SUBACCOUNT,
+            receiver,
+            dust,
+            None,
+            0,
+        );
+        let second = state.record_icp_pending_transfer(
+            WITHDRAWAL_PAYOUTS_SUBACCOUNT,
+            receiver,
+            dust,
+            None,
+            0,
+        );
+        let reason = state.pending_transfers[&first]
+            .rejection_reason()
+            .expect("the amount does not cover the fee");
+
+        state.record_transfer_rejected(first, reason.clone(), 1);
+        assert_eq!(
+            state.get_transfer_status(first),
+            TransferStatus::Rejected {
+                reason: reason.clone()
+            }
+        );
+        assert!(state.pending_transfers.contains_key(&second));
+        assert_eq!(state.pending_transfers.len(), 1);
+        assert_eq!(
+            state.unswept_rejected_funds[&(Unit::ICP, Some(WITHDRAWAL_PAYOUTS_SUBACCOUNT))],
+            dust.0
+        );
+
+        // Once the dust covers the fee, it is swept to the rejected transfers subaccount.
+        state.record_transfer_rejected(second, reason, 2);
+        assert!(state.unswept_rejected_funds.is_empty());
+        let sweep = state.pending_transfers.values().next().unwrap();
+        assert_eq!(sweep.amount, 2 * dust.0);
+        assert_eq!(sweep.from_subaccount, Some(WITHDRAWAL_PAYOUTS_SUBACCOUNT));
+        assert_eq!(
+            sweep.receiver,
+            Account {
+                owner: self_canister_id(),
+                subaccount: Some(REJECTED_TRANSFERS_SUBACCOUNT),
+            }
+        );
+        assert_eq!(sweep.created_at_time, 2);
+        assert_eq!(sweep.rejection_reason(), None);
+
+        // A rejected mint takes its nICP out of circulation.
+        let total_circulating_nicp = state.total_circulating_nicp;
+        state.record_icp_deposit(NNS_GOVERNANCE_ID.into(), None, ICP::ONE, 0, None, false, 3);
+        let mint_id = state.transfer_id - 1;
+        let reason = state.pending_transfers[&mint_id]
+            .rejection_reason()
+            .expect("the NNS governance cannot receive transfers");
+        state.record_transfer_rejected(mint_id, reason, 4);
+        assert_eq!(state.total_circulating_nicp, total_circulating_nicp);
+        assert!(state.unswept_rejected_funds.is_empty());
+        assert_eq!(state.rejected_transfers.len(), 3);
+    }
+
+    #[test]
+    fn should_quote_conversions() {
+        use crate::sns_distribution::compute_rewards;
+        use crate::{MIN_DISSOLVE_DELAY_FOR_REWARDS, SEC_NANOS};
+
+        let mut state = default_state();
+        let caller = Principal::from_str("2chl6-4hpzw-vqaaa-aaaaa-c").unwrap();
+        let now = 1_000 * SEC_NANOS;
+
+        let quote = state.quote_icp_to_nicp(ICP::from_unscaled(100), now);
+        assert_eq!(quote.nicp_amount, nICP::from_unscaled(100));
+        assert_eq!(
+            quote.airdrop_amount,
+            compute_rewards(ICP::ZERO, ICP::from_unscaled(100))
+        );
+        assert_eq!(quote.timestamp, now);
+        assert!(!quote.to_liquidity_buffer);
+
+        state.record_icp_deposit(
+            caller.into(),
+            None,
+            ICP::from_unscaled(100),
+            0,
+            None,
+            false,
+            0,
+        );
+        assert_eq!(
+            state.airdrop.get(&caller).copied(),
+            Some(quote.airdrop_amount)
+        );
+
+        let quote = state.quote_nicp_to_icp(nICP::from_unscaled(10), now);
+        assert_eq!(quote.icp_amount, ICP::from_unscaled(10));
+        assert_eq!(quote.instant_withdrawal_fee, None);
+        assert_eq!(
+            quote.estimated_completion_timestamp_secs,
+            1_000 + MIN_DISSOLVE_DELAY_FOR_REWARDS
+        );
+
+        // With batching, a withdrawal waits for the end of the current epoch.
+        state.withdrawal_epoch_seconds = 100;
+        state.record_nicp_withdrawal(caller.into(), None, nICP::from_unscaled(10), 1, now);
+        let quote = state.quote_nicp_to_icp(nICP::from_unscaled(10), now + 10 * SEC_NANOS);
+        assert_eq!(
+            quote.estimated_completion_timestamp_secs,
+            1_100 + MIN_DISSOLVE_DELAY_FOR_REWARDS
+        );
+    }
+
+    #[test]
+    fn instant_withdrawal_flow() {
+        use crate::state::LIQUIDITY_BUFFER_SUBACCOUNT;
+
+        let mut state = default_state();
+        let caller: Account = Principal::from_str("2chl6-4hpzw-vqaaa-aaaaa-c")
+            .unwrap()
+            .into();
+        state.liquidity_buffer_target = ICP::from_unscaled(100);
+        state.liquidity_buffer_rewards_share_percent = 50;
+
+        assert!(state.should_deposit_to_liquidity_buffer(ICP::from_unscaled(100)));
+        assert!(!state.should_deposit_to_liquidity_buffer(ICP::from_unscaled(101)));
+        state.record_icp_deposit(caller, None, ICP::from_unscaled(100), 0, None, true, 0);
+        assert_eq!(state.liquidity_buffer, ICP::from_unscaled(100));
+        assert_eq!(state.tracked_6m_stake, ICP::ZERO);
+
+        // The buffer is full, all the rewards go to the 6m neuron.
+        assert_eq!(
+            state.compute_liquidity_buffer_rewards_share(ICP::from_unscaled(10)),
+            ICP::ZERO
+        );
+
+        // Withdrawing 10 ICP leaves the buffer at 90% of its target: 30 + 270 * 10% = 57 bps.
+        let fee = state
+            .compute_instant_withdrawal_fee(ICP::from_unscaled(10))
+            .unwrap();
+        assert_eq!(fee, ICP::from_e8s(5_700_000));
+        let transfer_id =
+            state.record_instant_withdrawal(caller, None, nICP::from_unscaled(10), fee, 0);
+        assert_eq!(
+            state.pending_transfers.get(&transfer_id).unwrap(),
+            &PendingTransfer {
+                transfer_id: 1,
+                from_subaccount: Some(LIQUIDITY_BUFFER_SUBACCOUNT),
+                memo: None,
+                amount: 994_300_000,
+                receiver: caller,
+                unit: Unit::ICP,
+                created_at_time: 0,
+            }
+        );
+        assert_eq!(state.liquidity_buffer, ICP::from_e8s(9_005_700_000));
+        assert_eq!(state.total_circulating_nicp, nICP::from_unscaled(90));
+
+        // The buffer cannot pay out more than it holds.
+        assert_eq!(
+            state.compute_instant_withdrawal_fee(ICP::from_unscaled(91)),
+            None
+        );
+
+        // Nor the part reserved by the withdrawals burning their nICP.
+        state
+            .liquidity_buffer_reservations
+            .insert(Principal::anonymous(), ICP::from_unscaled(50));
+        assert_eq!(
+            state.available_liquidity_buffer(),
+            ICP::from_e8s(4_005_700_000)
+        );
+        assert_eq!(
+            state.compute_instant_withdrawal_fee(ICP::from_unscaled(41)),
+            None
+        );
+        assert!(
+            state
+                .compute_instant_withdrawal_fee(ICP::from_unscaled(40))
+                .is_some()
+        );
+        state.liquidity_buffer_reservations.clear();
+
+        assert_eq!(
+            state.compute_liquidity_buffer_rewards_share(ICP::from_unscaled(10)),
+            ICP::from_unscaled(5)
+        );
+    }
+
     #[test]
     fn withdrawal_flow() {
         let mut state = default_state();
@@ -1072,12 +3057,20 @@ pub mod test {
         let withdrawal_id = 0_u64;
         let neuron_id = NeuronId { id: 0 };
 
-        state.record_icp_deposit(caller.into(), ICP::from_unscaled(10), 0_64);
+        state.record_icp_deposit(
+            caller.into(),
+            None,
+            ICP::from_unscaled(10),
+            0_64,
+            None,
+            false,
+            0,
+        );
         assert_eq!(
             state.get_withdrawal_status(withdrawal_id),
             WithdrawalStatus::NotFound
         );
-        state.record_nicp_withdrawal(caller.into(), nICP::from_unscaled(5), 1, 0);
+        state.record_nicp_withdrawal(caller.into(), None, nICP::from_unscaled(5), 1, 0);
         assert_eq!(
             state.get_withdrawal_status(withdrawal_id),
             WithdrawalStatus::WaitingToSplitNeuron
@@ -1101,6 +3094,239 @@ pub mod test {
         );
     }
 
+    #[test]
+    fn should_pay_withdrawals_to_beneficiary() {
+        let mut state = default_state();
+        let caller: Account = Principal::from_str("2chl6-4hpzw-vqaaa-aaaaa-c")
+            .unwrap()
+            .into();
+        let beneficiary = Account {
+            owner: caller.owner,
+            subaccount: Some([1; 32]),
+        };
+        let neuron_id = NeuronId { id: 0 };
+
+        state.record_icp_deposit(caller, None, ICP::from_unscaled(100), 0, None, false, 0);
+        let withdrawal_id =
+            state.record_nicp_withdrawal(caller, Some(beneficiary), nICP::from_unscaled(10), 1, 0);
+        assert_eq!(state.account_to_withdrawals.get(&caller), Some(&vec![0]));
+        state.record_neuron_split(withdrawal_id, neuron_id);
+        state.record_started_to_dissolve_neuron(withdrawal_id);
+        assert_eq!(
+            state.to_disburse.get(&neuron_id).unwrap().receiver,
+            beneficiary
+        );
+    }
+
+    #[test]
+    fn partial_cancel_withdrawal_flow() {
+        let mut state = default_state();
+        let caller: Account = Principal::from_str("2chl6-4hpzw-vqaaa-aaaaa-c")
+            .unwrap()
+            .into();
+        let withdrawal_id = 0_u64;
+        let neuron_id = NeuronId { id: 0 };
+
+        state.record_icp_deposit(caller, None, ICP::from_unscaled(100), 0, None, false, 0);
+        state.record_nicp_withdrawal(caller, None, nICP::from_unscaled(50), 1, 0);
+        state.record_neuron_split(withdrawal_id, neuron_id);
+
+        let split_neuron_id = NeuronId { id: 1 };
+        let tracked_6m_stake = state.tracked_6m_stake;
+        state.record_partial_neuron_split(neuron_id, split_neuron_id, ICP::from_unscaled(20), None);
+        assert_eq!(
+            state.get_withdrawal_request(withdrawal_id).unwrap().icp_due,
+            ICP::from_unscaled(30)
+        );
+        // Nothing is minted until the split neuron gets merged.
+        assert_eq!(state.tracked_6m_stake, tracked_6m_stake);
+        assert_eq!(state.pending_transfers.len(), 1);
+        assert_eq!(
+            state.pending_partial_merges[&split_neuron_id].receiver,
+            caller
+        );
+
+        state.record_partial_neuron_merge(split_neuron_id, None, 0);
+        assert!(state.pending_partial_merges.is_empty());
+        assert_eq!(
+            state.get_withdrawal_status(withdrawal_id),
+            WithdrawalStatus::WaitingToStartDissolving { neuron_id }
+        );
+        // 20 ICP minus the split and merge fees, minted minus the 0.5% cancellation fee.
+        assert_eq!(state.tracked_6m_stake, ICP::from_e8s(6_999_970_000));
+        assert_eq!(
+            state.pending_transfers.get(&1).unwrap(),
+            &PendingTransfer {
+                transfer_id: 1,
+                from_subaccount: None,
+                memo: None,
+                amount: 1_989_970_150,
+                receiver: caller,
+                unit: Unit::NICP,
+                created_at_time: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn batch_withdrawal_flow() {
+        use crate::SEC_NANOS;
+        use crate::state::WITHDRAWAL_PAYOUTS_SUBACCOUNT;
+
+        let mut state = default_state();
+        let caller: Account = Principal::from_str("2chl6-4hpzw-vqaaa-aaaaa-c")
+            .unwrap()
+            .into();
+        let neuron_id = NeuronId { id: 0 };
+        state.withdrawal_epoch_seconds = 3_600;
+
+        state.record_icp_deposit(caller, None, ICP::from_unscaled(100), 0, None, false, 0);
+        state.record_nicp_withdrawal(caller, None, nICP::from_unscaled(10), 1, 0);
+        state.record_nicp_withdrawal(caller, None, nICP::from_unscaled(30), 2, SEC_NANOS);
+
+        let epoch_end = 3_600 * SEC_NANOS;
+        assert_eq!(state.get_withdrawal_batch_to_split(epoch_end - 1), None);
+        let withdrawal_ids = state.get_withdrawal_batch_to_split(epoch_end).unwrap();
+        assert_eq!(withdrawal_ids, vec![0, 1]);
+        assert_eq!(
+            state.compute_withdrawal_batch_icp_due(&withdrawal_ids),
+            ICP::from_unscaled(40)
+        );
+
+        state.record_neuron_batch_split(withdrawal_ids, neuron_id);
+        assert_eq!(
+            state.get_withdrawal_request_ids_to_dissolve(),
+            vec![neuron_id]
+        );
+        for withdrawal_id in [0, 1] {
+            assert_eq!(
+                state.get_withdrawal_status(withdrawal_id),
+                WithdrawalStatus::WaitingToStartDissolving { neuron_id }
+            );
+        }
+
+        state.record_started_to_dissolve_batch_neuron(neuron_id);
+        assert_eq!(
+            state.to_disburse.get(&neuron_id).unwrap().receiver,
+            state.get_withdrawal_payouts_account()
+        );
+        assert_eq!(
+            state.get_withdrawal_status(1),
+            WithdrawalStatus::WaitingDissolvement { neuron_id }
+        );
+
+        // 40 ICP minus the split and disburse fees, paid out pro rata.
+        state.record_batch_neuron_disbursed(neuron_id, 0);
+        assert_eq!(
+            state.get_withdrawal_status(0),
+            WithdrawalStatus::WaitingTransfer { transfer_id: 1 }
+        );
+        assert_eq!(
+            state.pending_transfers.get(&2).unwrap(),
+            &PendingTransfer {
+                transfer_id: 2,
+                from_subaccount: Some(WITHDRAWAL_PAYOUTS_SUBACCOUNT),
+                memo: None,
+                amount: 2_999_985_000,
+                receiver: caller,
+                unit: Unit::ICP,
+                created_at_time: 0,
+            }
+        );
+        assert_eq!(state.pending_transfers.get(&1).unwrap().amount, 999_995_000);
+
+        state.record_transfer_executed(1, Some(7), 0);
+        assert_eq!(
+            state.get_withdrawal_status(0),
+            WithdrawalStatus::ConversionDone {
+                transfer_block_height: 7
+            }
+        );
+        assert_eq!(
+            state.get_withdrawal_status(1),
+            WithdrawalStatus::WaitingTransfer { transfer_id: 2 }
+        );
+    }
+
+    #[test]
+    fn should_cancel_batched_withdrawals() {
+        use crate::state::{
+            Cancellation, NEURON_SPLIT_AND_MERGE_ICP_FEE, WITHDRAWAL_PAYOUTS_SUBACCOUNT,
+        };
+
+        let mut state = default_state();
+        let caller: Account = Principal::from_str("2chl6-4hpzw-vqaaa-aaaaa-c")
+            .unwrap()
+            .into();
+        let neuron_id = NeuronId { id: 0 };
+        let split_neuron_id = NeuronId { id: 1 };
+        state.withdrawal_epoch_seconds = 3_600;
+
+        state.record_icp_deposit(caller, None, ICP::from_unscaled(100), 0, None, false, 0);
+        for (amount, burn_index) in [(10, 1), (10, 2), (13, 3)] {
+            state.record_nicp_withdrawal(caller, None, nICP::from_unscaled(amount), burn_index, 0);
+        }
+        state.record_neuron_batch_split(vec![0, 1, 2], neuron_id);
+        assert_eq!(state.sole_withdrawal_of_neuron(neuron_id), None);
+
+        // The whole withdrawal is split out of the batch neuron, the others keep dissolving.
+        state.record_partial_neuron_split(
+            neuron_id,
+            split_neuron_id,
+            ICP::from_unscaled(10),
+            Some(1),
+        );
+        assert_eq!(state.get_withdrawal_status(1), WithdrawalStatus::Cancelled);
+        assert_eq!(state.batch_neuron_to_withdrawal_ids[&neuron_id], vec![0, 2]);
+        state.record_partial_neuron_merge(split_neuron_id, None, 0);
+        assert_eq!(
+            state.cancellations[&split_neuron_id],
+            Cancellation {
+                withdrawal_id: 1,
+                transfer_id: 1,
+                partial: false,
+                icp_fee: NEURON_SPLIT_AND_MERGE_ICP_FEE,
+            }
+        );
+
+        // 23 ICP minus the split and disburse fees, the rounding dust stays unswept.
+        state.record_started_to_dissolve_batch_neuron(neuron_id);
+        state.record_batch_neuron_disbursed(neuron_id, 0);
+        assert_eq!(state.pending_transfers[&2].amount, 999_991_304);
+        assert_eq!(state.pending_transfers[&3].amount, 1_299_988_695);
+        assert_eq!(
+            state.unswept_rejected_funds[&(Unit::ICP, Some(WITHDRAWAL_PAYOUTS_SUBACCOUNT))],
+            1
+        );
+        assert_eq!(
+            state.get_withdrawal_status(2),
+            WithdrawalStatus::WaitingTransfer { transfer_id: 3 }
+        );
+        state.record_transfer_executed(3, Some(8), 0);
+        assert_eq!(
+            state.get_withdrawal_status(2),
+            WithdrawalStatus::ConversionDone {
+                transfer_block_height: 8
+            }
+        );
+        assert_eq!(state.withdrawal_id_to_payout_transfer.get(&2), None);
+
+        // A batch neuron holding a single withdrawal is merged as a whole.
+        let single_neuron_id = NeuronId { id: 2 };
+        state.record_nicp_withdrawal(caller, None, nICP::from_unscaled(10), 4, 0);
+        state.record_neuron_batch_split(vec![3], single_neuron_id);
+        assert_eq!(state.sole_withdrawal_of_neuron(single_neuron_id), Some(3));
+        let minted = state.quote_neuron_merge(single_neuron_id);
+        assert!(minted.is_some());
+        state.record_neuron_merge(single_neuron_id, minted, 0);
+        assert_eq!(state.get_withdrawal_status(3), WithdrawalStatus::Cancelled);
+        assert!(!state.is_batch_neuron(single_neuron_id));
+        assert_eq!(
+            state.pending_transfers[&state.cancellations[&single_neuron_id].transfer_id].amount,
+            minted.unwrap().0.0
+        );
+    }
+
     #[test]
     fn should_compute_governance_share() {
         let mut state = default_state();
@@ -1178,6 +3404,78 @@ pub mod test {
         assert_eq!(state.compute_nicp_apy(), 0.5463);
     }
 
+    #[test]
+    fn should_compute_realized_apy() {
+        use crate::state::ExchangeRate;
+        use crate::{ONE_YEAR_SECONDS, SEC_NANOS};
+
+        let mut state = default_state();
+        state.tracked_6m_stake = ICP::from_unscaled(100);
+        state.total_circulating_nicp = nICP::from_unscaled(100);
+        let past = state.get_exchange_rate(0);
+        assert_eq!(past.exchange_rate, E8S);
+
+        // The rewards dispatched over a year back 10% more ICP per nICP.
+        state.tracked_6m_stake = ICP::from_unscaled(110);
+        let one_year = state.get_exchange_rate(ONE_YEAR_SECONDS * SEC_NANOS);
+        let apy = one_year.realized_apy_since(&past).unwrap();
+        assert!((apy - 0.1).abs() < 1e-6, "unexpected apy {apy}");
+
+        let half_year = ExchangeRate {
+            timestamp: ONE_YEAR_SECONDS * SEC_NANOS / 2,
+            ..one_year.clone()
+        };
+        let apy = half_year.realized_apy_since(&past).unwrap();
+        assert!((apy - 0.21).abs() < 1e-6, "unexpected apy {apy}");
+
+        assert_eq!(past.realized_apy_since(&past), None);
+        assert_eq!(past.realized_apy_since(&one_year), None);
+    }
+
+    #[test]
+    fn should_replay_events_in_chunks() {
+        use crate::state::audit::{initial_replay_state, replay_events, replay_events_until};
+        use crate::state::event::EventType;
+        use crate::storage::{record_event, total_event_count};
+        use std::cell::Cell;
+
+        let init_arg = InitArg {
+            wtn_ledger_id: Principal::anonymous(),
+            wtn_governance_id: Principal::anonymous(),
+            nicp_ledger_id: Principal::anonymous(),
+        };
+        record_event(EventType::Init(init_arg), 0);
+        for block_index in 0..5 {
+            record_event(
+                EventType::IcpDeposit {
+                    receiver: Principal::anonymous().into(),
+                    amount: ICP::ONE,
+                    block_index,
+                    created_at_time: None,
+                    to_liquidity_buffer: None,
+                    from: None,
+                    deposit_block_index: None,
+                },
+                0,
+            );
+        }
+
+        let (mut state, mut next_event) = initial_replay_state();
+        assert_eq!(next_event, 1);
+        let mut chunks = 0;
+        while next_event < total_event_count() {
+            let applied = Cell::new(0);
+            next_event = replay_events_until(&mut state, next_event, || {
+                applied.set(applied.get() + 1);
+                applied.get() >= 2
+            });
+            chunks += 1;
+        }
+        assert_eq!(chunks, 3);
+        assert_eq!(state.total_icp_deposited, ICP::from_unscaled(5));
+        assert_eq!(state.is_equivalent_to(&replay_events()), Ok(()));
+    }
+
     #[test]
     fn should_compute_governance_fee_share() {
         let mut state = default_state();
@@ -1,8 +1,29 @@
 use super::State;
 pub use super::event::{Event, EventType};
 use crate::state::SNS_GOVERNANCE_SUBACCOUNT;
-use crate::storage::{record_event, with_event_iter};
-use crate::{ICP, INITIAL_NEURON_STAKE, SNS_DISTRIBUTION_MEMO, nICP, timestamp_nanos};
+use crate::storage::{
+    load_state_checkpoint, record_event, total_event_count, with_event_iter, with_event_iter_from,
+};
+use crate::{ICP, INITIAL_NEURON_STAKE, SNS_DISTRIBUTION_MEMO, UpgradeArg, nICP, timestamp_nanos};
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+
+thread_local! {
+    static EVENT_REPLAY: RefCell<Option<EventReplay>> = RefCell::default();
+}
+
+/// An event replay spread over several messages, the state is read-only until it completes.
+struct EventReplay {
+    next_event: u64,
+    upgrade_arg: Option<UpgradeArg>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ReplayProgress {
+    pub replayed_event_count: u64,
+    pub total_event_count: u64,
+}
 
 /// Updates the state to reflect the given state transition.
 /// public because it's used in tests since process_event
@@ -15,12 +36,21 @@ pub fn apply_state_transition(state: &mut State, payload: &EventType, timestamp:
         EventType::Upgrade(upgrade_arg) => {
             state.record_upgrade(upgrade_arg.clone());
         }
+        EventType::ConversionLimitsUpdated(conversion_limits) => {
+            state.record_conversion_limits(conversion_limits.clone());
+        }
+        EventType::ProtocolPaused { reason } => state.record_protocol_paused(reason.clone()),
+        EventType::ProtocolResumed => state.record_protocol_resumed(),
+        EventType::ProtocolModeChanged { mode, reason } => {
+            state.record_protocol_mode_changed(*mode, reason.clone())
+        }
         EventType::DistributeICPtoSNS { amount, receiver } => {
             state.record_icp_pending_transfer(
                 SNS_GOVERNANCE_SUBACCOUNT,
                 *receiver,
                 *amount,
                 Some(SNS_DISTRIBUTION_MEMO),
+                timestamp,
             );
             state.last_distribution_ts = timestamp;
         }
@@ -31,19 +61,89 @@ pub fn apply_state_transition(state: &mut State, payload: &EventType, timestamp:
             transfer_id,
             block_index,
         } => state.record_transfer_executed(*transfer_id, *block_index, timestamp),
+        EventType::TransferFailed {
+            transfer_id,
+            error,
+            maybe_executed,
+        } => state.record_transfer_failed(*transfer_id, error.clone(), *maybe_executed, timestamp),
+        EventType::TransferDeadLettered {
+            transfer_id,
+            error,
+            maybe_executed,
+        } => state.record_transfer_dead_lettered(
+            *transfer_id,
+            error.clone(),
+            *maybe_executed,
+            timestamp,
+        ),
+        EventType::TransferRequeued {
+            transfer_id,
+            receiver,
+            verified_not_executed,
+        } => state.record_transfer_requeued(
+            *transfer_id,
+            *receiver,
+            *verified_not_executed,
+            timestamp,
+        ),
+        EventType::TransferRestamped { transfer_id } => {
+            state.record_transfer_restamped(*transfer_id, timestamp)
+        }
+        EventType::TransferRejected {
+            transfer_id,
+            reason,
+        } => state.record_transfer_rejected(*transfer_id, reason.clone(), timestamp),
         EventType::IcpDeposit {
             receiver,
             amount,
             block_index,
+            created_at_time,
+            to_liquidity_buffer,
+            from,
+            deposit_block_index,
         } => {
-            state.record_icp_deposit(*receiver, *amount, *block_index);
+            let transfer_id = state.record_icp_deposit(
+                *receiver,
+                *from,
+                *amount,
+                *block_index,
+                *created_at_time,
+                to_liquidity_buffer.unwrap_or(false),
+                timestamp,
+            );
+            if let (Some(deposit_account), Some(deposit_block_index)) = (from, deposit_block_index)
+            {
+                state.record_notified_deposit(
+                    *deposit_account,
+                    *deposit_block_index,
+                    *block_index,
+                    transfer_id,
+                    timestamp,
+                );
+            }
         }
         EventType::NIcpWithdrawal {
             receiver,
             nicp_burned,
             nicp_burn_index,
+            beneficiary,
         } => {
-            state.record_nicp_withdrawal(*receiver, *nicp_burned, *nicp_burn_index, timestamp);
+            state.record_nicp_withdrawal(
+                *receiver,
+                *beneficiary,
+                *nicp_burned,
+                *nicp_burn_index,
+                timestamp,
+            );
+        }
+        EventType::InstantWithdrawal {
+            receiver,
+            nicp_burned,
+            nicp_burn_index: _,
+            fee,
+            beneficiary,
+        } => {
+            state.record_instant_withdrawal(*receiver, *beneficiary, *nicp_burned, *fee, timestamp);
         }
         EventType::DispatchICPRewards {
             nicp_amount,
@@ -59,7 +159,48 @@ pub fn apply_state_transition(state: &mut State, payload: &EventType, timestamp:
             withdrawal_id,
             neuron_id,
         } => state.record_neuron_split(*withdrawal_id, *neuron_id),
-        EventType::MergeNeuron { neuron_id } => state.record_neuron_merge(*neuron_id),
+        EventType::SplitNeuronBatch {
+            withdrawal_ids,
+            neuron_id,
+        } => state.record_neuron_batch_split(withdrawal_ids.clone(), *neuron_id),
+        EventType::StartedToDissolveBatch { neuron_id } => {
+            state.record_started_to_dissolve_batch_neuron(*neuron_id)
+        }
+        EventType::DisbursedBatchNeuron {
+            neuron_id,
+            transfer_block_height: _,
+        } => state.record_batch_neuron_disbursed(*neuron_id, timestamp),
+        EventType::MergeNeuron {
+            neuron_id,
+            nicp_minted,
+            nicp_fee,
+        } => state.record_neuron_merge(*neuron_id, nicp_minted.zip(*nicp_fee), timestamp),
+        EventType::PartialSplitNeuron {
+            neuron_id,
+            split_neuron_id,
+            icp_amount,
+            withdrawal_id,
+        } => state.record_partial_neuron_split(
+            *neuron_id,
+            *split_neuron_id,
+            *icp_amount,
+            *withdrawal_id,
+        ),
+        EventType::WithdrawalPayoutRequeued {
+            withdrawal_id,
+            receiver,
+        } => state.record_withdrawal_payout_requeued(*withdrawal_id, *receiver, timestamp),
+        EventType::PartialMergeNeuron {
+            neuron_id: _,
+            split_neuron_id,
+            icp_amount: _,
+            nicp_minted,
+            nicp_fee,
+        } => state.record_partial_neuron_merge(
+            *split_neuron_id,
+            nicp_minted.zip(*nicp_fee),
+            timestamp,
+        ),
         EventType::StartedToDissolve { withdrawal_id } => {
             state.record_started_to_dissolve_neuron(*withdrawal_id)
         }
@@ -134,3 +275,88 @@ pub fn replay_events() -> State {
         state
     })
 }
+
+pub fn recover_state_from_checkpoint() -> Option<State> {
+    let (mut state, event_count) = load_state_checkpoint()?;
+    replay_events_until(&mut state, event_count, || false);
+    Some(state)
+}
+
+/// Returns the state to start a replay from and the index of the first event to apply:
+/// the latest checkpoint if there is a usable one, the Init event otherwise.
+pub fn initial_replay_state() -> (State, u64) {
+    if let Some(checkpoint) = load_state_checkpoint() {
+        return checkpoint;
+    }
+    with_event_iter(
+        |mut iter| match iter.next().expect("the event log should not be empty") {
+            Event {
+                payload: EventType::Init(init_arg),
+                timestamp: _,
+            } => (State::from_init_args(init_arg), 1),
+            other => panic!("the first event must be an Init event, got: {other:?}"),
+        },
+    )
+}
+
+/// Applies the events starting at index `start` until the end of the log
+/// or until `should_stop` returns true. Returns the index of the next event to apply.
+pub fn replay_events_until(state: &mut State, start: u64, should_stop: impl Fn() -> bool) -> u64 {
+    with_event_iter_from(start, |iter| {
+        let mut next_event = start;
+        for event in iter {
+            apply_state_transition(state, &event.payload, event.timestamp);
+            next_event += 1;
+            if should_stop() {
+                break;
+            }
+        }
+        next_event
+    })
+}
+
+pub fn is_replaying() -> bool {
+    EVENT_REPLAY.with(|r| r.borrow().is_some())
+}
+
+pub fn get_replay_progress() -> Option<ReplayProgress> {
+    EVENT_REPLAY.with(|r| {
+        r.borrow().as_ref().map(|replay| ReplayProgress {
+            replayed_event_count: replay.next_event,
+            total_event_count: total_event_count(),
+        })
+    })
+}
+
+/// Marks the state as being replayed, `upgrade_arg` gets applied once the replay completes.
+pub fn start_event_replay(next_event: u64, upgrade_arg: Option<UpgradeArg>) {
+    EVENT_REPLAY.with(|r| {
+        *r.borrow_mut() = Some(EventReplay {
+            next_event,
+            upgrade_arg,
+        })
+    });
+}
+
+pub fn get_replay_next_event() -> Option<u64> {
+    EVENT_REPLAY.with(|r| r.borrow().as_ref().map(|replay| replay.next_event))
+}
+
+pub fn set_replay_next_event(next_event: u64) {
+    EVENT_REPLAY.with(|r| {
+        r.borrow_mut()
+            .as_mut()
+            .expect("bug: no event replay in progress")
+            .next_event = next_event
+    });
+}
+
+/// Ends the event replay and returns the upgrade argument to apply.
+pub fn finish_event_replay() -> Option<UpgradeArg> {
+    EVENT_REPLAY.with(|r| {
+        r.borrow_mut()
+            .take()
+            .expect("bug: no event replay in progress")
+            .upgrade_arg
+    })
+}
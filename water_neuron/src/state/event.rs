@@ -1,12 +1,104 @@
 use crate::numeric::{ICP, nICP};
 use crate::state::{NeuronOrigin, WithdrawalId};
-use crate::{InitArg, NeuronId, Principal, ProposalId, TransferId, UpgradeArg};
+use crate::{
+    ConversionLimits, InitArg, NeuronId, Principal, ProposalId, ProtocolMode, TransferId,
+    UpgradeArg,
+};
 use candid::CandidType;
 use icrc_ledger_types::icrc1::account::Account;
 use minicbor_derive::{Decode, Encode};
 use serde::Deserialize;
+use strum_macros::EnumIter;
+
+/// The kind of an [EventType], used to filter the event log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, CandidType, Deserialize, EnumIter)]
+pub enum EventKind {
+    Init,
+    Upgrade,
+    DistributeICPtoSNS,
+    TransferExecuted,
+    IcpDeposit,
+    NIcpWithdrawal,
+    DispatchICPRewards,
+    SplitNeuron,
+    StartedToDissolve,
+    DisbursedUserNeuron,
+    MaturityNeuron,
+    DisbursedMaturityNeuron,
+    NeuronSixMonths,
+    NeuronEightYears,
+    ClaimedAirdrop,
+    MirroredProposal,
+    MergeNeuron,
+    DistributeICPtoSNSv2,
+    InstantWithdrawal,
+    SplitNeuronBatch,
+    StartedToDissolveBatch,
+    DisbursedBatchNeuron,
+    PartialMergeNeuron,
+    ConversionLimitsUpdated,
+    ProtocolPaused,
+    ProtocolResumed,
+    ProtocolModeChanged,
+    TransferFailed,
+    TransferDeadLettered,
+    TransferRequeued,
+    TransferRejected,
+    TransferRestamped,
+    PartialSplitNeuron,
+    WithdrawalPayoutRequeued,
+}
+
+impl EventKind {
+    /// The key of this kind in the stable event index, matches the CBOR index of the variant.
+    pub fn index_key(self) -> u8 {
+        match self {
+            EventKind::Init => 0,
+            EventKind::Upgrade => 1,
+            EventKind::DistributeICPtoSNS => 2,
+            EventKind::TransferExecuted => 3,
+            EventKind::IcpDeposit => 4,
+            EventKind::NIcpWithdrawal => 5,
+            EventKind::DispatchICPRewards => 6,
+            EventKind::SplitNeuron => 7,
+            EventKind::StartedToDissolve => 8,
+            EventKind::DisbursedUserNeuron => 9,
+            EventKind::MaturityNeuron => 10,
+            EventKind::DisbursedMaturityNeuron => 11,
+            EventKind::NeuronSixMonths => 12,
+            EventKind::NeuronEightYears => 13,
+            EventKind::ClaimedAirdrop => 14,
+            EventKind::MirroredProposal => 15,
+            EventKind::MergeNeuron => 16,
+            EventKind::DistributeICPtoSNSv2 => 17,
+            EventKind::InstantWithdrawal => 18,
+            EventKind::SplitNeuronBatch => 19,
+            EventKind::StartedToDissolveBatch => 20,
+            EventKind::DisbursedBatchNeuron => 21,
+            EventKind::PartialMergeNeuron => 22,
+            EventKind::ConversionLimitsUpdated => 23,
+            EventKind::ProtocolPaused => 24,
+            EventKind::ProtocolResumed => 25,
+            EventKind::ProtocolModeChanged => 26,
+            EventKind::TransferFailed => 27,
+            EventKind::TransferDeadLettered => 28,
+            EventKind::TransferRequeued => 29,
+            EventKind::TransferRejected => 30,
+            EventKind::TransferRestamped => 31,
+            EventKind::PartialSplitNeuron => 32,
+            EventKind::WithdrawalPayoutRequeued => 33,
+        }
+    }
+}
 
 /// The event describing a state transition.
+///
+/// Events are decoded from the stable log on every upgrade, a change that prevents an old
+/// event from decoding makes the canister fail to upgrade. Fields and variants are never
+/// removed, renumbered or retyped. A new field gets the next free index and must be
+/// optional: a plain `Option<T>`, or a `with` module providing `nil` and `is_nil` along with
+/// the `has_nil` attribute, so that events recorded without it still decode. Add a fixture
+/// of the new layout to `fixtures/events.txt` along with its value in the golden tests.
 #[derive(Clone, Debug, Encode, Decode, PartialEq, Eq, CandidType, Deserialize)]
 pub enum EventType {
     #[n(0)]
@@ -39,6 +131,18 @@ pub enum EventType {
         amount: ICP,
         #[n(2)]
         block_index: u64,
+        #[n(3)]
+        created_at_time: Option<u64>,
+        /// Set when the ICP were sent to the liquidity buffer instead of the 6m neuron.
+        #[n(4)]
+        to_liquidity_buffer: Option<bool>,
+        /// The account the ICP were pulled from, the receiver if not set.
+        #[cbor(n(5), with = "crate::cbor::account::option", has_nil)]
+        from: Option<Account>,
+        /// The block index of the transfer to the deposit account `from`, set for the
+        /// deposits converted by `notify_icp_deposit`.
+        #[n(6)]
+        deposit_block_index: Option<u64>,
     },
 
     #[n(5)]
@@ -49,6 +153,9 @@ pub enum EventType {
         nicp_burned: nICP,
         #[n(2)]
         nicp_burn_index: u64,
+        /// The account receiving the ICP, the receiver if not set.
+        #[cbor(n(3), with = "crate::cbor::account::option", has_nil)]
+        beneficiary: Option<Account>,
     },
 
     #[n(6)]
@@ -125,10 +232,258 @@ pub enum EventType {
     MergeNeuron {
         #[n(0)]
         neuron_id: NeuronId,
+        /// The nICP minted back to the receiver of the withdrawal.
+        #[n(1)]
+        nicp_minted: Option<nICP>,
+        /// The cancellation fee withheld from the nICP minted.
+        #[n(2)]
+        nicp_fee: Option<nICP>,
     },
 
     #[n(17)]
     DistributeICPtoSNSv2,
+
+    #[n(18)]
+    InstantWithdrawal {
+        #[cbor(n(0), with = "crate::cbor::account")]
+        receiver: Account,
+        #[n(1)]
+        nicp_burned: nICP,
+        #[n(2)]
+        nicp_burn_index: u64,
+        #[n(3)]
+        fee: ICP,
+        /// The account receiving the ICP, the receiver if not set.
+        #[cbor(n(4), with = "crate::cbor::account::option", has_nil)]
+        beneficiary: Option<Account>,
+    },
+
+    #[n(19)]
+    SplitNeuronBatch {
+        #[n(0)]
+        withdrawal_ids: Vec<WithdrawalId>,
+        #[n(1)]
+        neuron_id: NeuronId,
+    },
+
+    #[n(20)]
+    StartedToDissolveBatch {
+        #[n(0)]
+        neuron_id: NeuronId,
+    },
+
+    #[n(21)]
+    DisbursedBatchNeuron {
+        #[n(0)]
+        neuron_id: NeuronId,
+        #[n(1)]
+        transfer_block_height: u64,
+    },
+
+    /// Part of a withdrawal was cancelled: the neuron `split_neuron_id` recorded by
+    /// [EventType::PartialSplitNeuron] was merged into the 6 months neuron.
+    #[n(22)]
+    PartialMergeNeuron {
+        #[n(0)]
+        neuron_id: NeuronId,
+        #[n(1)]
+        split_neuron_id: NeuronId,
+        #[n(2)]
+        icp_amount: ICP,
+        /// The nICP minted back to the receiver of the withdrawal.
+        #[n(3)]
+        nicp_minted: Option<nICP>,
+        /// The cancellation fee withheld from the nICP minted.
+        #[n(4)]
+        nicp_fee: Option<nICP>,
+    },
+
+    #[n(23)]
+    ConversionLimitsUpdated(#[n(0)] ConversionLimits),
+
+    /// The protocol got fully paused automatically, until the next [EventType::ProtocolResumed].
+    #[n(24)]
+    ProtocolPaused {
+        #[n(0)]
+        reason: String,
+    },
+
+    #[n(25)]
+    ProtocolResumed,
+
+    /// The protocol mode was set by governance.
+    #[n(26)]
+    ProtocolModeChanged {
+        #[n(0)]
+        mode: ProtocolMode,
+        #[n(1)]
+        reason: Option<String>,
+    },
+
+    /// An attempt of the pending transfer failed with a retryable error.
+    #[n(27)]
+    TransferFailed {
+        #[n(0)]
+        transfer_id: TransferId,
+        #[n(1)]
+        error: String,
+        /// Whether an attempt of the transfer may have been executed by the ledger.
+        #[n(2)]
+        maybe_executed: bool,
+    },
+
+    /// The pending transfer failed with a terminal error or too many times.
+    #[n(28)]
+    TransferDeadLettered {
+        #[n(0)]
+        transfer_id: TransferId,
+        #[n(1)]
+        error: String,
+        /// Whether an attempt of the transfer may have been executed by the ledger.
+        #[n(2)]
+        maybe_executed: bool,
+    },
+
+    /// The dead-lettered transfer was put back in the queue by governance.
+    #[n(29)]
+    TransferRequeued {
+        #[n(0)]
+        transfer_id: TransferId,
+        /// The account receiving the transfer instead of its original receiver.
+        #[cbor(n(1), with = "crate::cbor::account::option", has_nil)]
+        receiver: Option<Account>,
+        /// Governance checked that no attempt of the transfer is on the ledger.
+        #[n(2)]
+        verified_not_executed: bool,
+    },
+
+    /// The pending transfer would be rejected by the ledger and was dropped without calling it.
+    #[n(30)]
+    TransferRejected {
+        #[n(0)]
+        transfer_id: TransferId,
+        #[n(1)]
+        reason: String,
+    },
+
+    /// The pending transfer, provably not executed, got a new `created_at_time` as its
+    /// previous one is out of the ledger deduplication window.
+    #[n(31)]
+    TransferRestamped {
+        #[n(0)]
+        transfer_id: TransferId,
+    },
+
+    /// Part of a withdrawal is being cancelled: `icp_amount` was split out of the withdrawal
+    /// neuron `neuron_id` into `split_neuron_id`, which is merged into the 6 months neuron
+    /// by [EventType::PartialMergeNeuron].
+    #[n(32)]
+    PartialSplitNeuron {
+        #[n(0)]
+        neuron_id: NeuronId,
+        #[n(1)]
+        split_neuron_id: NeuronId,
+        #[n(2)]
+        icp_amount: ICP,
+        /// The withdrawal cancelled, set when `neuron_id` is a batch neuron.
+        #[n(3)]
+        withdrawal_id: Option<WithdrawalId>,
+    },
+
+    /// Governance paid the withdrawal out to `receiver` after its payout was rejected.
+    #[n(33)]
+    WithdrawalPayoutRequeued {
+        #[n(0)]
+        withdrawal_id: WithdrawalId,
+        #[cbor(n(1), with = "crate::cbor::account")]
+        receiver: Account,
+    },
+}
+
+impl EventType {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            EventType::Init(_) => EventKind::Init,
+            EventType::Upgrade(_) => EventKind::Upgrade,
+            EventType::DistributeICPtoSNS { .. } => EventKind::DistributeICPtoSNS,
+            EventType::TransferExecuted { .. } => EventKind::TransferExecuted,
+            EventType::IcpDeposit { .. } => EventKind::IcpDeposit,
+            EventType::NIcpWithdrawal { .. } => EventKind::NIcpWithdrawal,
+            EventType::DispatchICPRewards { .. } => EventKind::DispatchICPRewards,
+            EventType::SplitNeuron { .. } => EventKind::SplitNeuron,
+            EventType::StartedToDissolve { .. } => EventKind::StartedToDissolve,
+            EventType::DisbursedUserNeuron { .. } => EventKind::DisbursedUserNeuron,
+            EventType::MaturityNeuron { .. } => EventKind::MaturityNeuron,
+            EventType::DisbursedMaturityNeuron { .. } => EventKind::DisbursedMaturityNeuron,
+            EventType::NeuronSixMonths(_) => EventKind::NeuronSixMonths,
+            EventType::NeuronEightYears(_) => EventKind::NeuronEightYears,
+            EventType::ClaimedAirdrop { .. } => EventKind::ClaimedAirdrop,
+            EventType::MirroredProposal { .. } => EventKind::MirroredProposal,
+            EventType::MergeNeuron { .. } => EventKind::MergeNeuron,
+            EventType::DistributeICPtoSNSv2 => EventKind::DistributeICPtoSNSv2,
+            EventType::InstantWithdrawal { .. } => EventKind::InstantWithdrawal,
+            EventType::SplitNeuronBatch { .. } => EventKind::SplitNeuronBatch,
+            EventType::StartedToDissolveBatch { .. } => EventKind::StartedToDissolveBatch,
+            EventType::DisbursedBatchNeuron { .. } => EventKind::DisbursedBatchNeuron,
+            EventType::PartialMergeNeuron { .. } => EventKind::PartialMergeNeuron,
+            EventType::ConversionLimitsUpdated(_) => EventKind::ConversionLimitsUpdated,
+            EventType::ProtocolPaused { .. } => EventKind::ProtocolPaused,
+            EventType::ProtocolResumed => EventKind::ProtocolResumed,
+            EventType::ProtocolModeChanged { .. } => EventKind::ProtocolModeChanged,
+            EventType::TransferFailed { .. } => EventKind::TransferFailed,
+            EventType::TransferDeadLettered { .. } => EventKind::TransferDeadLettered,
+            EventType::TransferRequeued { .. } => EventKind::TransferRequeued,
+            EventType::TransferRejected { .. } => EventKind::TransferRejected,
+            EventType::TransferRestamped { .. } => EventKind::TransferRestamped,
+            EventType::PartialSplitNeuron { .. } => EventKind::PartialSplitNeuron,
+            EventType::WithdrawalPayoutRequeued { .. } => EventKind::WithdrawalPayoutRequeued,
+        }
+    }
+
+    /// Returns the user accounts involved in this event, events that only
+    /// carry a principal report its default account.
+    pub fn accounts(&self) -> Vec<Account> {
+        match self {
+            EventType::IcpDeposit { receiver, from, .. } => {
+                let mut accounts = vec![*receiver];
+                accounts.extend(from.filter(|from| from != receiver));
+                accounts
+            }
+            EventType::NIcpWithdrawal {
+                receiver,
+                beneficiary,
+                ..
+            }
+            | EventType::InstantWithdrawal {
+                receiver,
+                beneficiary,
+                ..
+            } => {
+                let mut accounts = vec![*receiver];
+                accounts.extend(beneficiary.filter(|beneficiary| beneficiary != receiver));
+                accounts
+            }
+            EventType::ClaimedAirdrop { caller, .. } => vec![Account::from(*caller)],
+            EventType::DistributeICPtoSNS { receiver, .. } => vec![Account::from(*receiver)],
+            EventType::TransferRequeued {
+                receiver: Some(receiver),
+                ..
+            } => vec![*receiver],
+            EventType::WithdrawalPayoutRequeued { receiver, .. } => vec![*receiver],
+            _ => vec![],
+        }
+    }
+
+    /// Returns the principals owning the accounts involved in this event.
+    pub fn principals(&self) -> Vec<Principal> {
+        let mut principals: Vec<Principal> = vec![];
+        for account in self.accounts() {
+            if !principals.contains(&account.owner) {
+                principals.push(account.owner);
+            }
+        }
+        principals
+    }
 }
 
 #[derive(CandidType, Encode, Decode, Debug, PartialEq, Eq, Clone, Deserialize)]
@@ -152,3 +507,34 @@ pub struct GetEventsArg {
     pub start: u64,
     pub length: u64,
 }
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct GetEventsFilteredArg {
+    /// Only return events of these kinds, all kinds if empty.
+    pub kinds: Vec<EventKind>,
+    /// Only return events involving an account of this principal.
+    pub principal: Option<Principal>,
+    /// Only return events involving this account.
+    pub account: Option<Account>,
+    /// Only return events recorded at or after this timestamp, in nanoseconds.
+    pub from_timestamp: Option<u64>,
+    /// Only return events recorded at or before this timestamp, in nanoseconds.
+    pub to_timestamp: Option<u64>,
+    /// The index of the first event to consider, the `next_cursor` of a previous call.
+    pub cursor: Option<u64>,
+    pub length: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct IndexedEvent {
+    pub index: u64,
+    pub event: Event,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct GetEventsFilteredResult {
+    pub events: Vec<IndexedEvent>,
+    /// Set if more events might match, pass it as the `cursor` of the next call.
+    pub next_cursor: Option<u64>,
+    pub total_event_count: u64,
+}
@@ -1,9 +1,15 @@
-use crate::state::event::Event;
-use crate::{Account, EventType, ICP, InitArg, NeuronOrigin, UpgradeArg, nICP};
+use crate::guards::RateLimit;
+use crate::state::event::{Event, EventKind};
+use crate::{
+    Account, ConversionLimits, E8S, EventType, ICP, InitArg, NeuronId, NeuronOrigin, ProposalId,
+    ProtocolMode, UpgradeArg, nICP,
+};
 use candid::Principal;
 use proptest::array::uniform32;
 use proptest::collection::vec as pvec;
 use proptest::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
+use strum::IntoEnumIterator;
 
 fn arb_event() -> impl Strategy<Value = Event> {
     (any::<u64>(), arb_event_type()).prop_map(|(timestamp, payload)| Event { timestamp, payload })
@@ -43,12 +49,65 @@ prop_compose! {
     }
 }
 
+prop_compose! {
+    fn arb_conversion_limits()(
+        minimum_deposit_amount_e8s in proptest::option::of(any::<u64>()),
+        minimum_withdrawal_amount_e8s in proptest::option::of(any::<u64>()),
+    ) -> ConversionLimits {
+        ConversionLimits {
+            minimum_deposit_amount_e8s,
+            minimum_withdrawal_amount_e8s,
+        }
+    }
+}
+
+fn arb_protocol_mode() -> impl Strategy<Value = ProtocolMode> {
+    prop_oneof![
+        Just(ProtocolMode::Normal),
+        Just(ProtocolMode::DepositsPaused),
+        Just(ProtocolMode::WithdrawalsPaused),
+        Just(ProtocolMode::FullyPaused),
+    ]
+}
+
+prop_compose! {
+    fn arb_rate_limit()(
+        capacity in any::<u64>(),
+        refill_interval_secs in any::<u64>(),
+    ) -> RateLimit {
+        RateLimit {
+            capacity,
+            refill_interval_secs,
+        }
+    }
+}
+
 prop_compose! {
     fn arb_upgrade_arg()(
         governance_fee_share_percent in proptest::option::of(any::<u64>()),
+        liquidity_buffer_target_e8s in proptest::option::of(any::<u64>()),
+        liquidity_buffer_rewards_share_percent in proptest::option::of(any::<u64>()),
+        instant_withdrawal_min_fee_bps in proptest::option::of(any::<u64>()),
+        instant_withdrawal_max_fee_bps in proptest::option::of(any::<u64>()),
+        withdrawal_epoch_seconds in proptest::option::of(any::<u64>()),
+        conversion_limits in proptest::option::of(arb_conversion_limits()),
+        principal_rate_limit in proptest::option::of(arb_rate_limit()),
+        global_rate_limit in proptest::option::of(arb_rate_limit()),
+        max_reconciliation_drift_e8s in proptest::option::of(any::<u64>()),
+        max_reconciliation_nicp_drift_e8s in proptest::option::of(any::<u64>()),
     ) -> UpgradeArg {
         UpgradeArg {
-            governance_fee_share_percent
+            governance_fee_share_percent,
+            liquidity_buffer_target_e8s,
+            liquidity_buffer_rewards_share_percent,
+            instant_withdrawal_min_fee_bps,
+            instant_withdrawal_max_fee_bps,
+            withdrawal_epoch_seconds,
+            conversion_limits,
+            principal_rate_limit,
+            global_rate_limit,
+            max_reconciliation_drift_e8s,
+            max_reconciliation_nicp_drift_e8s,
         }
     }
 }
@@ -69,22 +128,50 @@ fn arb_event_type() -> impl Strategy<Value = EventType> {
                 block_index: Some(block_index),
             }
         }),
-        (arb_account(), any::<u64>(), any::<u64>()).prop_map(|(receiver, amount, block_index)| {
-            EventType::IcpDeposit {
-                receiver,
-                amount: ICP::from_e8s(amount),
-                block_index,
-            }
-        }),
-        (arb_account(), any::<u64>(), any::<u64>()).prop_map(
-            |(receiver, nicp_burned, nicp_burn_index)| {
+        (
+            arb_account(),
+            any::<u64>(),
+            any::<u64>(),
+            proptest::option::of(any::<u64>()),
+            proptest::option::of(any::<bool>()),
+            proptest::option::of(arb_account()),
+            proptest::option::of(any::<u64>())
+        )
+            .prop_map(
+                |(
+                    receiver,
+                    amount,
+                    block_index,
+                    created_at_time,
+                    to_liquidity_buffer,
+                    from,
+                    deposit_block_index,
+                )| {
+                    EventType::IcpDeposit {
+                        receiver,
+                        amount: ICP::from_e8s(amount),
+                        block_index,
+                        created_at_time,
+                        to_liquidity_buffer,
+                        from,
+                        deposit_block_index,
+                    }
+                }
+            ),
+        (
+            arb_account(),
+            any::<u64>(),
+            any::<u64>(),
+            proptest::option::of(arb_account())
+        )
+            .prop_map(|(receiver, nicp_burned, nicp_burn_index, beneficiary)| {
                 EventType::NIcpWithdrawal {
                     receiver,
                     nicp_burned: nICP::from_e8s(nicp_burned),
                     nicp_burn_index,
+                    beneficiary,
                 }
-            }
-        ),
+            }),
         (any::<u64>(), any::<u64>()).prop_map(|(nicp_amount, sns_gov_amount)| {
             EventType::DispatchICPRewards {
                 nicp_amount: ICP::from_e8s(nicp_amount),
@@ -92,6 +179,119 @@ fn arb_event_type() -> impl Strategy<Value = EventType> {
                 from_neuron_type: NeuronOrigin::SnsGovernanceEightYears,
             }
         }),
+        (
+            arb_account(),
+            any::<u64>(),
+            any::<u64>(),
+            any::<u64>(),
+            proptest::option::of(arb_account())
+        )
+            .prop_map(
+                |(receiver, nicp_burned, nicp_burn_index, fee, beneficiary)| {
+                    EventType::InstantWithdrawal {
+                        receiver,
+                        nicp_burned: nICP::from_e8s(nicp_burned),
+                        nicp_burn_index,
+                        fee: ICP::from_e8s(fee),
+                        beneficiary,
+                    }
+                }
+            ),
+        (pvec(any::<u64>(), 0..10), any::<u64>()).prop_map(|(withdrawal_ids, neuron_id)| {
+            EventType::SplitNeuronBatch {
+                withdrawal_ids,
+                neuron_id: NeuronId { id: neuron_id },
+            }
+        }),
+        any::<u64>().prop_map(|neuron_id| EventType::StartedToDissolveBatch {
+            neuron_id: NeuronId { id: neuron_id },
+        }),
+        (any::<u64>(), any::<u64>()).prop_map(|(neuron_id, transfer_block_height)| {
+            EventType::DisbursedBatchNeuron {
+                neuron_id: NeuronId { id: neuron_id },
+                transfer_block_height,
+            }
+        }),
+        (
+            any::<u64>(),
+            any::<u64>(),
+            any::<u64>(),
+            proptest::option::of(any::<u64>()),
+            proptest::option::of(any::<u64>())
+        )
+            .prop_map(
+                |(neuron_id, split_neuron_id, icp_amount, nicp_minted, nicp_fee)| {
+                    EventType::PartialMergeNeuron {
+                        neuron_id: NeuronId { id: neuron_id },
+                        split_neuron_id: NeuronId {
+                            id: split_neuron_id,
+                        },
+                        icp_amount: ICP::from_e8s(icp_amount),
+                        nicp_minted: nicp_minted.map(nICP::from_e8s),
+                        nicp_fee: nicp_fee.map(nICP::from_e8s),
+                    }
+                }
+            ),
+        arb_conversion_limits().prop_map(EventType::ConversionLimitsUpdated),
+        ".*".prop_map(|reason| EventType::ProtocolPaused { reason }),
+        Just(EventType::ProtocolResumed),
+        (arb_protocol_mode(), proptest::option::of(".*"))
+            .prop_map(|(mode, reason)| EventType::ProtocolModeChanged { mode, reason }),
+        (any::<u64>(), ".*", any::<bool>()).prop_map(|(transfer_id, error, maybe_executed)| {
+            EventType::TransferFailed {
+                transfer_id,
+                error,
+                maybe_executed,
+            }
+        }),
+        (any::<u64>(), ".*", any::<bool>()).prop_map(|(transfer_id, error, maybe_executed)| {
+            EventType::TransferDeadLettered {
+                transfer_id,
+                error,
+                maybe_executed,
+            }
+        }),
+        (
+            any::<u64>(),
+            proptest::option::of(arb_account()),
+            any::<bool>()
+        )
+            .prop_map(|(transfer_id, receiver, verified_not_executed)| {
+                EventType::TransferRequeued {
+                    transfer_id,
+                    receiver,
+                    verified_not_executed,
+                }
+            }),
+        (any::<u64>(), ".*").prop_map(|(transfer_id, reason)| {
+            EventType::TransferRejected {
+                transfer_id,
+                reason,
+            }
+        }),
+        any::<u64>().prop_map(|transfer_id| EventType::TransferRestamped { transfer_id }),
+        (
+            any::<u64>(),
+            any::<u64>(),
+            any::<u64>(),
+            proptest::option::of(any::<u64>())
+        )
+            .prop_map(|(neuron_id, split_neuron_id, icp_amount, withdrawal_id)| {
+                EventType::PartialSplitNeuron {
+                    neuron_id: NeuronId { id: neuron_id },
+                    split_neuron_id: NeuronId {
+                        id: split_neuron_id,
+                    },
+                    icp_amount: ICP::from_e8s(icp_amount),
+                    withdrawal_id,
+                }
+            }),
+        (any::<u64>(), arb_account()).prop_map(|(withdrawal_id, receiver)| {
+            EventType::WithdrawalPayoutRequeued {
+                withdrawal_id,
+                receiver,
+            }
+        }),
     ]
 }
 
@@ -103,3 +303,414 @@ proptest! {
         prop_assert_eq!(&event, &Event::from_bytes(bytes.clone()), "failed to decode bytes {}", hex::encode(bytes));
     }
 }
+
+/// The expected value of every fixture in `fixtures/events.txt`, `_v0` fixtures use
+/// the layout of the event before optional fields were added to it.
+fn golden_events() -> BTreeMap<&'static str, Event> {
+    const T: u64 = 1_700_000_000_000_000_000;
+    let p1 = Principal::from_slice(&[1, 2, 3]);
+    let p2 = Principal::from_slice(&[4, 5]);
+    let account = Account {
+        owner: p1,
+        subaccount: None,
+    };
+    let account_with_subaccount = Account {
+        owner: p2,
+        subaccount: Some([7; 32]),
+    };
+    let neuron_id = |id| NeuronId { id };
+    let payloads = vec![
+        (
+            "init",
+            EventType::Init(InitArg {
+                nicp_ledger_id: p1,
+                wtn_governance_id: p2,
+                wtn_ledger_id: p1,
+            }),
+        ),
+        (
+            "upgrade_v0",
+            EventType::Upgrade(UpgradeArg {
+                governance_fee_share_percent: Some(10),
+                ..Default::default()
+            }),
+        ),
+        (
+            "upgrade",
+            EventType::Upgrade(UpgradeArg {
+                governance_fee_share_percent: Some(10),
+                liquidity_buffer_target_e8s: Some(1_000 * E8S),
+                liquidity_buffer_rewards_share_percent: Some(50),
+                instant_withdrawal_min_fee_bps: Some(10),
+                instant_withdrawal_max_fee_bps: Some(100),
+                withdrawal_epoch_seconds: Some(86_400),
+                conversion_limits: Some(ConversionLimits {
+                    minimum_deposit_amount_e8s: Some(E8S),
+                    minimum_withdrawal_amount_e8s: Some(10 * E8S),
+                }),
+                principal_rate_limit: Some(RateLimit {
+                    capacity: 10,
+                    refill_interval_secs: 30,
+                }),
+                global_rate_limit: Some(RateLimit {
+                    capacity: 200,
+                    refill_interval_secs: 1,
+                }),
+                max_reconciliation_drift_e8s: None,
+                max_reconciliation_nicp_drift_e8s: None,
+            }),
+        ),
+        (
+            "distribute_icp_to_sns",
+            EventType::DistributeICPtoSNS {
+                amount: ICP::from_unscaled(5),
+                receiver: p1,
+            },
+        ),
+        (
+            "transfer_executed",
+            EventType::TransferExecuted {
+                transfer_id: 3,
+                block_index: Some(42),
+            },
+        ),
+        (
+            "transfer_executed_without_block_index",
+            EventType::TransferExecuted {
+                transfer_id: 3,
+                block_index: None,
+            },
+        ),
+        (
+            "icp_deposit_v0",
+            EventType::IcpDeposit {
+                receiver: account,
+                amount: ICP::ONE,
+                block_index: 7,
+                created_at_time: None,
+                to_liquidity_buffer: None,
+                from: None,
+                deposit_block_index: None,
+            },
+        ),
+        (
+            "icp_deposit",
+            EventType::IcpDeposit {
+                receiver: account,
+                amount: ICP::ONE,
+                block_index: 7,
+                created_at_time: Some(T),
+                to_liquidity_buffer: Some(true),
+                from: Some(account_with_subaccount),
+                deposit_block_index: None,
+            },
+        ),
+        (
+            "nicp_withdrawal_v0",
+            EventType::NIcpWithdrawal {
+                receiver: account_with_subaccount,
+                nicp_burned: nICP::from_unscaled(2),
+                nicp_burn_index: 8,
+                beneficiary: None,
+            },
+        ),
+        (
+            "nicp_withdrawal",
+            EventType::NIcpWithdrawal {
+                receiver: account_with_subaccount,
+                nicp_burned: nICP::from_unscaled(2),
+                nicp_burn_index: 8,
+                beneficiary: Some(account),
+            },
+        ),
+        (
+            "dispatch_icp_rewards",
+            EventType::DispatchICPRewards {
+                nicp_amount: ICP::from_unscaled(3),
+                sns_gov_amount: ICP::ONE,
+                from_neuron_type: NeuronOrigin::NICPSixMonths,
+            },
+        ),
+        (
+            "split_neuron",
+            EventType::SplitNeuron {
+                withdrawal_id: 2,
+                neuron_id: neuron_id(1234),
+            },
+        ),
+        (
+            "started_to_dissolve",
+            EventType::StartedToDissolve { withdrawal_id: 2 },
+        ),
+        (
+            "disbursed_user_neuron",
+            EventType::DisbursedUserNeuron {
+                withdrawal_id: 2,
+                transfer_block_height: 99,
+            },
+        ),
+        (
+            "maturity_neuron",
+            EventType::MaturityNeuron {
+                neuron_id: neuron_id(1235),
+                from_neuron_type: NeuronOrigin::SnsGovernanceEightYears,
+            },
+        ),
+        (
+            "disbursed_maturity_neuron",
+            EventType::DisbursedMaturityNeuron {
+                neuron_id: neuron_id(1235),
+                transfer_block_height: 100,
+            },
+        ),
+        (
+            "neuron_six_months",
+            EventType::NeuronSixMonths(neuron_id(1)),
+        ),
+        (
+            "neuron_eight_years",
+            EventType::NeuronEightYears(neuron_id(2)),
+        ),
+        (
+            "claimed_airdrop",
+            EventType::ClaimedAirdrop {
+                caller: p1,
+                block_index: 5,
+            },
+        ),
+        (
+            "mirrored_proposal",
+            EventType::MirroredProposal {
+                nns_proposal_id: ProposalId { id: 10 },
+                sns_proposal_id: ProposalId { id: 11 },
+            },
+        ),
+        (
+            "merge_neuron_v0",
+            EventType::MergeNeuron {
+                neuron_id: neuron_id(1234),
+                nicp_minted: None,
+                nicp_fee: None,
+            },
+        ),
+        ("distribute_icp_to_sns_v2", EventType::DistributeICPtoSNSv2),
+        (
+            "instant_withdrawal_v0",
+            EventType::InstantWithdrawal {
+                receiver: account,
+                nicp_burned: nICP::ONE,
+                nicp_burn_index: 9,
+                fee: ICP::from_e8s(50_000),
+                beneficiary: None,
+            },
+        ),
+        (
+            "instant_withdrawal",
+            EventType::InstantWithdrawal {
+                receiver: account,
+                nicp_burned: nICP::ONE,
+                nicp_burn_index: 9,
+                fee: ICP::from_e8s(50_000),
+                beneficiary: Some(account_with_subaccount),
+            },
+        ),
+        (
+            "split_neuron_batch",
+            EventType::SplitNeuronBatch {
+                withdrawal_ids: vec![2, 3],
+                neuron_id: neuron_id(1236),
+            },
+        ),
+        (
+            "started_to_dissolve_batch",
+            EventType::StartedToDissolveBatch {
+                neuron_id: neuron_id(1236),
+            },
+        ),
+        (
+            "disbursed_batch_neuron",
+            EventType::DisbursedBatchNeuron {
+                neuron_id: neuron_id(1236),
+                transfer_block_height: 101,
+            },
+        ),
+        (
+            "partial_merge_neuron_v0",
+            EventType::PartialMergeNeuron {
+                neuron_id: neuron_id(1234),
+                split_neuron_id: neuron_id(1237),
+                icp_amount: ICP::ONE,
+                nicp_minted: None,
+                nicp_fee: None,
+            },
+        ),
+        (
+            "conversion_limits_updated",
+            EventType::ConversionLimitsUpdated(ConversionLimits {
+                minimum_deposit_amount_e8s: Some(2 * E8S),
+                minimum_withdrawal_amount_e8s: None,
+            }),
+        ),
+        (
+            "protocol_paused",
+            EventType::ProtocolPaused {
+                reason: "drift".to_string(),
+            },
+        ),
+        ("protocol_resumed", EventType::ProtocolResumed),
+        (
+            "protocol_mode_changed",
+            EventType::ProtocolModeChanged {
+                mode: ProtocolMode::DepositsPaused,
+                reason: Some("audit".to_string()),
+            },
+        ),
+        (
+            "transfer_failed",
+            EventType::TransferFailed {
+                transfer_id: 4,
+                error: "fee".to_string(),
+                maybe_executed: true,
+            },
+        ),
+        (
+            "transfer_dead_lettered",
+            EventType::TransferDeadLettered {
+                transfer_id: 4,
+                error: "fee".to_string(),
+                maybe_executed: true,
+            },
+        ),
+        (
+            "transfer_requeued",
+            EventType::TransferRequeued {
+                transfer_id: 4,
+                receiver: Some(account),
+                verified_not_executed: false,
+            },
+        ),
+        (
+            "transfer_rejected",
+            EventType::TransferRejected {
+                transfer_id: 4,
+                reason: "dust".to_string(),
+            },
+        ),
+        (
+            "transfer_restamped",
+            EventType::TransferRestamped { transfer_id: 4 },
+        ),
+        (
+            "partial_split_neuron_v0",
+            EventType::PartialSplitNeuron {
+                neuron_id: neuron_id(1234),
+                split_neuron_id: neuron_id(1237),
+                icp_amount: ICP::ONE,
+                withdrawal_id: None,
+            },
+        ),
+        (
+            "notified_icp_deposit",
+            EventType::IcpDeposit {
+                receiver: account,
+                amount: ICP::ONE,
+                block_index: 7,
+                created_at_time: None,
+                to_liquidity_buffer: None,
+                from: Some(account_with_subaccount),
+                deposit_block_index: Some(42),
+            },
+        ),
+        (
+            "withdrawal_payout_requeued",
+            EventType::WithdrawalPayoutRequeued {
+                withdrawal_id: 3,
+                receiver: account_with_subaccount,
+            },
+        ),
+        (
+            "merge_neuron",
+            EventType::MergeNeuron {
+                neuron_id: neuron_id(1234),
+                nicp_minted: Some(nICP::ONE),
+                nicp_fee: Some(nICP::from_e8s(500_000)),
+            },
+        ),
+        (
+            "partial_merge_neuron",
+            EventType::PartialMergeNeuron {
+                neuron_id: neuron_id(1234),
+                split_neuron_id: neuron_id(1237),
+                icp_amount: ICP::ONE,
+                nicp_minted: Some(nICP::ONE),
+                nicp_fee: Some(nICP::from_e8s(500_000)),
+            },
+        ),
+        (
+            "partial_split_neuron",
+            EventType::PartialSplitNeuron {
+                neuron_id: neuron_id(1236),
+                split_neuron_id: neuron_id(1237),
+                icp_amount: ICP::ONE,
+                withdrawal_id: Some(3),
+            },
+        ),
+    ];
+    payloads
+        .into_iter()
+        .zip(T..)
+        .map(|((name, payload), timestamp)| (name, Event { timestamp, payload }))
+        .collect()
+}
+
+#[test]
+fn should_decode_golden_events() {
+    let fixtures: Vec<(&str, &str)> = include_str!("fixtures/events.txt")
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            line.split_once(' ')
+                .unwrap_or_else(|| panic!("malformed fixture line: {line}"))
+        })
+        .collect();
+    let golden = golden_events();
+
+    for (name, hex) in &fixtures {
+        let bytes = hex::decode(hex).unwrap_or_else(|e| panic!("invalid hex for {name}: {e}"));
+        let decoded: Event = minicbor::decode(&bytes)
+            .unwrap_or_else(|e| panic!("fixture {name} no longer decodes: {e}"));
+        assert_eq!(
+            Some(&decoded),
+            golden.get(name),
+            "fixture {name} decodes to another value"
+        );
+    }
+
+    for (name, event) in &golden {
+        let mut buf = vec![];
+        minicbor::encode(event, &mut buf).unwrap();
+        assert!(
+            fixtures.iter().any(|(fixture, _)| fixture == name),
+            "missing fixture, append `{name} {}` to fixtures/events.txt",
+            hex::encode(buf)
+        );
+
+        // The stable event index is keyed by the CBOR index of the variant.
+        let payload = minicbor::to_vec(&event.payload).unwrap();
+        let mut decoder = minicbor::Decoder::new(&payload);
+        decoder.array().unwrap();
+        assert_eq!(
+            decoder.u8().unwrap(),
+            event.payload.kind().index_key(),
+            "the index key of {name} does not match its CBOR index"
+        );
+    }
+
+    let covered: BTreeSet<EventKind> = golden.values().map(|e| e.payload.kind()).collect();
+    for kind in EventKind::iter() {
+        assert!(
+            covered.contains(&kind),
+            "no golden event of kind {kind:?}, add one along with its fixture"
+        );
+    }
+}
@@ -230,6 +230,7 @@ async fn e2e_basic() {
             water_neuron_wasm(),
             Encode!(&LiquidArg::Upgrade(Some(UpgradeArg {
                 governance_fee_share_percent: Some(20),
+                ..Default::default()
             })))
             .unwrap(),
         )
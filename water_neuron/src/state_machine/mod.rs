@@ -911,6 +911,11 @@ impl WaterNeuron {
             ConversionArg {
                 amount_e8s,
                 maybe_subaccount: None,
+                created_at_time: None,
+                memo: None,
+                beneficiary: None,
+                receiver: None,
+                min_amount_out_e8s: None,
             },
         )
         .await
@@ -929,6 +934,11 @@ impl WaterNeuron {
             ConversionArg {
                 amount_e8s,
                 maybe_subaccount: None,
+                created_at_time: None,
+                memo: None,
+                beneficiary: None,
+                receiver: None,
+                min_amount_out_e8s: None,
             },
         )
         .await
@@ -587,6 +587,7 @@ async fn should_mirror_proposal() {
                 water_neuron_wasm(),
                 Encode!(&LiquidArg::Upgrade(Some(UpgradeArg {
                     governance_fee_share_percent: None,
+                    ..Default::default()
                 })))
                 .unwrap(),
             )
@@ -798,7 +799,8 @@ async fn should_distribute_icp_to_sns_neurons() {
                 water_neuron.water_neuron_id,
                 water_neuron_wasm(),
                 Encode!(&LiquidArg::Upgrade(Some(UpgradeArg {
-                    governance_fee_share_percent: None
+                    governance_fee_share_percent: None,
+                    ..Default::default()
                 })))
                 .unwrap(),
             )
@@ -866,6 +868,11 @@ async fn transfer_ids_are_as_expected() {
     );
 
     let statuses = water_neuron.get_transfer_statuses(vec![0]).await;
+    let created_at_time = match &statuses[..] {
+        [TransferStatus::Pending(transfer)] => transfer.created_at_time,
+        _ => panic!("unexpected transfer statuses {statuses:?}"),
+    };
+    assert!(created_at_time > 0);
     assert_eq!(
         statuses,
         vec![TransferStatus::Pending(PendingTransfer {
@@ -875,6 +882,7 @@ async fn transfer_ids_are_as_expected() {
             amount: 100 * E8S,
             receiver: caller.0.into(),
             unit: Unit::NICP,
+            created_at_time,
         }),]
     );
 
@@ -884,7 +892,8 @@ async fn transfer_ids_are_as_expected() {
                 water_neuron.water_neuron_id,
                 water_neuron_wasm(),
                 Encode!(&LiquidArg::Upgrade(Some(UpgradeArg {
-                    governance_fee_share_percent: None
+                    governance_fee_share_percent: None,
+                    ..Default::default()
                 })))
                 .unwrap(),
             )
@@ -996,7 +1005,8 @@ async fn should_compute_exchange_rate() {
                 water_neuron.water_neuron_id,
                 water_neuron_wasm(),
                 Encode!(&LiquidArg::Upgrade(Some(UpgradeArg {
-                    governance_fee_share_percent: None
+                    governance_fee_share_percent: None,
+                    ..Default::default()
                 })))
                 .unwrap(),
             )
@@ -1103,6 +1113,7 @@ async fn should_mirror_all_proposals() {
                 water_neuron_wasm(),
                 Encode!(&LiquidArg::Upgrade(Some(UpgradeArg {
                     governance_fee_share_percent: None,
+                    ..Default::default()
                 })))
                 .unwrap(),
             )
@@ -1,17 +1,44 @@
-use crate::state::event::{Event, EventType};
+use crate::logs::INFO;
+use crate::reconciliation::ReconciliationReport;
+use crate::state::event::{
+    Event, EventType, GetEventsFilteredArg, GetEventsFilteredResult, IndexedEvent,
+};
+use crate::state::{ExchangeRate, ExchangeRateHistory, State};
 use candid::Principal;
+use ic_canister_log::log;
 use ic_stable_structures::{
-    DefaultMemoryImpl, StableBTreeMap,
+    DefaultMemoryImpl, Memory, StableBTreeMap, StableCell,
     log::Log as StableLog,
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
     storable::{Bound, Storable},
 };
+use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use std::cell::RefCell;
 
 const LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(0);
 const LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(1);
 const PRINCIPAL_TO_ICP_REWARDS_ID: MemoryId = MemoryId::new(2);
+const EXCHANGE_RATE_HISTORY_MEMORY_ID: MemoryId = MemoryId::new(3);
+const STATE_CHECKPOINT_MEMORY_ID: MemoryId = MemoryId::new(4);
+const EVENT_KIND_INDEX_MEMORY_ID: MemoryId = MemoryId::new(5);
+const EVENT_PRINCIPAL_INDEX_MEMORY_ID: MemoryId = MemoryId::new(6);
+const INDEXED_EVENT_COUNT_MEMORY_ID: MemoryId = MemoryId::new(7);
+const RECONCILIATION_REPORTS_MEMORY_ID: MemoryId = MemoryId::new(8);
+
+/// The maximum number of events returned by a single query.
+pub const MAX_EVENTS_PER_QUERY: u64 = 2_000;
+
+/// The maximum number of events a filtered query reads before returning a cursor.
+const MAX_EVENTS_SCANNED_PER_QUERY: usize = 10_000;
+
+/// The number of reconciliation reports kept, a month of hourly reports.
+pub const MAX_RECONCILIATION_REPORTS: u64 = 24 * 30;
+
+/// Bumped whenever the serialized layout of [State] or the semantics of its
+/// state transitions change, checkpoints with another version are ignored and
+/// the state gets replayed from scratch.
+pub const STATE_CHECKPOINT_VERSION: u32 = 1;
 
 type VMem = VirtualMemory<DefaultMemoryImpl>;
 type EventLog = StableLog<Event, VMem, VMem>;
@@ -31,6 +58,45 @@ impl Storable for Event {
     const BOUND: Bound = Bound::Unbounded;
 }
 
+impl Storable for ExchangeRate {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        minicbor::encode(self, &mut buf).expect("exchange rate encoding should always succeed");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        minicbor::decode(bytes.as_ref()).unwrap_or_else(|e| {
+            panic!(
+                "failed to decode exchange rate bytes {}: {e}",
+                hex::encode(bytes)
+            )
+        })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for ReconciliationReport {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        minicbor::encode(self, &mut buf)
+            .expect("reconciliation report encoding should always succeed");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        minicbor::decode(bytes.as_ref()).unwrap_or_else(|e| {
+            panic!(
+                "failed to decode reconciliation report bytes {}: {e}",
+                hex::encode(bytes)
+            )
+        })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
         MemoryManager::init(DefaultMemoryImpl::default())
@@ -50,13 +116,62 @@ thread_local! {
         MEMORY_MANAGER.with(|mm| {
         RefCell::new(StableBTreeMap::init(mm.borrow().get(PRINCIPAL_TO_ICP_REWARDS_ID)))
     });
+
+    static EXCHANGE_RATE_HISTORY: RefCell<StableBTreeMap<u64, ExchangeRate, VMem>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(EXCHANGE_RATE_HISTORY_MEMORY_ID)))
+    });
+
+    static STATE_CHECKPOINT: RefCell<StableCell<Vec<u8>, VMem>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(
+            StableCell::init(mm.borrow().get(STATE_CHECKPOINT_MEMORY_ID), vec![])
+                .expect("failed to initialize the state checkpoint")
+        )
+    });
+
+    /// (kind, event index) for every indexed event.
+    static EVENT_KIND_INDEX: RefCell<StableBTreeMap<(u8, u64), (), VMem>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(EVENT_KIND_INDEX_MEMORY_ID)))
+    });
+
+    /// (principal, event index) for every indexed event involving an account of the principal.
+    static EVENT_PRINCIPAL_INDEX: RefCell<StableBTreeMap<(Principal, u64), (), VMem>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(EVENT_PRINCIPAL_INDEX_MEMORY_ID)))
+    });
+
+    /// The events with an index below this count are in the secondary indexes.
+    static INDEXED_EVENT_COUNT: RefCell<StableCell<u64, VMem>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(
+            StableCell::init(mm.borrow().get(INDEXED_EVENT_COUNT_MEMORY_ID), 0)
+                .expect("failed to initialize the indexed event count")
+        )
+    });
+
+    static RECONCILIATION_REPORTS: RefCell<StableBTreeMap<u64, ReconciliationReport, VMem>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(RECONCILIATION_REPORTS_MEMORY_ID)))
+    });
 }
 
 /// Appends the event to the event log.
 pub fn record_event(payload: EventType, timestamp: u64) {
-    EVENTS
+    let index = EVENTS
         .with(|events| events.borrow().append(&Event { timestamp, payload }))
         .expect("recording an event should succeed");
+    // Events recorded while older ones are still being indexed get indexed by the backfill.
+    if indexed_event_count() == index {
+        let event = get_event(index).expect("bug: the event was just recorded");
+        index_event(index, &event.payload);
+        set_indexed_event_count(index + 1);
+    }
+}
+
+fn get_event(index: u64) -> Option<Event> {
+    EVENTS.with(|events| events.borrow().get(index))
 }
 
 /// Returns the total number of events in the audit log.
@@ -71,6 +186,277 @@ where
     EVENTS.with(|events| f(Box::new(events.borrow().iter())))
 }
 
+/// Reads the event log out of `memory`, a copy of the stable memory of the canister.
+pub fn read_event_log<M: Memory>(memory: M) -> Result<Vec<Event>, String> {
+    let memory_manager = MemoryManager::init(memory);
+    let events: StableLog<Event, _, _> = StableLog::init(
+        memory_manager.get(LOG_INDEX_MEMORY_ID),
+        memory_manager.get(LOG_DATA_MEMORY_ID),
+    )
+    .map_err(|e| format!("failed to read the event log: {e:?}"))?;
+    Ok(events.iter().collect())
+}
+
+/// Same as [with_event_iter] but starts at the event with index `start`.
+pub fn with_event_iter_from<F, R>(start: u64, f: F) -> R
+where
+    F: for<'a> FnOnce(Box<dyn Iterator<Item = Event> + 'a>) -> R,
+{
+    EVENTS.with(|events| {
+        let events = events.borrow();
+        let len = events.len();
+        f(Box::new((start..len).map(move |index| {
+            events
+                .get(index)
+                .expect("bug: the event log should contain the index")
+        })))
+    })
+}
+
+/// Returns the number of events present in the secondary indexes.
+pub fn indexed_event_count() -> u64 {
+    INDEXED_EVENT_COUNT.with(|c| *c.borrow().get())
+}
+
+fn set_indexed_event_count(count: u64) {
+    INDEXED_EVENT_COUNT.with(|c| {
+        c.borrow_mut()
+            .set(count)
+            .expect("failed to record the indexed event count");
+    });
+}
+
+fn index_event(index: u64, payload: &EventType) {
+    EVENT_KIND_INDEX.with(|i| {
+        i.borrow_mut()
+            .insert((payload.kind().index_key(), index), ());
+    });
+    EVENT_PRINCIPAL_INDEX.with(|i| {
+        let mut i = i.borrow_mut();
+        for principal in payload.principals() {
+            i.insert((principal, index), ());
+        }
+    });
+}
+
+/// Adds the events recorded before the secondary indexes existed to them,
+/// until all events are indexed or `should_stop` returns true.
+/// Returns true if all the events are indexed.
+pub fn index_events_until(should_stop: impl Fn() -> bool) -> bool {
+    let start = indexed_event_count();
+    let mut next_event = start;
+    let done = with_event_iter_from(start, |iter| {
+        for event in iter {
+            index_event(next_event, &event.payload);
+            next_event += 1;
+            if should_stop() {
+                return false;
+            }
+        }
+        true
+    });
+    if next_event != start {
+        set_indexed_event_count(next_event);
+    }
+    done && next_event == total_event_count()
+}
+
+/// Returns the index of the first event recorded at or after `timestamp`,
+/// relying on events being appended in timestamp order.
+fn first_event_at_or_after(timestamp: u64) -> u64 {
+    let (mut lo, mut hi) = (0, total_event_count());
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        let event = get_event(mid).expect("bug: the event log should contain the index");
+        if event.timestamp < timestamp {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    lo
+}
+
+fn matches_filter(arg: &GetEventsFilteredArg, event: &Event) -> bool {
+    if !arg.kinds.is_empty() && !arg.kinds.contains(&event.payload.kind()) {
+        return false;
+    }
+    if arg.principal.is_some() || arg.account.is_some() {
+        let accounts = event.payload.accounts();
+        let principal_matches = arg
+            .principal
+            .is_none_or(|principal| accounts.iter().any(|account| account.owner == principal));
+        let account_matches = arg
+            .account
+            .is_none_or(|account| accounts.contains(&account));
+        if !principal_matches || !account_matches {
+            return false;
+        }
+    }
+    true
+}
+
+/// Returns the indexes between `start` and `end` of the candidate events for `arg`, in increasing order,
+/// using the secondary indexes for the indexed events and the log for the others.
+fn candidate_event_indexes(arg: &GetEventsFilteredArg, start: u64, end: u64) -> Vec<u64> {
+    let indexed_end = indexed_event_count().clamp(start, end);
+    let mut candidates: Vec<u64> =
+        if let Some(principal) = arg.principal.or(arg.account.map(|account| account.owner)) {
+            EVENT_PRINCIPAL_INDEX.with(|i| {
+                i.borrow()
+                    .range((principal, start)..(principal, indexed_end))
+                    .take(MAX_EVENTS_SCANNED_PER_QUERY)
+                    .map(|((_, index), ())| index)
+                    .collect()
+            })
+        } else if !arg.kinds.is_empty() {
+            let mut kinds = arg.kinds.clone();
+            kinds.sort();
+            kinds.dedup();
+            let mut candidates: Vec<u64> = EVENT_KIND_INDEX.with(|i| {
+                let i = i.borrow();
+                kinds
+                    .iter()
+                    .flat_map(|kind| {
+                        let key = kind.index_key();
+                        i.range((key, start)..(key, indexed_end))
+                            .take(MAX_EVENTS_SCANNED_PER_QUERY)
+                            .map(|((_, index), ())| index)
+                            .collect::<Vec<u64>>()
+                    })
+                    .collect()
+            });
+            candidates.sort_unstable();
+            candidates.truncate(MAX_EVENTS_SCANNED_PER_QUERY);
+            candidates
+        } else {
+            (start..indexed_end)
+                .take(MAX_EVENTS_SCANNED_PER_QUERY)
+                .collect()
+        };
+    let remaining = MAX_EVENTS_SCANNED_PER_QUERY - candidates.len();
+    candidates.extend((indexed_end..end).take(remaining));
+    candidates
+}
+
+/// Returns at most `length` events matching all the filters of `arg`, in log order.
+pub fn get_events_filtered(arg: &GetEventsFilteredArg, length: usize) -> GetEventsFilteredResult {
+    let total_event_count = total_event_count();
+    let mut start = arg.cursor.unwrap_or(0);
+    if let Some(from_timestamp) = arg.from_timestamp {
+        start = start.max(first_event_at_or_after(from_timestamp));
+    }
+    let end = match arg.to_timestamp {
+        Some(to_timestamp) => first_event_at_or_after(to_timestamp.saturating_add(1)),
+        None => total_event_count,
+    };
+    if start >= end || length == 0 {
+        return GetEventsFilteredResult {
+            events: vec![],
+            next_cursor: None,
+            total_event_count,
+        };
+    }
+
+    let candidates = candidate_event_indexes(arg, start, end);
+    let scanned_everything = candidates.len() < MAX_EVENTS_SCANNED_PER_QUERY;
+    let mut events: Vec<IndexedEvent> = vec![];
+    let mut next_cursor = None;
+    for index in candidates.iter().copied() {
+        if events.len() >= length {
+            next_cursor = Some(index);
+            break;
+        }
+        let event = get_event(index).expect("bug: the event log should contain the index");
+        if matches_filter(arg, &event) {
+            events.push(IndexedEvent { index, event });
+        }
+    }
+    if next_cursor.is_none() && !scanned_everything {
+        next_cursor = candidates.last().map(|index| index + 1);
+    }
+    GetEventsFilteredResult {
+        events,
+        next_cursor,
+        total_event_count,
+    }
+}
+
+#[derive(Serialize)]
+struct StateCheckpointRef<'a> {
+    version: u32,
+    event_count: u64,
+    state: &'a State,
+}
+
+#[derive(Deserialize)]
+struct StateCheckpointHeader {
+    version: u32,
+}
+
+#[derive(Deserialize)]
+struct StateCheckpoint {
+    event_count: u64,
+    state: State,
+}
+
+pub fn encode_state_checkpoint(state: &State, event_count: u64) -> Vec<u8> {
+    let mut buf = vec![];
+    ciborium::into_writer(
+        &StateCheckpointRef {
+            version: STATE_CHECKPOINT_VERSION,
+            event_count,
+            state,
+        },
+        &mut buf,
+    )
+    .expect("state checkpoint encoding should always succeed");
+    buf
+}
+
+/// Returns the state and the number of events it covers.
+pub fn decode_state_checkpoint(bytes: &[u8]) -> Result<(State, u64), String> {
+    let header: StateCheckpointHeader = ciborium::from_reader(bytes)
+        .map_err(|e| format!("failed to decode the checkpoint header: {e}"))?;
+    if header.version != STATE_CHECKPOINT_VERSION {
+        return Err(format!(
+            "unsupported checkpoint version {}, expected {STATE_CHECKPOINT_VERSION}",
+            header.version
+        ));
+    }
+    let checkpoint: StateCheckpoint = ciborium::from_reader(bytes)
+        .map_err(|e| format!("failed to decode the checkpoint: {e}"))?;
+    Ok((checkpoint.state, checkpoint.event_count))
+}
+
+/// Saves `state` as the result of replaying the first `event_count` events.
+pub fn record_state_checkpoint(state: &State, event_count: u64) {
+    let bytes = encode_state_checkpoint(state, event_count);
+    STATE_CHECKPOINT.with(|c| {
+        c.borrow_mut()
+            .set(bytes)
+            .expect("failed to record the state checkpoint");
+    });
+}
+
+/// Returns the latest checkpoint, if any, and the number of events it covers.
+pub fn load_state_checkpoint() -> Option<(State, u64)> {
+    STATE_CHECKPOINT.with(|c| {
+        let c = c.borrow();
+        let bytes = c.get();
+        if bytes.is_empty() {
+            return None;
+        }
+        match decode_state_checkpoint(bytes) {
+            Ok(checkpoint) => Some(checkpoint),
+            Err(error) => {
+                log!(INFO, "[load_state_checkpoint] ignoring checkpoint: {error}");
+                None
+            }
+        }
+    })
+}
+
 pub fn stable_add_rewards(to: Principal, amount_e8s: u64) {
     PRINCIPAL_TO_ICP_REWARDS.with(|p| {
         let balance = p.borrow().get(&to).unwrap_or(0);
@@ -127,6 +513,83 @@ pub fn total_pending_rewards() -> u64 {
     PRINCIPAL_TO_ICP_REWARDS.with(|p| p.borrow().values().sum())
 }
 
+pub fn record_exchange_rate(exchange_rate: ExchangeRate) {
+    EXCHANGE_RATE_HISTORY.with(|h| {
+        h.borrow_mut()
+            .insert(exchange_rate.timestamp, exchange_rate);
+    });
+}
+
+/// Returns the latest snapshot taken at or before `timestamp`.
+pub fn get_exchange_rate_at(timestamp: u64) -> Option<ExchangeRate> {
+    EXCHANGE_RATE_HISTORY.with(|h| {
+        h.borrow()
+            .iter_upper_bound(&timestamp.saturating_add(1))
+            .next()
+            .map(|(_, exchange_rate)| exchange_rate)
+    })
+}
+
+/// Returns at most `length` snapshots taken between `from_ts` and `to_ts` included,
+/// keeping the first snapshot of every `granularity_nanos` window if it is not 0.
+/// The timestamps and the granularity are in nanoseconds.
+pub fn get_exchange_rate_history(
+    from_ts: u64,
+    to_ts: u64,
+    granularity_nanos: u64,
+    length: usize,
+) -> ExchangeRateHistory {
+    let mut history = ExchangeRateHistory {
+        exchange_rates: vec![],
+        next_from_ts: None,
+    };
+    if from_ts > to_ts {
+        return history;
+    }
+    EXCHANGE_RATE_HISTORY.with(|h| {
+        let mut next_window_start = from_ts;
+        for (timestamp, exchange_rate) in h.borrow().range(from_ts..=to_ts) {
+            if timestamp < next_window_start {
+                continue;
+            }
+            // The windows are aligned on the granularity, the next call resumes at this one.
+            if history.exchange_rates.len() >= length {
+                history.next_from_ts = Some(timestamp);
+                break;
+            }
+            if granularity_nanos > 0 {
+                next_window_start =
+                    (timestamp - timestamp % granularity_nanos).saturating_add(granularity_nanos);
+            }
+            history.exchange_rates.push(exchange_rate);
+        }
+    });
+    history
+}
+
+/// Records the report, dropping the oldest ones beyond [MAX_RECONCILIATION_REPORTS].
+pub fn record_reconciliation_report(report: ReconciliationReport) {
+    RECONCILIATION_REPORTS.with(|r| {
+        let mut reports = r.borrow_mut();
+        reports.insert(report.timestamp, report);
+        while reports.len() > MAX_RECONCILIATION_REPORTS {
+            reports.pop_first();
+        }
+    });
+}
+
+/// Returns at most `length` reports, the latest first.
+pub fn get_reconciliation_reports(length: usize) -> Vec<ReconciliationReport> {
+    RECONCILIATION_REPORTS.with(|r| {
+        r.borrow()
+            .iter()
+            .rev()
+            .take(length)
+            .map(|(_, report)| report)
+            .collect()
+    })
+}
+
 #[test]
 fn should_do_operation_on_rewards() {
     let caller = Principal::anonymous();
@@ -148,3 +611,231 @@ fn should_return_rewards_ready() {
         vec![(caller, 10_000_000_000)]
     );
 }
+
+#[test]
+fn should_return_exchange_rate_history() {
+    use crate::numeric::{ICP, nICP};
+
+    for timestamp in [10, 15, 20, 25, 31, 40] {
+        record_exchange_rate(ExchangeRate {
+            short_term_neuron_stake: ICP::from_e8s(timestamp),
+            nicp_supply: nICP::from_e8s(timestamp),
+            timestamp,
+            exchange_rate: 100_000_000,
+            liquidity_buffer: None,
+        });
+    }
+    let timestamps = |history: ExchangeRateHistory| {
+        history
+            .exchange_rates
+            .into_iter()
+            .map(|exchange_rate| exchange_rate.timestamp)
+            .collect::<Vec<u64>>()
+    };
+
+    assert_eq!(
+        timestamps(get_exchange_rate_history(0, u64::MAX, 0, 100)),
+        vec![10, 15, 20, 25, 31, 40]
+    );
+    assert_eq!(
+        timestamps(get_exchange_rate_history(15, 31, 0, 100)),
+        vec![15, 20, 25, 31]
+    );
+    assert_eq!(
+        timestamps(get_exchange_rate_history(0, u64::MAX, 10, 100)),
+        vec![10, 20, 31, 40]
+    );
+    assert_eq!(
+        timestamps(get_exchange_rate_history(0, u64::MAX, 0, 2)),
+        vec![10, 15]
+    );
+    assert_eq!(get_exchange_rate_history(0, 40, 0, 6).next_from_ts, None);
+    assert_eq!(
+        get_exchange_rate_history(40, 10, 0, 100).exchange_rates,
+        vec![]
+    );
+
+    // Resuming from the cursor keeps the windows of the granularity.
+    let page = get_exchange_rate_history(0, u64::MAX, 10, 2);
+    assert_eq!(page.next_from_ts, Some(31));
+    let next_page = get_exchange_rate_history(page.next_from_ts.unwrap(), u64::MAX, 10, 2);
+    assert_eq!(
+        timestamps(page)
+            .into_iter()
+            .chain(timestamps(next_page.clone()))
+            .collect::<Vec<u64>>(),
+        vec![10, 20, 31, 40]
+    );
+    assert_eq!(next_page.next_from_ts, None);
+
+    assert_eq!(get_exchange_rate_at(5), None);
+    assert_eq!(get_exchange_rate_at(30).map(|e| e.timestamp), Some(25));
+    assert_eq!(get_exchange_rate_at(31).map(|e| e.timestamp), Some(31));
+}
+
+#[test]
+fn should_read_event_log_from_memory() {
+    use ic_stable_structures::VectorMemory;
+
+    let memory = VectorMemory::default();
+    let events = vec![
+        Event {
+            timestamp: 1,
+            payload: EventType::DistributeICPtoSNSv2,
+        },
+        Event {
+            timestamp: 2,
+            payload: EventType::MergeNeuron {
+                neuron_id: crate::NeuronId { id: 3 },
+                nicp_minted: None,
+                nicp_fee: None,
+            },
+        },
+    ];
+    {
+        let memory_manager = MemoryManager::init(memory.clone());
+        let log: StableLog<Event, _, _> = StableLog::init(
+            memory_manager.get(LOG_INDEX_MEMORY_ID),
+            memory_manager.get(LOG_DATA_MEMORY_ID),
+        )
+        .unwrap();
+        for event in &events {
+            log.append(event).unwrap();
+        }
+    }
+    assert_eq!(read_event_log(memory), Ok(events));
+}
+
+#[test]
+fn should_roundtrip_state_checkpoint() {
+    let mut state = crate::state::test::default_state();
+    state.total_icp_deposited = crate::numeric::ICP::from_unscaled(42);
+    state.principal_guards.insert(Principal::anonymous());
+
+    let bytes = encode_state_checkpoint(&state, 7);
+    let (decoded, event_count) = decode_state_checkpoint(&bytes).unwrap();
+    assert_eq!(event_count, 7);
+    assert_eq!(decoded.total_icp_deposited, state.total_icp_deposited);
+    assert_eq!(decoded.is_equivalent_to(&state), Ok(()));
+    assert!(decoded.principal_guards.is_empty());
+
+    let mut buf = vec![];
+    ciborium::into_writer(
+        &StateCheckpointRef {
+            version: STATE_CHECKPOINT_VERSION + 1,
+            event_count: 7,
+            state: &state,
+        },
+        &mut buf,
+    )
+    .unwrap();
+    assert!(decode_state_checkpoint(&buf).is_err());
+}
+
+#[test]
+fn should_return_filtered_events() {
+    use crate::numeric::ICP;
+    use crate::state::event::EventKind;
+    use icrc_ledger_types::icrc1::account::Account;
+
+    let alice = Principal::from_slice(&[1]);
+    let bob = Principal::from_slice(&[2]);
+    let alice_subaccount = Account {
+        owner: alice,
+        subaccount: Some([1; 32]),
+    };
+    let deposit = |receiver: Account, block_index: u64| EventType::IcpDeposit {
+        receiver,
+        amount: ICP::ONE,
+        block_index,
+        created_at_time: None,
+        to_liquidity_buffer: None,
+        from: None,
+        deposit_block_index: None,
+    };
+    record_event(deposit(alice.into(), 0), 10);
+    record_event(
+        EventType::TransferExecuted {
+            transfer_id: 0,
+            block_index: Some(1),
+        },
+        20,
+    );
+    // Recorded before the indexes exist, picked up by the backfill.
+    set_indexed_event_count(0);
+    record_event(deposit(bob.into(), 2), 30);
+    record_event(deposit(alice_subaccount, 3), 40);
+    record_event(
+        EventType::ClaimedAirdrop {
+            caller: alice,
+            block_index: 4,
+        },
+        50,
+    );
+    assert_eq!(indexed_event_count(), 0);
+
+    let indexes = |arg: GetEventsFilteredArg, length: usize| {
+        let result = get_events_filtered(&arg, length);
+        (
+            result
+                .events
+                .into_iter()
+                .map(|event| event.index)
+                .collect::<Vec<u64>>(),
+            result.next_cursor,
+        )
+    };
+    let by_alice = GetEventsFilteredArg {
+        principal: Some(alice),
+        ..Default::default()
+    };
+
+    // Unindexed events are scanned from the log.
+    assert_eq!(indexes(by_alice.clone(), 10), (vec![0, 3, 4], None));
+    assert!(index_events_until(|| false));
+    assert_eq!(indexed_event_count(), 5);
+    assert_eq!(indexes(by_alice.clone(), 10), (vec![0, 3, 4], None));
+    assert_eq!(indexes(by_alice.clone(), 2), (vec![0, 3], Some(4)));
+    assert_eq!(
+        indexes(
+            GetEventsFilteredArg {
+                cursor: Some(4),
+                ..by_alice.clone()
+            },
+            2
+        ),
+        (vec![4], None)
+    );
+    assert_eq!(
+        indexes(
+            GetEventsFilteredArg {
+                account: Some(alice_subaccount),
+                ..Default::default()
+            },
+            10
+        ),
+        (vec![3], None)
+    );
+    assert_eq!(
+        indexes(
+            GetEventsFilteredArg {
+                kinds: vec![EventKind::IcpDeposit, EventKind::TransferExecuted],
+                ..Default::default()
+            },
+            10
+        ),
+        (vec![0, 1, 2, 3], None)
+    );
+    assert_eq!(
+        indexes(
+            GetEventsFilteredArg {
+                kinds: vec![EventKind::IcpDeposit],
+                from_timestamp: Some(15),
+                to_timestamp: Some(40),
+                ..by_alice
+            },
+            10
+        ),
+        (vec![3], None)
+    );
+}
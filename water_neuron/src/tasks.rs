@@ -24,6 +24,13 @@ pub enum TaskType {
     RefreshShortTerm,
     MaybeDistributeRewards,
     ProcessRewardsTransfer,
+    SnapshotExchangeRate,
+    CheckpointState,
+    ReplayEvents,
+    IndexEvents,
+    Reconcile,
+    PruneRateLimits,
+    ProcessPendingMerges,
 }
 
 #[derive(Clone, Debug, Ord, PartialOrd, Eq, PartialEq)]